@@ -1,20 +1,138 @@
 use egui::{Color32, CornerRadius, Frame, Visuals};
-use egui_phosphor;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinSet;
 
 use crate::calculation_channel::*;
+use crate::channel_quality::*;
+use crate::modbus_block::*;
+use crate::modbus_connection::*;
 use crate::modbus_device::*;
+use crate::modbus_gateway::*;
+use crate::modbus_scaling::*;
+use crate::modbus_scan::*;
+use crate::modbus_script::*;
+use crate::modbus_simulator::*;
+use crate::modbus_traffic::*;
+use crate::ui::ui_calculations::*;
+use crate::ui::ui_gateway::*;
 use crate::ui::ui_panels::*;
-use crate::ui::ModbusDeviceBuffer;
+use crate::ui::ui_scanner::*;
+use crate::ui::ui_simulator::*;
+use crate::ui::ui_traffic::*;
+use crate::ui::{ModbusChannelBuffer, ModbusDeviceBuffer, ScannerBuffer};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub enum ThreadStatus {
     Healthy(String),
     Error(String),
 }
+
+// The status receiver is only dropped with the GUI,
+// the polling tasks end when they get this error.
+type StatusClosed = mpsc::error::SendError<ThreadStatus>;
+
+// Commands sent from the GUI to the polling thread.
+// The first field is the target device id.
+pub enum ThreadCommand {
+    // New connection settings, the device is reconnected.
+    DeviceConfig(usize, ModbusDeviceConfig),
+    BlockConfig(usize, ModbusBlockConfig),
+    ScanConfig(usize, ModbusScanConfig),
+    RequestConfig(usize, ModbusRequestConfig),
+    // Reset the exception counts and link statistics of the device.
+    ClearDiagnostics(usize),
+    // Channels found by the scanner, appended to the device.
+    AddChannels(usize, Vec<ModbusChannel>),
+    // Scaling, clamp, unit and scan class of a device channel.
+    ChannelSettings(usize, ChannelSettings),
+    Write(ModbusWrite),
+    // New gateway settings, not tied to a device.
+    GatewayConfig(GatewayConfig),
+    // The edited calculation channels, replacing all of them.
+    Calculations(Vec<CalculationChannel>),
+    // Evaluate the draft calculation at the index once, for the editor.
+    TestCalculation(Vec<CalculationChannel>, usize),
+    // A Modbus request of a calculation script.
+    ScriptCall(ScriptCall),
+}
+
+// Settings of a device channel, edited in the right panel and the channels table.
+#[derive(Clone, Debug)]
+pub struct ChannelSettings {
+    pub channel_id: usize,
+    pub scaling: ModbusScaling,
+    pub clamp: Option<(f64, f64)>,
+    pub unit: String,
+    pub scan_class: Option<String>,
+}
+
+impl ChannelSettings {
+    // The current settings of a channel.
+    pub fn from_channel(channel: &ModbusChannel) -> Self {
+        Self {
+            channel_id: channel.id,
+            scaling: channel.scaling.clone(),
+            clamp: channel.clamp,
+            unit: channel.unit.clone(),
+            scan_class: channel.scan_class.clone(),
+        }
+    }
+
+    pub fn apply(&self, channel: &mut ModbusChannel) {
+        channel.scaling = self.scaling.clone();
+        channel.clamp = self.clamp;
+        channel.unit = self.unit.clone();
+        channel.scan_class = self.scan_class.clone();
+    }
+}
+
+// A value to write to a device channel.
+#[derive(Clone, Debug)]
+pub struct ModbusWrite {
+    pub device_id: usize,
+    pub channel_id: usize,
+    pub channel_name: String,
+    pub value: ModbusValue,
+}
+
+impl ThreadCommand {
+    pub fn device_id(&self) -> Option<usize> {
+        match self {
+            ThreadCommand::DeviceConfig(device_id, _) => Some(*device_id),
+            ThreadCommand::BlockConfig(device_id, _) => Some(*device_id),
+            ThreadCommand::ScanConfig(device_id, _) => Some(*device_id),
+            ThreadCommand::RequestConfig(device_id, _) => Some(*device_id),
+            ThreadCommand::ClearDiagnostics(device_id) => Some(*device_id),
+            ThreadCommand::AddChannels(device_id, _) => Some(*device_id),
+            ThreadCommand::ChannelSettings(device_id, _) => Some(*device_id),
+            ThreadCommand::Write(write) => Some(write.device_id),
+            ThreadCommand::ScriptCall(call) => Some(call.device_id),
+            ThreadCommand::GatewayConfig(_) => None,
+            ThreadCommand::Calculations(_) => None,
+            ThreadCommand::TestCalculation(..) => None,
+        }
+    }
+}
+
+// Data sent from the polling thread to the GUI.
+pub enum ThreadUpdate {
+    Device(DeviceUpdate),
+    // Results of every calculation channel, sent each scan.
+    Calculations(Vec<CalculationChannel>),
+    // Value and quality of a test evaluation, or its error.
+    TestResult(Result<String, String>),
+}
+
+// Polled data of one device, sent from the polling thread.
+pub struct DeviceUpdate {
+    pub device_id: usize,
+    pub device: ModbusDevice,
+}
+
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -23,11 +141,24 @@ pub struct ColossalApp {
 
     // Device tabel UI buffer.
     pub device_config_ui_buffer: ModbusDeviceBuffer,
+    // Settings of the selected channel.
+    pub channel_config_ui_buffer: ModbusChannelBuffer,
 
     // Channels table selected row
     pub tabel_selected_row: Option<usize>,
-    // Holder of the received data from the thread
-    pub received_device_data: Option<ModbusDevice>,
+    // Value typed in the right panel for the selected channel
+    #[serde(skip)]
+    pub write_value_buffer: String,
+    // Write waiting for the user confirmation
+    #[serde(skip)]
+    pub pending_write: Option<ModbusWrite>,
+    #[serde(skip)]
+    pub write_error: Option<String>,
+    // Device shown in the channels table
+    pub selected_device: usize,
+    // Holder of the received data from the thread, by device id
+    #[serde(skip)]
+    pub received_devices: BTreeMap<usize, ModbusDevice>,
     #[serde(skip)]
     pub thread_status: String,
     #[serde(skip)]
@@ -35,14 +166,32 @@ pub struct ColossalApp {
     // Device and Calc Channels======================
     #[serde(skip)]
     pub modbus_devices: Vec<ModbusDevice>,
-    #[serde(skip)]
+    // Saved without their results, sent to the polling thread on startup.
     pub calculation_channels: Vec<CalculationChannel>,
+    #[serde(skip)]
+    pub calculation_editor: CalculationEditor,
     // ===============================================
     // Thread communication channels
+    // Modbus TCP server republishing the channels and calculations.
+    pub gateway_config: GatewayConfig,
+    pub scanner_buffer: ScannerBuffer,
+    #[serde(skip)]
+    pub scanner: ScannerState,
+    // In-app slave simulator, see the standalone `simulator` binary.
+    pub simulator_config: SimulatorConfig,
+    pub simulator_map_path: String,
+    #[serde(skip)]
+    pub simulator: Option<SimulatorHandle>,
     #[serde(skip)]
-    pub sender_main_to_thread: Sender<ModbusDeviceConfig>,
+    pub simulator_error: Option<String>,
+    // Requests and responses of all the devices, filled by the polling thread.
     #[serde(skip)]
-    pub receiver_thread_to_main: Receiver<ModbusDevice>,
+    pub traffic: TrafficMonitor,
+    pub traffic_view: TrafficView,
+    #[serde(skip)]
+    pub sender_main_to_thread: Sender<ThreadCommand>,
+    #[serde(skip)]
+    pub receiver_thread_to_main: Receiver<ThreadUpdate>,
     // Thread status
     #[serde(skip)]
     pub receiver_status_to_main: Receiver<ThreadStatus>,
@@ -58,7 +207,7 @@ pub struct ColossalApp {
 impl Default for ColossalApp {
     fn default() -> Self {
         let device = init_mb_tcp_device("127.0.0.1".to_owned(), 5502, "Device_1".to_owned(), 10);
-        let calculation_channels = init_channel_list(5);
+        let calculation_channels = init_channel_list(&device.name, 5);
 
         // This is just a placeholder for the application startup.
         // sender and receiver will be overwritten later.
@@ -68,13 +217,28 @@ impl Default for ColossalApp {
 
         Self {
             // Example stuff:
-            device_config_ui_buffer: ModbusDeviceBuffer::default(),
+            device_config_ui_buffer: ModbusDeviceBuffer::from_device(&device),
+            channel_config_ui_buffer: ModbusChannelBuffer::default(),
             tabel_selected_row: None,
+            write_value_buffer: String::new(),
+            pending_write: None,
+            write_error: None,
+            selected_device: device.id,
             modbus_devices: vec![device],
             status_bar_frame: Frame::new(),
             thread_status: String::from("Status: Healthy"),
             calculation_channels,
-            received_device_data: None,
+            received_devices: BTreeMap::new(),
+            gateway_config: GatewayConfig::default(),
+            scanner_buffer: ScannerBuffer::default(),
+            scanner: ScannerState::default(),
+            simulator_config: SimulatorConfig::default(),
+            simulator_map_path: String::new(),
+            simulator: None,
+            simulator_error: None,
+            calculation_editor: CalculationEditor::default(),
+            traffic: TrafficMonitor::default(),
+            traffic_view: TrafficView::default(),
             sender_main_to_thread: config_sender,
             receiver_thread_to_main: receiver,
             receiver_status_to_main: receiver_status,
@@ -147,20 +311,22 @@ impl eframe::App for ColossalApp {
         if self.first_scan {
             let devices = self.modbus_devices.clone();
             let calculation_channels = self.calculation_channels.clone();
+            let gateway_config = self.gateway_config.clone();
+            let traffic = self.traffic.clone();
 
             // Configuration update channel.
             // We send it from the GUI main to the thread
             // in case of configuration changes.
             let (sender_main_to_thread, receiver_main_to_thread): (
-                Sender<ModbusDeviceConfig>,
-                Receiver<ModbusDeviceConfig>,
+                Sender<ThreadCommand>,
+                Receiver<ThreadCommand>,
             ) = mpsc::channel(16);
 
             // Data polling channel. This is the main channel
             // that carries all the polling data.
             let (sender_thread_to_main, receiver_thread_to_main): (
-                Sender<ModbusDevice>,
-                Receiver<ModbusDevice>,
+                Sender<ThreadUpdate>,
+                Receiver<ThreadUpdate>,
             ) = mpsc::channel(16);
 
             // Thread health status.
@@ -185,9 +351,10 @@ impl eframe::App for ColossalApp {
                     .build()
                     .unwrap()
                     .block_on(async move {
-                        //let mut device = devices[0].clone();
                         async_pool_thread(
                             calculation_channels,
+                            gateway_config,
+                            traffic,
                             receiver_main_to_thread,
                             sender_thread_to_main,
                             sender_status_to_main,
@@ -222,7 +389,7 @@ impl eframe::App for ColossalApp {
                 });
             });
 
-        if let Ok(status_msg) = self.receiver_status_to_main.try_recv() {
+        while let Ok(status_msg) = self.receiver_status_to_main.try_recv() {
             match status_msg {
                 ThreadStatus::Healthy(msg) => {
                     self.status_bar_frame = Frame {
@@ -258,103 +425,593 @@ impl eframe::App for ColossalApp {
             // The central panel the region left after adding TopPanel's and SidePanel's
 
             // Check for any data coming from the thread.
-            if let Ok(received_device_data) = self.receiver_thread_to_main.try_recv() {
-                self.received_device_data = Some(received_device_data);
+            while let Ok(update) = self.receiver_thread_to_main.try_recv() {
+                match update {
+                    ThreadUpdate::Device(update) => {
+                        self.received_devices
+                            .insert(update.device_id, update.device);
+                    }
+                    ThreadUpdate::Calculations(results) => {
+                        // Only the results, the calculations may be edited.
+                        for result in results {
+                            if let Some(channel) = self
+                                .calculation_channels
+                                .iter_mut()
+                                .find(|channel| channel.id == result.id)
+                            {
+                                channel.value = result.value;
+                                channel.quality = result.quality;
+                                channel.error = result.error;
+                            }
+                        }
+                    }
+                    ThreadUpdate::TestResult(result) => {
+                        self.calculation_editor.set_test_result(result);
+                    }
+                }
             }
 
             match ui_device_channels_table(self, ui) {
                 Ok(_) => {}
                 Err(e) => println!("{e}"),
             }
+
+            match ui_calculation_channels_table(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_calculation_editor(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_device_diagnostics(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_gateway(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_traffic(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_scanner(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
+
+            match ui_simulator(self, ui) {
+                Ok(_) => {}
+                Err(e) => println!("{e}"),
+            }
         });
     }
 }
 
 async fn async_pool_thread(
-    calculation_channels: Vec<CalculationChannel>,
-    mut receiver_main_to_thread: Receiver<ModbusDeviceConfig>,
-    sender_thread_to_main: Sender<ModbusDevice>,
+    mut calculation_channels: Vec<CalculationChannel>,
+    gateway_config: GatewayConfig,
+    traffic: TrafficMonitor,
+    mut receiver_main_to_thread: Receiver<ThreadCommand>,
+    sender_thread_to_main: Sender<ThreadUpdate>,
     sender_status_to_main: Sender<ThreadStatus>,
-    mut devices: Vec<ModbusDevice>,
+    devices: Vec<ModbusDevice>,
 ) {
+    // Devices on the same endpoint share a connection.
+    let connections = ModbusConnections::new(traffic);
+
+    // Every device task reports its polled data here.
+    let (sender_device_to_pool, mut receiver_device_to_pool): (
+        Sender<DeviceUpdate>,
+        Receiver<DeviceUpdate>,
+    ) = mpsc::channel(64);
+
+    // Latest polled state of every device, used by the calculations
+    // and to restart a device task that panicked.
+    let mut device_states = devices.clone();
+    let mut command_senders: HashMap<usize, Sender<ThreadCommand>> = HashMap::new();
+    let mut task_devices: HashMap<tokio::task::Id, usize> = HashMap::new();
+    let mut tasks = JoinSet::new();
+
+    for device in devices {
+        let device_id = device.id;
+        let (sender_command, receiver_command) = mpsc::channel(16);
+        let task = tasks.spawn(device_poll_task(
+            device,
+            connections.clone(),
+            receiver_command,
+            sender_device_to_pool.clone(),
+            sender_status_to_main.clone(),
+        ));
+        command_senders.insert(device_id, sender_command);
+        task_devices.insert(task.id(), device_id);
+    }
+
+    // Writes from the gateway clients, forwarded like GUI writes.
+    let (sender_gateway_write, mut receiver_gateway_write) = mpsc::channel(64);
+    let Ok(mut gateway) = start_gateway(
+        gateway_config,
+        &sender_gateway_write,
+        &sender_status_to_main,
+    )
+    .await
+    else {
+        return;
+    };
+
+    // Requests of the calculation scripts, sent on by the device tasks.
+    let (sender_script_call, mut receiver_script_call) = mpsc::channel(64);
+
+    // The calculations are compiled once and evaluated on every tick.
+    // Cycles between calculations are reported here, they keep
+    // failing on evaluation until fixed.
+    let mut calculation_engine =
+        CalculationEngine::new(ScriptModbus::periodic(sender_script_call.clone()));
+    calculation_engine.update_devices(&device_states);
+    if let Err(e) = calculation_engine.configure(&calculation_channels) {
+        let status = ThreadStatus::Error(format!("Calculation configuration error: {e}"));
+        if sender_status_to_main.send(status).await.is_err() {
+            return;
+        }
+    }
+    let mut calculation_interval = tokio::time::interval(Duration::from_millis(1000));
+
     loop {
-        let ctx = devices[0].connect_to_device().await;
-        match ctx {
-            Ok(mut ctx) => {
-                loop {
-                    //
-                    if let Ok(_msg) = receiver_main_to_thread.try_recv() {
-                        sender_status_to_main
-                            .send(ThreadStatus::Healthy("Config update.".to_owned()))
-                            .await
-                            .unwrap();
-                        println!("Thread received a message");
+        tokio::select! {
+            Some(update) = receiver_device_to_pool.recv() => {
+                if let Some(state) = device_states
+                    .iter_mut()
+                    .find(|device| device.id == update.device_id)
+                {
+                    *state = update.device.clone();
+                }
+                if let Some(gateway) = &gateway {
+                    gateway.update_device(&update.device);
+                }
+
+                if let Err(e) = sender_thread_to_main.send(ThreadUpdate::Device(update)).await {
+                    println!("Sender error: {e}");
+                    let status = ThreadStatus::Error("Could not send data back to main.".to_owned());
+                    if sender_status_to_main.send(status).await.is_err() {
+                        return;
                     }
-                    match devices[0].poll(&mut ctx).await {
-                        Ok(_) => {
-                            sender_status_to_main
-                                .send(ThreadStatus::Healthy(format!("Healthy",)))
-                                .await
-                                .unwrap();
-                            match sender_thread_to_main.send(devices[0].clone()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    println!("Sender error: {e}");
-                                    sender_status_to_main
-                                        .send(ThreadStatus::Error(
-                                            "Could not send data back to main.".to_owned(),
-                                        ))
-                                        .await
-                                        .unwrap();
-                                }
-                            }
-                            for channel in &devices[0].channels {
-                                println!("{:?}", channel.value);
-                            }
+                }
+            }
+            Some(command) = receiver_main_to_thread.recv() => {
+                if let ThreadCommand::GatewayConfig(config) = command {
+                    // Close the old server first so the port is free.
+                    drop(gateway.take());
+                    let Ok(started) =
+                        start_gateway(config, &sender_gateway_write, &sender_status_to_main).await
+                    else {
+                        return;
+                    };
+                    gateway = started;
+                    continue;
+                }
+                if let ThreadCommand::Calculations(channels) = command {
+                    calculation_channels = channels;
+                    if let Err(e) = calculation_engine.configure(&calculation_channels) {
+                        let status =
+                            ThreadStatus::Error(format!("Calculation configuration error: {e}"));
+                        if sender_status_to_main.send(status).await.is_err() {
+                            return;
                         }
-                        Err(e) => {
-                            println!("{e}");
-                            sender_status_to_main
-                                .send(ThreadStatus::Error(format!("Poll error: {e}")))
-                                .await
-                                .unwrap();
+                    }
+                    continue;
+                }
+                if let ThreadCommand::TestCalculation(channels, index) = command {
+                    // The script may send requests and wait for them,
+                    // so it runs aside from this loop.
+                    let sender_script_call = sender_script_call.clone();
+                    let devices = device_states.clone();
+                    let sender = sender_thread_to_main.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let result =
+                            test_calculation(sender_script_call, &devices, channels, index);
+                        sender.blocking_send(ThreadUpdate::TestResult(result)).ok();
+                    });
+                    continue;
+                }
 
-                            break;
-                        }
+                if route_command(&command_senders, command, &sender_status_to_main)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Some(write) = receiver_gateway_write.recv() => {
+                let command = ThreadCommand::Write(write);
+                if route_command(&command_senders, command, &sender_status_to_main)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Some(call) = receiver_script_call.recv() => {
+                let command = ThreadCommand::ScriptCall(call);
+                if route_command(&command_senders, command, &sender_status_to_main)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            // Device tasks only end if they panic, so we restart them
+            // from the last state they reported.
+            Some(joined) = tasks.join_next_with_id() => {
+                let (task_id, reason) = match joined {
+                    Ok((task_id, _)) => (task_id, "stopped".to_owned()),
+                    Err(e) => (e.id(), format!("{e}")),
+                };
+                let Some(device_id) = task_devices.remove(&task_id) else {
+                    continue;
+                };
+                let Some(device) = device_states
+                    .iter()
+                    .find(|device| device.id == device_id)
+                    .cloned()
+                else {
+                    continue;
+                };
+
+                let status = ThreadStatus::Error(format!(
+                    "{}: Polling task {reason}, restarting.",
+                    device.name
+                ));
+                if sender_status_to_main.send(status).await.is_err() {
+                    return;
+                }
+
+                let (sender_command, receiver_command) = mpsc::channel(16);
+                let task = tasks.spawn(device_poll_task(
+                    device,
+                    connections.clone(),
+                    receiver_command,
+                    sender_device_to_pool.clone(),
+                    sender_status_to_main.clone(),
+                ));
+                command_senders.insert(device_id, sender_command);
+                task_devices.insert(task.id(), device_id);
+            }
+            _ = calculation_interval.tick() => {
+                // Evaluate each calculation channel.
+                // Use the latest device states so we are sure
+                // we are working with the updated values from
+                // the poll function.
+                calculation_engine.update_devices(&device_states);
+                // Each calculation after the ones it uses.
+                let order = calculation_engine.order().to_vec();
+                for index in order {
+                    let channel = &mut calculation_channels[index];
+                    if !channel.enabled {
+                        continue;
                     }
-                    // Evaluate each calculation channel.
-                    for mut channel in calculation_channels.clone() {
-                        // Use the reference to devices so we are sure
-                        // we are working with the updated values from
-                        // the poll function.
-                        match channel.evaluate(&devices) {
-                            Ok(_) => {
-                                println!("Calculation result: {}", channel.value);
-                            }
-                            Err(e) => {
-                                sender_status_to_main
-                                    .send(ThreadStatus::Error(format!(
-                                        "Calculation evaluation error: {e}"
-                                    )))
-                                    .await
-                                    .unwrap();
+                    match channel.evaluate(&mut calculation_engine) {
+                        Ok(_) => channel.error = None,
+                        Err(e) => {
+                            // The value is the last good one, the quality
+                            // tells the GUI and the gateway it failed.
+                            channel.quality = ChannelQuality::Bad(format!("{e}"));
+                            channel.error = Some(format!("{e}"));
+                            let status =
+                                ThreadStatus::Error(format!("Calculation evaluation error: {e}"));
+                            if sender_status_to_main.send(status).await.is_err() {
+                                return;
                             }
                         }
                     }
-                    std::thread::sleep(Duration::from_millis(1000));
+                }
+
+                if let Some(gateway) = &gateway {
+                    gateway.update_calculations(&calculation_channels);
+                }
+
+                let update = ThreadUpdate::Calculations(calculation_channels.clone());
+                if let Err(e) = sender_thread_to_main.send(update).await {
+                    println!("Sender error: {e}");
                 }
             }
-            Err(e) => {
-                sender_status_to_main
-                    .send(ThreadStatus::Error(format!("{e}")))
-                    .await
-                    .unwrap();
-                println!("{e}");
+        }
+    }
+}
+
+// Send a GUI or gateway command to its device task.
+async fn route_command(
+    command_senders: &HashMap<usize, Sender<ThreadCommand>>,
+    command: ThreadCommand,
+    sender_status_to_main: &Sender<ThreadStatus>,
+) -> Result<(), StatusClosed> {
+    let Some(device_id) = command.device_id() else {
+        return Ok(());
+    };
+
+    let status = match command_senders.get(&device_id) {
+        // The task may be restarting, in which case the
+        // command is dropped and has to be sent again.
+        Some(sender) => match sender.send(command).await {
+            Ok(_) => return Ok(()),
+            Err(_) => format!("Device {device_id} is restarting, command dropped."),
+        },
+        None => format!("Unknown device {device_id}"),
+    };
+
+    sender_status_to_main
+        .send(ThreadStatus::Error(status))
+        .await
+}
+
+// Start the gateway server if it's enabled, reporting why it couldn't.
+async fn start_gateway(
+    config: GatewayConfig,
+    sender_gateway_write: &Sender<ModbusWrite>,
+    sender_status_to_main: &Sender<ThreadStatus>,
+) -> Result<Option<ModbusGateway>, StatusClosed> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let address = format!("{}, port {}", config.bind_address, config.port);
+    let status = match ModbusGateway::start(
+        config,
+        sender_gateway_write.clone(),
+        sender_status_to_main.clone(),
+    )
+    .await
+    {
+        Ok(gateway) => {
+            sender_status_to_main
+                .send(ThreadStatus::Healthy(format!(
+                    "Gateway: Serving on {address}."
+                )))
+                .await?;
+            return Ok(Some(gateway));
+        }
+        Err(e) => ThreadStatus::Error(format!("Gateway: Can't serve on {address}: {e}")),
+    };
+
+    sender_status_to_main.send(status).await?;
+    Ok(None)
+}
+
+// Evaluate one calculation with its own engine, the Modbus requests
+// of the script are answered before it goes on. Returns the value
+// and quality. Blocks, so it runs outside of the runtime.
+fn test_calculation(
+    sender_script_call: Sender<ScriptCall>,
+    devices: &[ModbusDevice],
+    mut channels: Vec<CalculationChannel>,
+    index: usize,
+) -> Result<String, String> {
+    let mut engine = CalculationEngine::new(ScriptModbus::new(sender_script_call));
+    engine.update_devices(devices);
+    engine.configure(&channels).ok();
+
+    let channel = channels
+        .get_mut(index)
+        .ok_or_else(|| format!("Unknown calculation {index}"))?;
+    match channel.evaluate(&mut engine) {
+        Ok(_) => Ok(format!("{} ({})", channel.value, channel.quality)),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+// Polling loop of a single device. Each device runs in its own
+// task so an unreachable device doesn't hold back the others.
+async fn device_poll_task(
+    mut device: ModbusDevice,
+    connections: ModbusConnections,
+    mut receiver_command: Receiver<ThreadCommand>,
+    sender_device_to_pool: Sender<DeviceUpdate>,
+    sender_status_to_main: Sender<ThreadStatus>,
+) {
+    let mut schedule = ScanSchedule::new(&device);
+    // Set after a failed poll, no scan starts before it.
+    let mut reconnect_at: Option<tokio::time::Instant> = None;
+
+    loop {
+        // Wait for the next scan, handling the GUI commands as they come.
+        let next_due = schedule
+            .next_due()
+            .map(|next_due| reconnect_at.map_or(next_due, |at| next_due.max(at)));
+        tokio::select! {
+            command = receiver_command.recv() => {
+                let Some(command) = command else {
+                    return;
+                };
+
+                // Only these commands change what is polled and when.
+                let changes_scan = matches!(
+                    command,
+                    ThreadCommand::ScanConfig(..)
+                        | ThreadCommand::AddChannels(..)
+                        | ThreadCommand::ChannelSettings(..)
+                );
+                let written_channel = match &command {
+                    ThreadCommand::Write(write) => device
+                        .channels
+                        .iter()
+                        .position(|channel| channel.id == write.channel_id),
+                    _ => None,
+                };
+
+                let Ok(reconnect) =
+                    apply_thread_command(&connections, &mut device, command, &sender_status_to_main)
+                        .await
+                else {
+                    return;
+                };
+                if reconnect {
+                    let status = ThreadStatus::Healthy(format!(
+                        "{}: Config update. Reconnecting.",
+                        device.name
+                    ));
+                    if sender_status_to_main.send(status).await.is_err() {
+                        return;
+                    }
+                    // A new endpoint is polled in full right away.
+                    schedule = ScanSchedule::new(&device);
+                    reconnect_at = None;
+                } else if changes_scan {
+                    schedule.update(&device);
+                }
+                // After a write we want the new value right away.
+                if let Some(index) = written_channel {
+                    schedule.poll_now(index);
+                }
                 continue;
             }
+            _ = async {
+                match next_due {
+                    Some(next_due) => tokio::time::sleep_until(next_due).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+        }
+
+        let selection = schedule.take_due(tokio::time::Instant::now());
+        if selection.is_empty() {
+            continue;
+        }
+
+        // The device is connected on the first poll
+        // and after every failed one.
+        let scan_start = SystemTime::now();
+        let result = connections.poll(&mut device, &selection).await;
+        if let Err(e) = &result {
+            let status = ThreadStatus::Error(format!("{}: Poll error: {e}", device.name));
+            if sender_status_to_main.send(status).await.is_err() {
+                return;
+            }
+
+            device.set_missed_scan(&selection, scan_start);
+        } else {
+            // Channels with exceptions keep being polled,
+            // we only report how many are bad.
+            let bad_channels = device
+                .channels
+                .iter()
+                .filter(|channel| matches!(channel.quality, ChannelQuality::Bad(_)))
+                .count();
+            let status = if bad_channels == 0 {
+                ThreadStatus::Healthy(format!("{}: Healthy", device.name))
+            } else {
+                ThreadStatus::Error(format!("{}: {bad_channels} bad channels", device.name))
+            };
+            if sender_status_to_main.send(status).await.is_err() {
+                return;
+            }
+
+            // Asked once, devices without FC43 answer with an exception.
+            if device.identification.is_none() {
+                let identification = connections.read_identification(&mut device).await;
+                device.identification = Some(identification.map_err(|e| format!("{e}")));
+            }
+        }
+
+        // Failed polls are sent too so the GUI sees the stale channels.
+        let update = DeviceUpdate {
+            device_id: device.id,
+            device: device.clone(),
+        };
+        if sender_device_to_pool.send(update).await.is_err() {
+            return;
         }
 
         // We wait for a while before the next connection attempt.
-        std::thread::sleep(Duration::from_millis(5000));
+        reconnect_at = result.is_err().then(|| {
+            let reconnect_delay = device.request_config.reconnect_delay_ms;
+            tokio::time::Instant::now() + Duration::from_millis(reconnect_delay)
+        });
     }
 }
+
+// Apply a GUI command to the device.
+// Returns true if the device needs to reconnect, an error once the GUI is gone.
+async fn apply_thread_command(
+    connections: &ModbusConnections,
+    device: &mut ModbusDevice,
+    command: ThreadCommand,
+    sender_status_to_main: &Sender<ThreadStatus>,
+) -> Result<bool, StatusClosed> {
+    let reconnect = match command {
+        ThreadCommand::DeviceConfig(_, config) => {
+            // Close the old endpoint before we lose track of it.
+            connections.disconnect(device).await;
+            device.config = config;
+            device.identification = None;
+            true
+        }
+        ThreadCommand::BlockConfig(_, config) => {
+            device.block_config = config;
+            false
+        }
+        ThreadCommand::ScanConfig(_, config) => {
+            device.scan_config = config;
+            false
+        }
+        ThreadCommand::RequestConfig(_, config) => {
+            device.request_config = config;
+            false
+        }
+        ThreadCommand::ClearDiagnostics(_) => {
+            device.clear_diagnostics();
+            false
+        }
+        ThreadCommand::AddChannels(_, channels) => {
+            device.channels.extend(channels);
+            false
+        }
+        ThreadCommand::ChannelSettings(_, settings) => {
+            if let Some(channel) = device
+                .channels
+                .iter_mut()
+                .find(|channel| channel.id == settings.channel_id)
+            {
+                settings.apply(channel);
+            }
+            false
+        }
+        // Handled by the pool thread.
+        ThreadCommand::GatewayConfig(_)
+        | ThreadCommand::Calculations(_)
+        | ThreadCommand::TestCalculation(..) => false,
+        // Sent on the device link so the request shows in its
+        // statistics and traffic. The script may have stopped waiting.
+        ThreadCommand::ScriptCall(call) => {
+            let answer = connections
+                .call(device, call.call)
+                .await
+                .map_err(|e| format!("{}: {e}", device.name));
+            call.reply.send(answer).ok();
+            false
+        }
+        ThreadCommand::Write(write) => {
+            let status = match connections
+                .write(device, write.channel_id, &write.value)
+                .await
+            {
+                Ok(_) => ThreadStatus::Healthy(format!(
+                    "{}: Wrote {} to {}.",
+                    device.name, write.value, write.channel_name
+                )),
+                Err(e) => ThreadStatus::Error(format!(
+                    "{}: Write to {} failed: {e}",
+                    device.name, write.channel_name
+                )),
+            };
+            sender_status_to_main.send(status).await?;
+            false
+        }
+    };
+
+    Ok(reconnect)
+}
@@ -0,0 +1,27 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+// Standalone Modbus TCP slave simulator.
+// Usage: simulator [register_map.ron]
+// Without a map the channels of the default device are served.
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = match std::env::args().nth(1) {
+        Some(path) => colossal::SimulatorConfig::load(&path)?,
+        None => colossal::SimulatorConfig::default(),
+    };
+
+    println!(
+        "Serving {} registers on port {}. Press Ctrl+C to stop.",
+        config.registers.len(),
+        config.port
+    );
+
+    colossal::run_simulator(
+        config,
+        async {
+            tokio::signal::ctrl_c().await.ok();
+        },
+        |e| eprintln!("Client error: {e}"),
+    )
+    .await
+}
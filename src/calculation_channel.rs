@@ -1,8 +1,22 @@
+use crate::channel_quality::*;
 use crate::modbus_device::*;
-use anyhow::{anyhow, Result};
-use egui::TextBuffer;
+use crate::modbus_script::*;
+use anyhow::Result;
 use regex::Regex;
-use rhai::{Engine, EvalAltResult};
+use rhai::{Dynamic, Engine, Map, ParseErrorType, Scope, AST};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::LazyLock;
+
+// Tag references of a calculation: `Device_1.MB1`, `Device_1["Any name"]`
+// or a bare `MB1`. Strings and comments are matched so they can be skipped,
+// and so are members of other values, like `x.abs`.
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?s)"(?:[^"\\]|\\.)*"|`[^`]*`|//[^\n]*|/\*.*?\*/|(\.\s*)?\b([A-Za-z_][A-Za-z0-9_]*)(?:\s*\.\s*([A-Za-z_][A-Za-z0-9_]*)|\s*\[\s*"((?:[^"\\]|\\.)*)"\s*\])?"#,
+    )
+    .unwrap()
+});
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct CalculationChannel {
@@ -10,73 +24,632 @@ pub struct CalculationChannel {
     pub id: usize,
     pub name: String,
     pub calculation: String,
+    // The results aren't saved with the app state.
+    #[serde(skip)]
     pub value: f64,
+    // The worst quality of the channels used by the calculation.
+    #[serde(skip)]
+    pub quality: ChannelQuality,
+    #[serde(skip)]
     pub error: Option<String>,
 }
 
 impl CalculationChannel {
     // Evaluate the channel calculation and store it in the value member.
-    pub fn evaluate(&mut self, devices: &Vec<ModbusDevice>) -> Result<()> {
-        let re = Regex::new(r"\bMB\D{0,4}(\d{0,8})(?:\d|$)")?;
-        let hay = &self.calculation;
-        let mut calculation_string: String = self.calculation.clone();
-        let matches: Vec<_> = re.find_iter(hay.as_str()).map(|m| m.as_str()).collect();
-
-        let mut replace_value = String::new();
-
-        //println!("{:?}", matches);
-        for m in matches {
-            let match_str = format!("{}", m);
-            for device in devices {
-                for channel in &device.channels {
-                    if channel.name == match_str.trim() {
-                        match channel.value {
-                            ModbusValue::Int(v) => {
-                                replace_value = format!("{}", v);
-                            }
-                            ModbusValue::Real(v) => {
-                                replace_value = format!("{}", v);
-                            }
-                            ModbusValue::Bool(_) => {
-                                unimplemented!()
-                            }
-                        }
-
-                        calculation_string =
-                            calculation_string.replace(match_str.as_str(), &replace_value);
-                        let engine = Engine::new();
-                        let result = engine.eval::<f64>(&calculation_string);
-
-                        match result {
-                            Ok(v) => {
-                                self.value = v;
-                            }
-                            Err(e) => {
-                                anyhow::bail!("{e}");
-                            }
-                        }
+    // The channel values are the ones last given to the engine.
+    pub fn evaluate(&mut self, engine: &mut CalculationEngine) -> Result<()> {
+        let (value, quality) = engine.evaluate(self.id, &self.calculation)?;
+        self.value = value;
+        self.quality = quality;
+
+        Ok(())
+    }
+}
+
+// Why a calculation doesn't compile, with the line and
+// column of the error when known, counted from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalculationError {
+    pub message: String,
+    pub position: Option<(usize, usize)>,
+}
+
+impl Display for CalculationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{} (line {line}, position {column})", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// A calculation compiled from its source.
+struct CompiledCalculation {
+    ast: AST,
+    // Devices, calculations and bare tags bound as variables.
+    variables: BTreeSet<String>,
+    // Channels read, as (device, channel) indexes.
+    tags: BTreeSet<(usize, usize)>,
+    // Calculations read, evaluated before this one.
+    calculations: BTreeSet<usize>,
+}
+
+// A polled channel or a calculation result as seen by the calculations.
+#[derive(Clone)]
+struct TagValue {
+    value: Dynamic,
+    quality: ChannelQuality,
+}
+
+// The channels of a device, bound as a map from channel name to value.
+struct DeviceTags {
+    map: Dynamic,
+    channels: Vec<TagValue>,
+    names: HashMap<String, usize>,
+}
+
+// A configured calculation and its last result.
+struct CalculationTag {
+    id: usize,
+    name: String,
+    source: String,
+    result: TagValue,
+}
+
+// How a name of a calculation resolves. Names used by several
+// devices are ambiguous and have to be qualified.
+#[derive(Clone, Copy, PartialEq)]
+enum Binding<T> {
+    Unique(T),
+    Ambiguous,
+}
+
+fn insert_binding<T: PartialEq>(bindings: &mut HashMap<String, Binding<T>>, name: &str, value: T) {
+    let binding = Binding::Unique(value);
+    match bindings.get(name) {
+        Some(existing) if *existing != binding => {
+            bindings.insert(name.to_owned(), Binding::Ambiguous);
+        }
+        Some(_) => {}
+        None => {
+            bindings.insert(name.to_owned(), binding);
+        }
+    }
+}
+
+// Compiles each calculation once, again only when its source or the
+// names it uses change, and evaluates it with the channel values
+// bound as variables. Calculations using other calculations are
+// evaluated after them.
+pub struct CalculationEngine {
+    engine: Engine,
+    modbus: ScriptModbus,
+    // Compilation result by calculation, with the source it came from.
+    compiled: HashMap<usize, (String, Result<CompiledCalculation, CalculationError>)>,
+    devices: Vec<DeviceTags>,
+    // Device names and codes.
+    device_names: HashMap<String, Binding<usize>>,
+    // Channel names, for the bare references.
+    channel_names: HashMap<String, Binding<(usize, usize)>>,
+    // Device names, codes and channel names the names were built from.
+    layout: Vec<(String, String, Vec<String>)>,
+    calculations: Vec<CalculationTag>,
+    calculation_names: HashMap<String, Binding<usize>>,
+    // Calculation indexes, each one after the calculations it uses.
+    order: Vec<usize>,
+    // The cycle error of the calculations using each other.
+    cycles: HashMap<usize, String>,
+    scope: Scope<'static>,
+}
+
+impl CalculationEngine {
+    pub fn new(modbus: ScriptModbus) -> Self {
+        let mut engine = Engine::new();
+        // Unknown tags are reported when compiling.
+        engine.set_strict_variables(true);
+        modbus.register(&mut engine);
+
+        Self {
+            engine,
+            modbus,
+            compiled: HashMap::new(),
+            devices: Vec::new(),
+            device_names: HashMap::new(),
+            channel_names: HashMap::new(),
+            layout: Vec::new(),
+            calculations: Vec::new(),
+            calculation_names: HashMap::new(),
+            order: Vec::new(),
+            cycles: HashMap::new(),
+            scope: Scope::new(),
+        }
+    }
+
+    // Take the latest polled values before evaluating.
+    pub fn update_devices(&mut self, devices: &[ModbusDevice]) {
+        let renamed = devices.len() != self.layout.len()
+            || devices
+                .iter()
+                .zip(&self.layout)
+                .any(|(device, (name, code, channels))| {
+                    device.name != *name
+                        || device.code != *code
+                        || device.channels.len() != channels.len()
+                        || device
+                            .channels
+                            .iter()
+                            .zip(channels)
+                            .any(|(c, name)| c.name != *name)
+                });
+        if renamed {
+            self.rebuild_names(devices);
+        }
+
+        self.devices = devices
+            .iter()
+            .zip(std::mem::take(&mut self.devices))
+            .map(|(device, tags)| {
+                let channels: Vec<TagValue> = device
+                    .channels
+                    .iter()
+                    .map(|channel| tag_value(device, channel))
+                    .collect();
+                let map: Map = device
+                    .channels
+                    .iter()
+                    .zip(&channels)
+                    .map(|(channel, tag)| (channel.name.as_str().into(), tag.value.clone()))
+                    .collect();
+
+                DeviceTags {
+                    map: Dynamic::from_map(map).into_shared(),
+                    channels,
+                    names: tags.names,
+                }
+            })
+            .collect();
+
+        self.modbus.set_devices(devices.to_vec());
+    }
+
+    // Channels were added, removed or renamed, compile everything again.
+    fn rebuild_names(&mut self, devices: &[ModbusDevice]) {
+        self.compiled.clear();
+        self.device_names.clear();
+        self.channel_names.clear();
+        self.devices.clear();
+        self.layout = devices
+            .iter()
+            .map(|device| {
+                let channels = device.channels.iter().map(|c| c.name.clone()).collect();
+                (device.name.clone(), device.code.clone(), channels)
+            })
+            .collect();
+
+        for (index, device) in devices.iter().enumerate() {
+            insert_binding(&mut self.device_names, &device.name, index);
+            insert_binding(&mut self.device_names, &device.code, index);
+
+            let mut names = HashMap::new();
+            for (channel_index, channel) in device.channels.iter().enumerate() {
+                names.entry(channel.name.clone()).or_insert(channel_index);
+                insert_binding(
+                    &mut self.channel_names,
+                    &channel.name,
+                    (index, channel_index),
+                );
+            }
+            self.devices.push(DeviceTags {
+                map: Dynamic::UNIT,
+                channels: Vec::new(),
+                names,
+            });
+        }
+
+        // The cycles are found again, they were reported when configured.
+        self.sort().ok();
+    }
+
+    // Compile the calculations and find the order to evaluate them in.
+    // The device names must be known, see `update_devices`. Calculations
+    // using each other are reported and fail on every evaluation.
+    pub fn configure(&mut self, channels: &[CalculationChannel]) -> Result<()> {
+        let renamed = channels.len() != self.calculations.len()
+            || channels
+                .iter()
+                .zip(&self.calculations)
+                .any(|(channel, calculation)| {
+                    channel.id != calculation.id || channel.name != calculation.name
+                });
+        if renamed {
+            self.compiled.clear();
+            self.calculation_names.clear();
+            for (index, channel) in channels.iter().enumerate() {
+                insert_binding(&mut self.calculation_names, &channel.name, index);
+            }
+        }
+
+        self.calculations = channels
+            .iter()
+            .map(|channel| CalculationTag {
+                id: channel.id,
+                name: channel.name.clone(),
+                source: channel.calculation.clone(),
+                result: TagValue {
+                    value: Dynamic::from_float(channel.value),
+                    // Disabled calculations keep their last value.
+                    quality: match channel.enabled {
+                        true => channel.quality.clone(),
+                        false => ChannelQuality::Stale,
+                    },
+                },
+            })
+            .collect();
+
+        self.sort()
+    }
+
+    // Calculation indexes in evaluation order.
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    // Whether a configured calculation compiles and isn't in a cycle.
+    pub fn check(&self, id: usize) -> Result<(), CalculationError> {
+        let index = self.calculations.iter().position(|c| c.id == id);
+        let error = match index.map(|index| (self.compiled.get(&index), self.cycles.get(&index))) {
+            Some((Some((_, Err(e))), _)) => return Err(e.clone()),
+            Some((_, Some(cycle))) => cycle.clone(),
+            Some((Some((_, Ok(_))), None)) => return Ok(()),
+            Some((None, None)) | None => format!("Calculation {id} isn't configured"),
+        };
+
+        Err(CalculationError {
+            message: error,
+            position: None,
+        })
+    }
+
+    // Sort the calculations so each one comes after the ones it uses.
+    fn sort(&mut self) -> Result<()> {
+        let count = self.calculations.len();
+        let mut dependencies = Vec::with_capacity(count);
+        for index in 0..count {
+            let source = self.calculations[index].source.clone();
+            let calculations = match self.compile_if_changed(index, &source) {
+                Ok(compiled) => compiled.calculations.clone(),
+                Err(_) => BTreeSet::new(),
+            };
+            dependencies.push(calculations);
+        }
+
+        let mut users = vec![Vec::new(); count];
+        for (index, calculations) in dependencies.iter().enumerate() {
+            for calculation in calculations {
+                users[*calculation].push(index);
+            }
+        }
+
+        // Kahn's algorithm, the calculations left over use a cycle.
+        let mut waiting: Vec<usize> = dependencies.iter().map(BTreeSet::len).collect();
+        let ready: VecDeque<usize> = (0..count).filter(|i| waiting[*i] == 0).collect();
+        self.order.clear();
+        take_ready(&mut self.order, &users, &mut waiting, ready);
+
+        // Drop the ones only using a cycle, the rest is in one.
+        let mut in_cycle: Vec<bool> = waiting.iter().map(|w| *w > 0).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in 0..count {
+                if in_cycle[index] && !users[index].iter().any(|user| in_cycle[*user]) {
+                    in_cycle[index] = false;
+                    changed = true;
+                }
+            }
+        }
+        // They are still evaluated: the cycle first, with its last
+        // results, then the calculations using it.
+        let mut ready = VecDeque::new();
+        for index in (0..count).filter(|index| in_cycle[*index]) {
+            self.order.push(index);
+            for user in &users[index] {
+                if !in_cycle[*user] {
+                    waiting[*user] -= 1;
+                    if waiting[*user] == 0 {
+                        ready.push_back(*user);
+                    }
+                }
+            }
+        }
+        take_ready(&mut self.order, &users, &mut waiting, ready);
+
+        // One error per cycle, with the calculations linked to it.
+        self.cycles.clear();
+        let mut errors = Vec::new();
+        for start in 0..count {
+            if !in_cycle[start] || self.cycles.contains_key(&start) {
+                continue;
+            }
+
+            let mut cycle = BTreeSet::from([start]);
+            let mut pending = vec![start];
+            while let Some(index) = pending.pop() {
+                let linked = users[index].iter().chain(&dependencies[index]);
+                for other in linked {
+                    if in_cycle[*other] && cycle.insert(*other) {
+                        pending.push(*other);
                     }
                 }
             }
+
+            let names: Vec<&str> = cycle
+                .iter()
+                .map(|index| self.calculations[*index].name.as_str())
+                .collect();
+            let error = format!("Calculation cycle between {}", names.join(", "));
+            for index in cycle {
+                self.cycles.insert(index, error.clone());
+            }
+            errors.push(error);
         }
 
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
         Ok(())
     }
+
+    // The calculation result and the worst quality of its tags.
+    fn evaluate(&mut self, id: usize, source: &str) -> Result<(f64, ChannelQuality)> {
+        let Some(index) = self.calculations.iter().position(|c| c.id == id) else {
+            anyhow::bail!("Calculation {id} isn't configured");
+        };
+        if self.calculations[index].source != source {
+            // Edited, the order may change too.
+            self.calculations[index].source = source.to_owned();
+            self.sort().ok();
+        }
+
+        let result = self.run(index);
+        let calculation = &mut self.calculations[index];
+        match &result {
+            Ok((value, quality)) => {
+                calculation.result = TagValue {
+                    value: Dynamic::from_float(*value),
+                    quality: quality.clone(),
+                }
+            }
+            Err(e) => {
+                calculation.result.quality =
+                    ChannelQuality::Bad(format!("{}: {e}", calculation.name))
+            }
+        }
+
+        result
+    }
+
+    fn run(&mut self, index: usize) -> Result<(f64, ChannelQuality)> {
+        if let Some(cycle) = self.cycles.get(&index) {
+            anyhow::bail!("{cycle}");
+        }
+        let compiled = match self.compiled.get(&index) {
+            Some((_, Ok(compiled))) => compiled,
+            Some((_, Err(e))) => anyhow::bail!("{e}"),
+            None => anyhow::bail!("Calculation isn't compiled"),
+        };
+
+        let mut quality = ChannelQuality::Good;
+        for (device, channel) in &compiled.tags {
+            let tag = &self.devices[*device].channels[*channel];
+            quality = quality.worst(tag.quality.clone());
+        }
+        for calculation in &compiled.calculations {
+            quality = quality.worst(self.calculations[*calculation].result.quality.clone());
+        }
+
+        self.scope.clear();
+        for variable in &compiled.variables {
+            let value = match (
+                self.device_names.get(variable),
+                self.calculation_names.get(variable),
+                self.channel_names.get(variable),
+            ) {
+                (Some(Binding::Unique(device)), _, _) => self.devices[*device].map.clone(),
+                (_, Some(Binding::Unique(calculation)), _) => {
+                    self.calculations[*calculation].result.value.clone()
+                }
+                (_, _, Some(Binding::Unique((device, channel)))) => {
+                    self.devices[*device].channels[*channel].value.clone()
+                }
+                _ => continue,
+            };
+            self.scope.push_constant_dynamic(variable.clone(), value);
+        }
+
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut self.scope, &compiled.ast)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        let value = match result.as_float() {
+            Ok(v) => v,
+            Err(_) => match result.as_int() {
+                Ok(v) => v as f64,
+                Err(type_name) => anyhow::bail!("The calculation returned a {type_name}"),
+            },
+        };
+
+        Ok((value, quality))
+    }
+
+    fn compile_if_changed(
+        &mut self,
+        index: usize,
+        source: &str,
+    ) -> &Result<CompiledCalculation, CalculationError> {
+        let changed = match self.compiled.get(&index) {
+            Some((compiled_source, _)) => compiled_source != source,
+            None => true,
+        };
+        if changed {
+            let compiled = self.compile(source);
+            self.compiled.insert(index, (source.to_owned(), compiled));
+        }
+
+        &self.compiled[&index].1
+    }
+
+    fn compile(&self, source: &str) -> Result<CompiledCalculation, CalculationError> {
+        let mut variables = BTreeSet::new();
+        let mut tags = BTreeSet::new();
+        let mut calculations = BTreeSet::new();
+
+        for captures in REFERENCE.captures_iter(source) {
+            let Some(name) = captures.get(2) else {
+                // A string or a comment.
+                continue;
+            };
+            if captures.get(1).is_some() {
+                continue;
+            }
+            let member = captures.get(3).or(captures.get(4));
+            let position = Some(text_position(source, name.start()));
+            let name = name.as_str();
+
+            match (self.device_names.get(name), member) {
+                (Some(Binding::Ambiguous), _) => {
+                    return Err(CalculationError {
+                        message: format!("Ambiguous device {name}, more than one device uses it"),
+                        position,
+                    });
+                }
+                (Some(Binding::Unique(device)), Some(member)) => {
+                    let Some(channel) = self.devices[*device].names.get(member.as_str()) else {
+                        return Err(CalculationError {
+                            message: format!("Unknown tag {name}.{}", member.as_str()),
+                            position,
+                        });
+                    };
+                    tags.insert((*device, *channel));
+                }
+                // The whole device is used, e.g. indexed by a variable.
+                (Some(Binding::Unique(device)), None) => {
+                    tags.extend((0..self.devices[*device].names.len()).map(|c| (*device, c)));
+                }
+                (None, _) => match (
+                    self.calculation_names.get(name),
+                    self.channel_names.get(name),
+                ) {
+                    (Some(Binding::Unique(calculation)), None) => {
+                        calculations.insert(*calculation);
+                    }
+                    (Some(_), _) => {
+                        return Err(CalculationError {
+                            message: format!("Ambiguous tag {name}, more than one channel uses it"),
+                            position,
+                        });
+                    }
+                    (None, Some(Binding::Ambiguous)) => {
+                        return Err(CalculationError {
+                            message: format!(
+                                "Ambiguous tag {name}, qualify it with its device, e.g. Device_1.{name}"
+                            ),
+                            position,
+                        });
+                    }
+                    (None, Some(Binding::Unique(tag))) => {
+                        tags.insert(*tag);
+                    }
+                    // A local variable, a function or an unknown tag
+                    // the compiler reports.
+                    (None, None) => continue,
+                },
+            }
+            variables.insert(name.to_owned());
+        }
+
+        // The variables are only declared, their values
+        // are bound on every evaluation.
+        let mut scope = Scope::new();
+        for variable in &variables {
+            scope.push_dynamic(variable.clone(), Dynamic::UNIT);
+        }
+        let ast = self
+            .engine
+            .compile_with_scope(&scope, source)
+            .map_err(|e| {
+                let message = match e.err_type() {
+                    ParseErrorType::VariableUndefined(name) => format!("Unknown tag {name}"),
+                    other => format!("{other}"),
+                };
+                let position = e.position();
+                CalculationError {
+                    message,
+                    position: position.line().zip(position.position()),
+                }
+            })?;
+
+        Ok(CompiledCalculation {
+            ast,
+            variables,
+            tags,
+            calculations,
+        })
+    }
+}
+
+fn tag_value(device: &ModbusDevice, channel: &ModbusChannel) -> TagValue {
+    // Bits are seen as 0 or 1 by the calculation.
+    let value = match &channel.value {
+        ModbusValue::Int(v) => Dynamic::from_int(*v),
+        ModbusValue::UInt(v) => match i64::try_from(*v) {
+            Ok(v) => Dynamic::from_int(v),
+            Err(_) => Dynamic::from_float(*v as f64),
+        },
+        ModbusValue::Real(v) => Dynamic::from_float(*v),
+        ModbusValue::Bool(v) => Dynamic::from_int(i64::from(*v)),
+        ModbusValue::Str(v) => Dynamic::from(v.clone()),
+    };
+    let quality = match &channel.quality {
+        ChannelQuality::Bad(reason) => {
+            ChannelQuality::Bad(format!("{}.{}: {reason}", device.name, channel.name))
+        }
+        ChannelQuality::Uncertain(reason) => {
+            ChannelQuality::Uncertain(format!("{}.{}: {reason}", device.name, channel.name))
+        }
+        other => other.clone(),
+    };
+
+    TagValue { value, quality }
+}
+
+// Line and column of a byte offset, counted from 1 like rhai does.
+fn text_position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
 }
 
 // A convenience method to construct a channel array.
-pub fn init_channel_list(n: usize) -> Vec<CalculationChannel> {
+pub fn init_channel_list(device: &str, n: usize) -> Vec<CalculationChannel> {
     let mut channel_list = Vec::with_capacity(n);
 
     for i in 1..=n {
-        // The 0.0 is a temporal fix for a weird bug.
-        let calculation = format!("MB{i} + MB{i} + 0.0");
+        let calculation = format!("{device}.MB{i} + {device}.MB{i}");
         let channel = CalculationChannel {
             enabled: true,
             id: i,
             name: format!("CH{i}"),
             calculation,
             value: 0.0,
+            quality: ChannelQuality::NotYetRead,
             error: None,
         };
 
@@ -85,3 +658,258 @@ pub fn init_channel_list(n: usize) -> Vec<CalculationChannel> {
 
     channel_list
 }
+
+// Append the ready calculations to the order, then the users
+// they leave with nothing to wait for.
+fn take_ready(
+    order: &mut Vec<usize>,
+    users: &[Vec<usize>],
+    waiting: &mut [usize],
+    mut ready: VecDeque<usize>,
+) {
+    while let Some(index) = ready.pop_front() {
+        order.push(index);
+        for user in &users[index] {
+            waiting[*user] -= 1;
+            if waiting[*user] == 0 {
+                ready.push_back(*user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Device_1 (D1) and Device_2 (D2) with channels MB1-MB3,
+    // MB1 is 1.0 and 2.0 on them.
+    fn devices() -> Vec<ModbusDevice> {
+        (1..=2)
+            .map(|i| {
+                let mut device =
+                    init_mb_tcp_device("127.0.0.1".to_owned(), 502, format!("Device_{i}"), 3);
+                device.id = i;
+                device.code = format!("D{i}");
+                device.channels[0].value = ModbusValue::Real(i as f64);
+                device.channels[0].quality = ChannelQuality::Good;
+                device
+            })
+            .collect()
+    }
+
+    fn engine(devices: &[ModbusDevice]) -> CalculationEngine {
+        let (sender_script_call, _) = tokio::sync::mpsc::channel(1);
+        let mut engine = CalculationEngine::new(ScriptModbus::periodic(sender_script_call));
+        engine.update_devices(devices);
+        engine
+    }
+
+    fn calculation(id: usize, calculation: &str) -> CalculationChannel {
+        CalculationChannel {
+            enabled: true,
+            id,
+            name: format!("CH{id}"),
+            calculation: calculation.to_owned(),
+            value: 0.0,
+            quality: ChannelQuality::NotYetRead,
+            error: None,
+        }
+    }
+
+    // The compile error of a single calculation.
+    fn compile_error(devices: &[ModbusDevice], source: &str) -> CalculationError {
+        let mut engine = engine(devices);
+        engine.configure(&[calculation(1, source)]).unwrap();
+        engine.check(1).unwrap_err()
+    }
+
+    fn evaluate(devices: &[ModbusDevice], source: &str) -> (f64, ChannelQuality) {
+        let mut channel = calculation(1, source);
+        let mut engine = engine(devices);
+        engine.configure(&[channel.clone()]).unwrap();
+        channel.evaluate(&mut engine).unwrap();
+        (channel.value, channel.quality)
+    }
+
+    #[test]
+    fn tags_resolve_by_device_name_and_code() {
+        let devices = devices();
+
+        assert_eq!(evaluate(&devices, "Device_1.MB1 + D2.MB1").0, 3.0);
+        assert_eq!(evaluate(&devices, r#"Device_2["MB1"] * 10"#).0, 20.0);
+    }
+
+    #[test]
+    fn any_channel_name_can_be_used() {
+        let mut devices = devices();
+        devices[1].channels[0].name = "Flow rate".to_owned();
+        devices[1].channels[1].name = "Level".to_owned();
+
+        assert_eq!(evaluate(&devices, r#"Device_2["Flow rate"] + 1"#).0, 3.0);
+        // A name only one device uses needs no device.
+        assert_eq!(evaluate(&devices, "Level").0, 3.0);
+    }
+
+    #[test]
+    fn qualities_of_the_tags_used_are_combined() {
+        let mut devices = devices();
+        devices[1].channels[0].quality = ChannelQuality::Bad("Timeout".to_owned());
+
+        assert_eq!(evaluate(&devices, "Device_1.MB1").1, ChannelQuality::Good);
+        assert_eq!(
+            evaluate(&devices, "Device_1.MB1 + Device_2.MB1").1,
+            ChannelQuality::Bad("Device_2.MB1: Timeout".to_owned())
+        );
+    }
+
+    #[test]
+    fn unknown_channels_are_reported_where_used() {
+        assert_eq!(
+            compile_error(&devices(), "1 +\n  Device_1.MB9"),
+            CalculationError {
+                message: "Unknown tag Device_1.MB9".to_owned(),
+                position: Some((2, 3)),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_devices_are_reported_where_used() {
+        assert_eq!(
+            compile_error(&devices(), "Device_1.MB1 + Device_9.MB1"),
+            CalculationError {
+                message: "Unknown tag Device_9".to_owned(),
+                position: Some((1, 16)),
+            }
+        );
+    }
+
+    #[test]
+    fn names_used_by_several_devices_are_ambiguous() {
+        let error = compile_error(&devices(), "MB1 + 1");
+        assert_eq!(
+            error.message,
+            "Ambiguous tag MB1, qualify it with its device, e.g. Device_1.MB1"
+        );
+
+        let mut devices = devices();
+        devices[1].code = "D1".to_owned();
+        let error = compile_error(&devices, "D1.MB1");
+        assert_eq!(
+            error.message,
+            "Ambiguous device D1, more than one device uses it"
+        );
+    }
+
+    #[test]
+    fn strings_comments_and_members_are_not_tags() {
+        let source = r#"
+            // Device_9.MB1 isn't read
+            let text = "Device_9.MB1";
+            (Device_1.MB1 - 3.0).abs() + text.len()
+        "#;
+
+        assert_eq!(evaluate(&devices(), source).0, 14.0);
+    }
+
+    fn calculations(sources: &[&str]) -> Vec<CalculationChannel> {
+        sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| calculation(index + 1, source))
+            .collect()
+    }
+
+    #[test]
+    fn calculations_come_after_the_ones_they_use() {
+        let mut channels = calculations(&["CH2 + 1", "Device_1.MB1", "CH1 * 2", "CH1 + CH3"]);
+        let mut engine = engine(&devices());
+        engine.configure(&channels).unwrap();
+
+        assert_eq!(engine.order(), &[1, 0, 2, 3]);
+        let order = engine.order().to_vec();
+        for index in order {
+            channels[index].evaluate(&mut engine).unwrap();
+        }
+        let values: Vec<f64> = channels.iter().map(|channel| channel.value).collect();
+        assert_eq!(values, vec![2.0, 1.0, 4.0, 6.0]);
+        assert_eq!(channels[3].quality, ChannelQuality::Good);
+    }
+
+    #[test]
+    fn cycles_are_reported_and_fail_on_evaluation() {
+        let mut channels =
+            calculations(&["Device_1.MB1", "CH5 + 1", "CH4 + 1", "CH3 + 1", "CH3 * 2"]);
+        let mut engine = engine(&devices());
+
+        let error = engine.configure(&channels).unwrap_err();
+        assert_eq!(format!("{error}"), "Calculation cycle between CH3, CH4");
+        assert_eq!(
+            engine.check(3).unwrap_err().message,
+            "Calculation cycle between CH3, CH4"
+        );
+        // Only using a cycle isn't being in it.
+        assert!(engine.check(5).is_ok());
+        assert!(engine.check(2).is_ok());
+
+        // The cycle is still evaluated, then what uses it in order.
+        assert_eq!(engine.order(), &[0, 2, 3, 4, 1]);
+        let order = engine.order().to_vec();
+        let results: Vec<bool> = order
+            .into_iter()
+            .map(|index| channels[index].evaluate(&mut engine).is_ok())
+            .collect();
+        assert_eq!(results, vec![true, false, false, true, true]);
+        // CH5 comes after the cycle, so it gets its failed quality.
+        assert_eq!(
+            channels[4].quality,
+            ChannelQuality::Bad("CH3: Calculation cycle between CH3, CH4".to_owned())
+        );
+    }
+
+    #[test]
+    fn each_cycle_gets_its_own_message() {
+        let channels = calculations(&["CH2", "CH1", "CH3 + 1", "CH1 + CH3"]);
+        let mut engine = engine(&devices());
+
+        let error = engine.configure(&channels).unwrap_err();
+        assert_eq!(
+            format!("{error}"),
+            "Calculation cycle between CH1, CH2; Calculation cycle between CH3"
+        );
+        assert!(engine.check(4).is_ok());
+
+        // Fixed by editing the source.
+        let channels = calculations(&["CH2", "1", "2", "CH1 + CH3"]);
+        engine.configure(&channels).unwrap();
+        assert_eq!(engine.order(), &[1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn calculations_are_saved_without_their_results() {
+        let mut channels = calculations(&["Device_1.MB1 * 2", "CH1 + 1"]);
+        channels[1].enabled = false;
+        channels[0].value = 2.0;
+        channels[0].quality = ChannelQuality::Good;
+        channels[0].error = Some("Stale".to_owned());
+
+        let text = ron::to_string(&channels).unwrap();
+        let saved: Vec<CalculationChannel> = ron::from_str(&text).unwrap();
+
+        let fields: Vec<(usize, &str, &str, bool)> = saved
+            .iter()
+            .map(|c| (c.id, c.name.as_str(), c.calculation.as_str(), c.enabled))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (1, "CH1", "Device_1.MB1 * 2", true),
+                (2, "CH2", "CH1 + 1", false),
+            ]
+        );
+        assert_eq!(saved[0].value, 0.0);
+        assert_eq!(saved[0].quality, ChannelQuality::NotYetRead);
+        assert_eq!(saved[0].error, None);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt::Display;
+
+// How far a channel value can be trusted.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+pub enum ChannelQuality {
+    // Nothing has been read since the app started.
+    #[default]
+    NotYetRead,
+    Good,
+    // The value was read but is doubtful, e.g. clamped.
+    Uncertain(String),
+    // The last read failed, the value is the last good one.
+    Bad(String),
+    // Several scans were missed, e.g. while disconnected.
+    Stale,
+}
+
+impl ChannelQuality {
+    pub fn is_good(&self) -> bool {
+        matches!(self, ChannelQuality::Good)
+    }
+
+    // Higher is worse, used to combine qualities.
+    fn severity(&self) -> u8 {
+        match self {
+            ChannelQuality::Good => 0,
+            ChannelQuality::Uncertain(_) => 1,
+            ChannelQuality::Stale => 2,
+            ChannelQuality::Bad(_) => 3,
+            ChannelQuality::NotYetRead => 4,
+        }
+    }
+
+    // The worse of the two qualities, the first one on a tie.
+    pub fn worst(self, other: ChannelQuality) -> ChannelQuality {
+        if other.severity() > self.severity() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl Display for ChannelQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelQuality::NotYetRead => write!(f, "NOT READ"),
+            ChannelQuality::Good => write!(f, "GOOD"),
+            ChannelQuality::Uncertain(reason) => write!(f, "UNCERTAIN: {reason}"),
+            ChannelQuality::Bad(reason) => write!(f, "BAD: {reason}"),
+            ChannelQuality::Stale => write!(f, "STALE"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_decode::*;
+    use crate::modbus_device::*;
+    use std::time::SystemTime;
+
+    fn bad(reason: &str) -> ChannelQuality {
+        ChannelQuality::Bad(reason.to_owned())
+    }
+
+    fn uncertain(reason: &str) -> ChannelQuality {
+        ChannelQuality::Uncertain(reason.to_owned())
+    }
+
+    fn channel() -> ModbusChannel {
+        ModbusChannel::new(
+            1,
+            "Pressure".to_owned(),
+            ModbusRegisterSpace::HoldingRegister,
+            0,
+            ModbusChannelType::U16,
+            ModbusByteOrder::Abcd,
+        )
+    }
+
+    #[test]
+    fn worst_orders_by_severity() {
+        let ordered = [
+            ChannelQuality::Good,
+            uncertain("Clamped"),
+            ChannelQuality::Stale,
+            bad("Timeout"),
+            ChannelQuality::NotYetRead,
+        ];
+
+        for (i, better) in ordered.iter().enumerate() {
+            for worse in &ordered[i..] {
+                assert_eq!(better.clone().worst(worse.clone()), *worse);
+                assert_eq!(worse.clone().worst(better.clone()), *worse);
+            }
+        }
+    }
+
+    #[test]
+    fn worst_keeps_the_first_reason_on_a_tie() {
+        assert_eq!(bad("First").worst(bad("Second")), bad("First"));
+        assert_eq!(
+            uncertain("First").worst(uncertain("Second")),
+            uncertain("First")
+        );
+    }
+
+    #[test]
+    fn read_results_set_the_quality() {
+        let mut channel = channel();
+        channel.clamp = Some((0.0, 100.0));
+
+        channel.set_read_result(Ok(ModbusValue::UInt(50)), SystemTime::now());
+        assert_eq!(channel.quality, ChannelQuality::Good);
+
+        channel.set_read_result(Ok(ModbusValue::UInt(150)), SystemTime::now());
+        assert_eq!(channel.quality, uncertain("Clamped to range"));
+        assert_eq!(channel.value, ModbusValue::Real(100.0));
+
+        // A failed read keeps the last value.
+        channel.set_read_result(Err(anyhow::anyhow!("Short response")), SystemTime::now());
+        assert_eq!(channel.quality, bad("Short response"));
+        assert_eq!(channel.value, ModbusValue::Real(100.0));
+    }
+
+    #[test]
+    fn missed_scans_make_read_channels_stale() {
+        let mut channel = channel();
+
+        // Channels never read stay that way.
+        assert!(!channel.set_missed_scan(1));
+        assert_eq!(channel.quality, ChannelQuality::NotYetRead);
+
+        channel.set_read_result(Ok(ModbusValue::UInt(1)), SystemTime::now());
+        assert!(!channel.set_missed_scan(2));
+        assert_eq!(channel.quality, ChannelQuality::Good);
+        assert!(channel.set_missed_scan(2));
+        assert_eq!(channel.quality, ChannelQuality::Stale);
+        // Only reported once.
+        assert!(!channel.set_missed_scan(2));
+
+        channel.set_read_result(Ok(ModbusValue::UInt(2)), SystemTime::now());
+        assert_eq!(channel.quality, ChannelQuality::Good);
+        assert_eq!(channel.missed_scans, 0);
+    }
+}
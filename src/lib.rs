@@ -2,10 +2,38 @@
 
 mod app;
 mod calculation_channel;
+mod channel_quality;
+mod modbus_block;
+mod modbus_connection;
+mod modbus_decode;
 mod modbus_device;
+mod modbus_diagnostics;
+mod modbus_gateway;
+mod modbus_identification;
+mod modbus_image;
+mod modbus_scaling;
+mod modbus_scan;
+mod modbus_scanner;
+mod modbus_script;
+mod modbus_simulator;
+mod modbus_traffic;
 mod ui;
 
 pub use app::ColossalApp;
 pub use calculation_channel::*;
+pub use channel_quality::*;
+pub use modbus_block::*;
+pub use modbus_connection::*;
+pub use modbus_decode::*;
 pub use modbus_device::*;
+pub use modbus_diagnostics::*;
+pub use modbus_gateway::*;
+pub use modbus_identification::*;
+pub use modbus_image::*;
+pub use modbus_scaling::*;
+pub use modbus_scan::*;
+pub use modbus_scanner::*;
+pub use modbus_script::*;
+pub use modbus_simulator::*;
+pub use modbus_traffic::*;
 pub use ui::*;
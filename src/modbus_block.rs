@@ -0,0 +1,263 @@
+use crate::modbus_device::*;
+
+// Protocol limits for a single read request.
+pub(crate) const MAX_READ_REGISTERS: u16 = 125;
+const MAX_READ_BITS: u16 = 2000;
+
+// How the poller groups channels into block reads and writes them back.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ModbusBlockConfig {
+    // Largest block in registers (or bits for coils/DI).
+    pub max_block_size: u16,
+    // Unused addresses allowed between two channels of a block.
+    pub max_gap: u16,
+    // Always write with FC15/FC16, for devices without FC05/FC06.
+    #[serde(default)]
+    pub use_multiple_write: bool,
+    // Write holding registers with FC23, reading them back in the same request.
+    #[serde(default)]
+    pub read_back_writes: bool,
+}
+
+impl Default for ModbusBlockConfig {
+    fn default() -> Self {
+        Self {
+            max_block_size: 100,
+            max_gap: 4,
+            use_multiple_write: false,
+            read_back_writes: false,
+        }
+    }
+}
+
+// A single read request covering one or more channels.
+#[derive(Clone, Debug)]
+pub struct ModbusReadBlock {
+    pub register_space: ModbusRegisterSpace,
+    pub address: u16,
+    pub count: u16,
+    // Indexes into the device channel list.
+    pub channels: Vec<usize>,
+}
+
+impl ModbusReadBlock {
+    fn end(&self) -> u32 {
+        self.address as u32 + self.count as u32
+    }
+}
+
+// Group the selected channels into contiguous blocks per register space.
+// Disabled channels and channels no request can read are skipped.
+pub fn plan_read_blocks(
+    channels: &[ModbusChannel],
+    selection: &[usize],
+    config: &ModbusBlockConfig,
+) -> Vec<ModbusReadBlock> {
+    let mut blocks = Vec::new();
+
+    for register_space in [
+        ModbusRegisterSpace::Coil,
+        ModbusRegisterSpace::DiscreteInput,
+        ModbusRegisterSpace::InputRegister,
+        ModbusRegisterSpace::HoldingRegister,
+    ] {
+        let limit = if register_space.is_bit() {
+            MAX_READ_BITS
+        } else {
+            MAX_READ_REGISTERS
+        };
+        let max_block_size = config.max_block_size.clamp(1, limit) as u32;
+
+        let mut indexes: Vec<usize> = selection
+            .iter()
+            .copied()
+            .filter(|index| {
+                channels.get(*index).is_some_and(|channel| {
+                    channel.enabled
+                        && channel.register_space == register_space
+                        && channel.channel_type.check().is_ok()
+                })
+            })
+            .collect();
+        indexes.sort_by_key(|index| channels[*index].address);
+
+        let mut current: Option<ModbusReadBlock> = None;
+        for index in indexes {
+            let channel = &channels[index];
+            let count = if register_space.is_bit() {
+                1
+            } else {
+                channel.channel_type.register_count()
+            };
+            let start = channel.address as u32;
+            let end = start + count as u32;
+
+            if let Some(block) = current.as_mut() {
+                let new_end = end.max(block.end());
+                let fits_gap = start <= block.end() + config.max_gap as u32;
+                let fits_size = new_end - block.address as u32 <= max_block_size;
+
+                if fits_gap && fits_size {
+                    block.count = (new_end - block.address as u32) as u16;
+                    block.channels.push(index);
+                    continue;
+                }
+            }
+
+            if let Some(block) = current.take() {
+                blocks.push(block);
+            }
+            current = Some(ModbusReadBlock {
+                register_space,
+                address: channel.address,
+                count,
+                channels: vec![index],
+            });
+        }
+
+        if let Some(block) = current {
+            blocks.push(block);
+        }
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        channel_type: ModbusChannelType,
+    ) -> ModbusChannel {
+        let mut device = init_mb_tcp_device("127.0.0.1".to_owned(), 502, "PLC".to_owned(), 1);
+        let mut channel = device.channels.remove(0);
+        channel.register_space = register_space;
+        channel.address = address;
+        channel.channel_type = channel_type;
+        channel
+    }
+
+    fn plan(channels: &[ModbusChannel], config: &ModbusBlockConfig) -> Vec<(u16, u16, usize)> {
+        let selection: Vec<usize> = (0..channels.len()).collect();
+
+        plan_read_blocks(channels, &selection, config)
+            .iter()
+            .map(|block| (block.address, block.count, block.channels.len()))
+            .collect()
+    }
+
+    #[test]
+    fn adjacent_channels_share_a_block() {
+        let channels: Vec<ModbusChannel> = [4, 0, 2]
+            .into_iter()
+            .map(|address| {
+                channel(
+                    ModbusRegisterSpace::HoldingRegister,
+                    address,
+                    ModbusChannelType::F32,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            plan(&channels, &ModbusBlockConfig::default()),
+            vec![(0, 6, 3)]
+        );
+    }
+
+    #[test]
+    fn gaps_up_to_max_gap_are_read_through() {
+        let config = ModbusBlockConfig::default();
+        let space = ModbusRegisterSpace::HoldingRegister;
+
+        let within = [
+            channel(space, 0, ModbusChannelType::U16),
+            channel(space, 5, ModbusChannelType::U16),
+        ];
+        let beyond = [
+            channel(space, 0, ModbusChannelType::U16),
+            channel(space, 6, ModbusChannelType::U16),
+        ];
+
+        assert_eq!(plan(&within, &config), vec![(0, 6, 2)]);
+        assert_eq!(plan(&beyond, &config), vec![(0, 1, 1), (6, 1, 1)]);
+    }
+
+    #[test]
+    fn register_spaces_get_separate_blocks() {
+        let channels = [
+            channel(
+                ModbusRegisterSpace::HoldingRegister,
+                0,
+                ModbusChannelType::U16,
+            ),
+            channel(
+                ModbusRegisterSpace::InputRegister,
+                1,
+                ModbusChannelType::U16,
+            ),
+            channel(ModbusRegisterSpace::Coil, 2, ModbusChannelType::Bool),
+        ];
+
+        let blocks = plan_read_blocks(&channels, &[0, 1, 2], &ModbusBlockConfig::default());
+        let spaces: Vec<ModbusRegisterSpace> =
+            blocks.iter().map(|block| block.register_space).collect();
+
+        assert_eq!(
+            spaces,
+            vec![
+                ModbusRegisterSpace::Coil,
+                ModbusRegisterSpace::InputRegister,
+                ModbusRegisterSpace::HoldingRegister,
+            ]
+        );
+    }
+
+    #[test]
+    fn blocks_are_capped_at_the_protocol_limits() {
+        let config = ModbusBlockConfig {
+            max_block_size: u16::MAX,
+            ..Default::default()
+        };
+
+        let registers: Vec<ModbusChannel> = (0..70)
+            .map(|i| {
+                channel(
+                    ModbusRegisterSpace::HoldingRegister,
+                    i * 2,
+                    ModbusChannelType::F32,
+                )
+            })
+            .collect();
+        let coils: Vec<ModbusChannel> = (0..2100)
+            .map(|i| channel(ModbusRegisterSpace::Coil, i, ModbusChannelType::Bool))
+            .collect();
+
+        assert_eq!(plan(&registers, &config), vec![(0, 124, 62), (124, 16, 8)]);
+        assert_eq!(
+            plan(&coils, &config),
+            vec![(0, 2000, 2000), (2000, 100, 100)]
+        );
+    }
+
+    #[test]
+    fn unreadable_and_disabled_channels_are_skipped() {
+        let space = ModbusRegisterSpace::HoldingRegister;
+        let mut disabled = channel(space, 20, ModbusChannelType::U16);
+        disabled.enabled = false;
+        let channels = [
+            channel(space, 0, ModbusChannelType::String(0)),
+            channel(space, 10, ModbusChannelType::String(300)),
+            disabled,
+            channel(space, 30, ModbusChannelType::String(125)),
+        ];
+
+        assert_eq!(
+            plan(&channels, &ModbusBlockConfig::default()),
+            vec![(30, 125, 1)]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use crate::modbus_device::*;
+use crate::modbus_identification::*;
+use crate::modbus_traffic::*;
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// An open connection and the recorder of its traffic.
+pub struct ModbusSession {
+    pub ctx: tokio_modbus::client::Context,
+    pub traffic: TrafficRecorder,
+}
+
+// A connection slot for one endpoint. It stays empty
+// until a device connects and after a transport failure.
+pub type ModbusLink = Arc<tokio::sync::Mutex<Option<ModbusSession>>>;
+
+// Connections shared by all the devices polling the same
+// endpoint, e.g. a dozen unit IDs behind one TCP-to-RTU gateway.
+// Each device switches the slave ID before its requests.
+#[derive(Clone, Default)]
+pub struct ModbusConnections {
+    links: Arc<Mutex<HashMap<String, ModbusLink>>>,
+    traffic: TrafficMonitor,
+}
+
+impl ModbusConnections {
+    // Connections recording their requests to the monitor.
+    pub fn new(traffic: TrafficMonitor) -> Self {
+        Self {
+            links: Default::default(),
+            traffic,
+        }
+    }
+
+    // Get the link for the device endpoint, creating an empty one if needed.
+    pub fn link(&self, device: &ModbusDevice) -> ModbusLink {
+        // The link stays open between polls. Config changes
+        // close the old endpoint with `disconnect`.
+        let mut links = self.links.lock().unwrap();
+        links.entry(device.endpoint()).or_default().clone()
+    }
+
+    // Close the device endpoint so the next poll reconnects.
+    pub async fn disconnect(&self, device: &ModbusDevice) {
+        let link = self.link(device);
+        link.lock().await.take();
+    }
+
+    // Write a channel over the device link, connecting first if needed.
+    // Only transport errors close the link, Modbus exceptions are returned.
+    pub async fn write(
+        &self,
+        device: &mut ModbusDevice,
+        channel_id: usize,
+        value: &ModbusValue,
+    ) -> Result<()> {
+        let link = self.link(device);
+        let mut session = link.lock().await;
+        self.connect(device, &mut session).await?;
+
+        let result = match session.as_mut() {
+            Some(session) => {
+                device
+                    .write_channel(&mut session.ctx, channel_id, value, &session.traffic)
+                    .await
+            }
+            None => anyhow::bail!("{} is not connected", device.endpoint()),
+        };
+
+        match result {
+            Ok(result) => result.map_err(|e| anyhow::anyhow!("{e}")),
+            Err(e) => {
+                session.take();
+                Err(e)
+            }
+        }
+    }
+
+    // Send a call over the device link, connecting first if needed.
+    // Modbus exceptions are returned as errors but keep the link open.
+    pub async fn call(
+        &self,
+        device: &mut ModbusDevice,
+        call: ModbusCall,
+    ) -> Result<ModbusCallResponse> {
+        let link = self.link(device);
+        let mut session = link.lock().await;
+        self.connect(device, &mut session).await?;
+
+        let result = match session.as_mut() {
+            Some(session) => device.call(&mut session.ctx, call, &session.traffic).await,
+            None => anyhow::bail!("{} is not connected", device.endpoint()),
+        };
+
+        match result {
+            Ok(result) => result.map_err(|e| anyhow::anyhow!("{e}")),
+            Err(e) => {
+                session.take();
+                Err(e)
+            }
+        }
+    }
+
+    // Read the device identification over its link.
+    pub async fn read_identification(
+        &self,
+        device: &mut ModbusDevice,
+    ) -> Result<ModbusDeviceIdentification> {
+        if !device.supports_identification() {
+            anyhow::bail!("Device identification is only read over Modbus TCP");
+        }
+
+        let link = self.link(device);
+        let mut session = link.lock().await;
+        self.connect(device, &mut session).await?;
+
+        let result = match session.as_mut() {
+            Some(session) => {
+                device
+                    .read_identification(&mut session.ctx, &session.traffic)
+                    .await
+            }
+            None => anyhow::bail!("{} is not connected", device.endpoint()),
+        };
+
+        match result {
+            Ok(result) => result.map_err(|e| anyhow::anyhow!("{e}")),
+            Err(e) => {
+                session.take();
+                Err(e)
+            }
+        }
+    }
+
+    // Poll the device over its shared link, connecting first if needed.
+    // Any error closes the link so the next poll reconnects.
+    pub async fn poll(&self, device: &mut ModbusDevice, selection: &[usize]) -> Result<()> {
+        let link = self.link(device);
+        let mut session = link.lock().await;
+        self.connect(device, &mut session).await?;
+
+        let result = match session.as_mut() {
+            Some(session) => {
+                device
+                    .poll(&mut session.ctx, selection, &session.traffic)
+                    .await
+            }
+            None => anyhow::bail!("{} is not connected", device.endpoint()),
+        };
+
+        if result.is_err() {
+            session.take();
+        }
+
+        result
+    }
+
+    // Open the link if it isn't yet.
+    async fn connect(
+        &self,
+        device: &mut ModbusDevice,
+        session: &mut Option<ModbusSession>,
+    ) -> Result<()> {
+        if session.is_none() {
+            let traffic = self.traffic.recorder();
+            let ctx = device.connect_to_device(traffic.capture()).await?;
+            *session = Some(ModbusSession { ctx, traffic });
+            device.diagnostics.stats.record_connect();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt::Display;
+
+// Byte layout of values spanning several registers.
+// The letters name the bytes of the value from the most
+// significant (A) to the least significant (D), in the
+// order they arrive over the wire. For 64 bit values the
+// word swap reverses all four registers.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ModbusByteOrder {
+    // Big endian, first register holds the high word.
+    #[default]
+    Abcd,
+    // Word swapped, first register holds the low word.
+    Cdab,
+    // Byte swapped within each register.
+    Badc,
+    // Little endian, both words and bytes swapped.
+    Dcba,
+}
+
+impl ModbusByteOrder {
+    fn swaps_words(&self) -> bool {
+        matches!(self, ModbusByteOrder::Cdab | ModbusByteOrder::Dcba)
+    }
+
+    fn swaps_bytes(&self) -> bool {
+        matches!(self, ModbusByteOrder::Badc | ModbusByteOrder::Dcba)
+    }
+}
+
+impl Display for ModbusByteOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusByteOrder::Abcd => write!(f, "ABCD"),
+            ModbusByteOrder::Cdab => write!(f, "CDAB"),
+            ModbusByteOrder::Badc => write!(f, "BADC"),
+            ModbusByteOrder::Dcba => write!(f, "DCBA"),
+        }
+    }
+}
+
+// Combine the registers into one big endian integer,
+// undoing the device byte order on the way.
+fn combine_registers(registers: &[u16], order: ModbusByteOrder) -> u64 {
+    let mut words = registers.to_vec();
+    if order.swaps_words() {
+        words.reverse();
+    }
+
+    words.iter().fold(0, |acc, word| {
+        let word = if order.swaps_bytes() {
+            word.swap_bytes()
+        } else {
+            *word
+        };
+        (acc << 16) | word as u64
+    })
+}
+
+// Split a big endian integer into `count` registers
+// laid out in the device byte order.
+fn split_registers(value: u64, count: usize, order: ModbusByteOrder) -> Vec<u16> {
+    let mut words: Vec<u16> = (0..count)
+        .map(|i| {
+            let word = (value >> (16 * (count - 1 - i))) as u16;
+            if order.swaps_bytes() {
+                word.swap_bytes()
+            } else {
+                word
+            }
+        })
+        .collect();
+    if order.swaps_words() {
+        words.reverse();
+    }
+
+    words
+}
+
+pub fn decode_u32(registers: [u16; 2], order: ModbusByteOrder) -> u32 {
+    combine_registers(&registers, order) as u32
+}
+
+pub fn decode_u64(registers: [u16; 4], order: ModbusByteOrder) -> u64 {
+    combine_registers(&registers, order)
+}
+
+pub fn decode_f32(registers: [u16; 2], order: ModbusByteOrder) -> f32 {
+    f32::from_bits(decode_u32(registers, order))
+}
+
+pub fn decode_f64(registers: [u16; 4], order: ModbusByteOrder) -> f64 {
+    f64::from_bits(decode_u64(registers, order))
+}
+
+pub fn encode_u32(value: u32, order: ModbusByteOrder) -> Vec<u16> {
+    split_registers(value as u64, 2, order)
+}
+
+pub fn encode_u64(value: u64, order: ModbusByteOrder) -> Vec<u16> {
+    split_registers(value, 4, order)
+}
+
+pub fn encode_f32(value: f32, order: ModbusByteOrder) -> Vec<u16> {
+    encode_u32(value.to_bits(), order)
+}
+
+pub fn encode_f64(value: f64, order: ModbusByteOrder) -> Vec<u16> {
+    encode_u64(value.to_bits(), order)
+}
+
+// Packed decimal, one digit per nibble.
+pub fn decode_bcd(raw: u64, digits: u32) -> anyhow::Result<u64> {
+    let mut value = 0;
+    for i in (0..digits).rev() {
+        let digit = (raw >> (i * 4)) & 0xF;
+        if digit > 9 {
+            anyhow::bail!("Invalid BCD value {raw:#X}");
+        }
+        value = value * 10 + digit;
+    }
+
+    Ok(value)
+}
+
+pub fn encode_bcd(value: u64, digits: u32) -> anyhow::Result<u64> {
+    if value >= 10u64.pow(digits) {
+        anyhow::bail!("{value} doesn't fit in {digits} BCD digits");
+    }
+
+    let mut raw = 0;
+    let mut rest = value;
+    for i in 0..digits {
+        raw |= (rest % 10) << (i * 4);
+        rest /= 10;
+    }
+
+    Ok(raw)
+}
+
+// Two ASCII characters per register, trailing
+// NULs and spaces are dropped. Only the byte swap
+// applies, registers are always read in order.
+pub fn decode_string(registers: &[u16], order: ModbusByteOrder) -> String {
+    let bytes: Vec<u8> = registers
+        .iter()
+        .flat_map(|word| {
+            if order.swaps_bytes() {
+                word.to_le_bytes()
+            } else {
+                word.to_be_bytes()
+            }
+        })
+        .collect();
+
+    String::from_utf8_lossy(&bytes)
+        .trim_end_matches(['\0', ' '])
+        .to_owned()
+}
+
+// Pad the string with NULs to fill `count` registers.
+pub fn encode_string(
+    value: &str,
+    count: usize,
+    order: ModbusByteOrder,
+) -> anyhow::Result<Vec<u16>> {
+    let mut bytes = value.as_bytes().to_vec();
+    if bytes.len() > count * 2 {
+        anyhow::bail!("\"{value}\" doesn't fit in {count} registers");
+    }
+    bytes.resize(count * 2, 0);
+
+    let words = bytes
+        .chunks(2)
+        .map(|pair| {
+            let word = u16::from_be_bytes([pair[0], pair[1]]);
+            if order.swaps_bytes() {
+                word.swap_bytes()
+            } else {
+                word
+            }
+        })
+        .collect();
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 123.456_f32 is 0x42F6E979.
+    const F32_REGISTERS: [(ModbusByteOrder, [u16; 2]); 4] = [
+        (ModbusByteOrder::Abcd, [0x42F6, 0xE979]),
+        (ModbusByteOrder::Cdab, [0xE979, 0x42F6]),
+        (ModbusByteOrder::Badc, [0xF642, 0x79E9]),
+        (ModbusByteOrder::Dcba, [0x79E9, 0xF642]),
+    ];
+
+    // 1234.5678_f64 is 0x40934A456D5CFAAD.
+    const F64_REGISTERS: [(ModbusByteOrder, [u16; 4]); 4] = [
+        (ModbusByteOrder::Abcd, [0x4093, 0x4A45, 0x6D5C, 0xFAAD]),
+        (ModbusByteOrder::Cdab, [0xFAAD, 0x6D5C, 0x4A45, 0x4093]),
+        (ModbusByteOrder::Badc, [0x9340, 0x454A, 0x5C6D, 0xADFA]),
+        (ModbusByteOrder::Dcba, [0xADFA, 0x5C6D, 0x454A, 0x9340]),
+    ];
+
+    #[test]
+    fn decode_u32_all_orders() {
+        for (order, registers) in F32_REGISTERS {
+            assert_eq!(decode_u32(registers, order), 0x42F6_E979, "{order}");
+        }
+    }
+
+    #[test]
+    fn decode_f32_all_orders() {
+        for (order, registers) in F32_REGISTERS {
+            assert_eq!(decode_f32(registers, order), 123.456, "{order}");
+        }
+    }
+
+    #[test]
+    fn decode_u64_all_orders() {
+        for (order, registers) in F64_REGISTERS {
+            assert_eq!(
+                decode_u64(registers, order),
+                0x4093_4A45_6D5C_FAAD,
+                "{order}"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_f64_all_orders() {
+        for (order, registers) in F64_REGISTERS {
+            assert_eq!(decode_f64(registers, order), 1234.5678, "{order}");
+        }
+    }
+
+    #[test]
+    fn encode_all_orders() {
+        for (order, registers) in F32_REGISTERS {
+            assert_eq!(encode_f32(123.456, order), registers, "{order}");
+        }
+        for (order, registers) in F64_REGISTERS {
+            assert_eq!(encode_f64(1234.5678, order), registers, "{order}");
+        }
+    }
+
+    #[test]
+    fn decode_bcd_digits() {
+        assert_eq!(decode_bcd(0x1234, 4).unwrap(), 1234);
+        assert_eq!(decode_bcd(0x2359_0059, 8).unwrap(), 23_590_059);
+        assert!(decode_bcd(0x12A4, 4).is_err());
+        assert_eq!(encode_bcd(1234, 4).unwrap(), 0x1234);
+        assert!(encode_bcd(12345, 4).is_err());
+    }
+
+    #[test]
+    fn decode_string_byte_orders() {
+        let registers = [0x4142, 0x4344, 0x4500];
+        assert_eq!(decode_string(&registers, ModbusByteOrder::Abcd), "ABCDE");
+
+        let registers = [0x4241, 0x4443, 0x0045];
+        assert_eq!(decode_string(&registers, ModbusByteOrder::Badc), "ABCDE");
+        assert_eq!(
+            encode_string("ABCDE", 3, ModbusByteOrder::Badc).unwrap(),
+            registers
+        );
+    }
+}
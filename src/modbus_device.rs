@@ -1,7 +1,20 @@
+use crate::channel_quality::*;
+use crate::modbus_block::*;
+use crate::modbus_decode::*;
+use crate::modbus_diagnostics::*;
+use crate::modbus_identification::*;
+use crate::modbus_scaling::*;
+use crate::modbus_scan::*;
+use crate::modbus_traffic::*;
 use anyhow::Result;
-use std::{fmt::Display, net::SocketAddr, time::Duration};
-use tcp::connect;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpStream;
 use tokio_modbus::prelude::*;
+use tokio_serial::SerialStream;
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 pub struct ModbusChannel {
@@ -10,8 +23,217 @@ pub struct ModbusChannel {
     pub name: String,
     pub description: String,
     pub address: u16,
+    #[serde(default)]
+    pub register_space: ModbusRegisterSpace,
     pub channel_type: ModbusChannelType,
+    #[serde(default)]
+    pub byte_order: ModbusByteOrder,
+    #[serde(default)]
+    pub scaling: ModbusScaling,
+    // Engineering limits (min, max) of the scaled value.
+    #[serde(default)]
+    pub clamp: Option<(f64, f64)>,
+    // Engineering unit, e.g. "bar" or "°C".
+    #[serde(default)]
+    pub unit: String,
+    // Name of the device scan class, None for the device interval.
+    #[serde(default)]
+    pub scan_class: Option<String>,
     pub value: ModbusValue,
+    #[serde(default)]
+    pub quality: ChannelQuality,
+    // When the request was sent and when its response arrived.
+    #[serde(default)]
+    pub source_time: Option<SystemTime>,
+    #[serde(default)]
+    pub receive_time: Option<SystemTime>,
+    // Scans in a row without a response.
+    #[serde(default)]
+    pub missed_scans: u32,
+    // Last exception the device answered with for this channel.
+    #[serde(default)]
+    pub last_exception: Option<ModbusException>,
+    #[serde(default)]
+    pub exception_count: u64,
+}
+
+impl ModbusChannel {
+    // An enabled channel without scaling, not read yet.
+    pub fn new(
+        id: usize,
+        name: String,
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        channel_type: ModbusChannelType,
+        byte_order: ModbusByteOrder,
+    ) -> Self {
+        Self {
+            id,
+            enabled: true,
+            name,
+            description: String::new(),
+            address,
+            register_space,
+            channel_type,
+            byte_order,
+            scaling: ModbusScaling::None,
+            clamp: None,
+            unit: String::new(),
+            scan_class: None,
+            value: ModbusValue::Real(0.0),
+            quality: ChannelQuality::NotYetRead,
+            source_time: None,
+            receive_time: None,
+            missed_scans: 0,
+            last_exception: None,
+            exception_count: 0,
+        }
+    }
+
+    // Store the outcome of a read, the raw value is scaled here.
+    // On errors the value is kept and marked bad.
+    pub fn set_read_result(&mut self, result: Result<ModbusValue>, source_time: SystemTime) {
+        self.source_time = Some(source_time);
+        self.receive_time = Some(SystemTime::now());
+        self.missed_scans = 0;
+
+        match result {
+            Ok(raw) => {
+                self.quality = if self.is_clamped(&raw) {
+                    ChannelQuality::Uncertain("Clamped to range".to_owned())
+                } else {
+                    ChannelQuality::Good
+                };
+                self.value = self.to_engineering(raw);
+            }
+            Err(e) => self.quality = ChannelQuality::Bad(format!("{e}")),
+        }
+    }
+
+    // Mark the channel bad with the exception the device answered with.
+    pub fn set_exception(&mut self, exception: ModbusException, source_time: SystemTime) {
+        self.set_read_result(Err(anyhow::anyhow!("{exception}")), source_time);
+        self.exception_count += 1;
+        self.last_exception = Some(exception);
+    }
+
+    // Count a scan the channel got no response in.
+    // Returns true if the channel just became stale.
+    pub fn set_missed_scan(&mut self, stale_after_scans: u32) -> bool {
+        self.missed_scans += 1;
+
+        let is_stale = self.missed_scans >= stale_after_scans
+            && self.quality != ChannelQuality::NotYetRead
+            && self.quality != ChannelQuality::Stale;
+        if is_stale {
+            self.quality = ChannelQuality::Stale;
+        }
+
+        is_stale
+    }
+
+    // Whether the scaled raw value falls outside the clamp range.
+    fn is_clamped(&self, raw: &ModbusValue) -> bool {
+        match (self.clamp, raw) {
+            (None, _) | (_, ModbusValue::Bool(_)) => false,
+            (Some((min, max)), raw) => raw.as_f64().is_some_and(|raw| {
+                let value = self.scaling.scale(raw);
+                value < min || value > max
+            }),
+        }
+    }
+
+    // Apply the scaling and clamping to a decoded value.
+    // Scaled numbers become reals, bools and strings are left as is.
+    pub fn to_engineering(&self, raw: ModbusValue) -> ModbusValue {
+        if self.scaling.is_none() && self.clamp.is_none() {
+            return raw;
+        }
+
+        match (&raw, raw.as_f64()) {
+            (ModbusValue::Bool(_), _) | (_, None) => raw,
+            (_, Some(raw)) => {
+                let mut value = self.scaling.scale(raw);
+                if let Some((min, max)) = self.clamp {
+                    value = value.max(min).min(max);
+                }
+                ModbusValue::Real(value)
+            }
+        }
+    }
+
+    // The raw value to write for an engineering value.
+    pub fn to_raw(&self, value: &ModbusValue) -> Result<ModbusValue> {
+        if self.scaling.is_none() {
+            return Ok(value.clone());
+        }
+
+        match value {
+            ModbusValue::Bool(_) | ModbusValue::Str(_) => Ok(value.clone()),
+            _ => {
+                let eu = value
+                    .as_f64()
+                    .ok_or_else(|| anyhow::anyhow!("{value} is not a number"))?;
+                let raw = self.scaling.unscale(eu);
+
+                // Integer registers can only take whole numbers.
+                if self.channel_type.is_float() {
+                    Ok(ModbusValue::Real(raw))
+                } else {
+                    Ok(ModbusValue::Real(raw.round()))
+                }
+            }
+        }
+    }
+
+    // Parse a value typed in engineering units. Numbers of scaled
+    // channels are reals, the write turns them into raw values.
+    pub fn parse_value(&self, text: &str) -> Result<ModbusValue> {
+        let is_number = !matches!(
+            self.channel_type,
+            ModbusChannelType::Bool | ModbusChannelType::Bit(_) | ModbusChannelType::String(_)
+        );
+        if !is_number || (self.scaling.is_none() && self.clamp.is_none()) {
+            return self.channel_type.parse_value(text);
+        }
+
+        // Without scaling the type decides which input is valid,
+        // e.g. whole numbers only, the clamp only limits it.
+        if let (true, Some((min, max))) = (self.scaling.is_none(), self.clamp) {
+            let value = self.channel_type.parse_value(text)?;
+            let (min, max) = match self.channel_type.is_float() {
+                true => (min, max),
+                false => (min.ceil(), max.floor()),
+            };
+            return match value.as_f64() {
+                Some(number) if number < min => Ok(self.channel_type.from_f64(min)),
+                Some(number) if number > max => Ok(self.channel_type.from_f64(max)),
+                _ => Ok(value),
+            };
+        }
+
+        let text = text.trim();
+        let mut value = text
+            .parse::<f64>()
+            .map_err(|_| anyhow::anyhow!("Invalid number \"{text}\""))?;
+        if !value.is_finite() {
+            anyhow::bail!("Invalid number \"{text}\"");
+        }
+        if let Some((min, max)) = self.clamp {
+            value = value.max(min).min(max);
+        }
+
+        Ok(ModbusValue::Real(value))
+    }
+
+    // The value with its unit, for display.
+    pub fn display_value(&self) -> String {
+        if self.unit.is_empty() {
+            format!("{}", self.value)
+        } else {
+            format!("{} {}", self.value, self.unit)
+        }
+    }
 }
 
 impl Display for ModbusChannel {
@@ -20,11 +242,38 @@ impl Display for ModbusChannel {
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
 pub enum ModbusValue {
-    Int(u16),
-    Real(f32),
+    // Signed integers.
+    Int(i64),
+    // Unsigned integers and BCD.
+    UInt(u64),
+    Real(f64),
     Bool(bool),
+    Str(String),
+}
+
+impl ModbusValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ModbusValue::Int(v) => Some(*v as f64),
+            ModbusValue::UInt(v) => Some(*v as f64),
+            ModbusValue::Real(v) => Some(*v),
+            ModbusValue::Bool(v) => Some(f64::from(u8::from(*v))),
+            ModbusValue::Str(_) => None,
+        }
+    }
+
+    // Whole numbers only, floats with a fraction are rejected.
+    fn as_i128(&self) -> Result<i128> {
+        match self {
+            ModbusValue::Int(v) => Ok(*v as i128),
+            ModbusValue::UInt(v) => Ok(*v as i128),
+            ModbusValue::Real(v) if v.fract() == 0.0 => Ok(*v as i128),
+            ModbusValue::Bool(v) => Ok(i128::from(*v)),
+            _ => anyhow::bail!("{self} is not an integer"),
+        }
+    }
 }
 
 impl Display for ModbusValue {
@@ -33,42 +282,328 @@ impl Display for ModbusValue {
             ModbusValue::Int(v) => {
                 write!(f, "{v}")
             }
+            ModbusValue::UInt(v) => {
+                write!(f, "{v}")
+            }
             ModbusValue::Real(v) => {
                 write!(f, "{:.2}", v)
             }
             ModbusValue::Bool(v) => {
                 write!(f, "{:?}", v)
             }
+            ModbusValue::Str(v) => {
+                write!(f, "{v}")
+            }
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum ModbusChannelType {
-    Int,
-    Real,
-    Coil,
+    #[serde(alias = "Coil", alias = "DiscreteInput")]
+    Bool,
+    #[serde(alias = "Int")]
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    #[serde(alias = "Real")]
+    F32,
+    F64,
+    // Packed decimal, 4 digits per register.
+    Bcd16,
+    Bcd32,
+    // ASCII string, 2 characters per register.
+    // Holds the number of registers.
+    String(u16),
+    // A single bit (0-15) of a register.
+    Bit(u8),
 }
 
-impl Display for ModbusChannelType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl ModbusChannelType {
+    pub fn is_float(&self) -> bool {
+        matches!(self, ModbusChannelType::F32 | ModbusChannelType::F64)
+    }
+
+    // A number as a value of this type, integers are rounded
+    // and saturate at the limits of the type.
+    pub fn from_f64(&self, value: f64) -> ModbusValue {
+        let (min, max) = match self {
+            ModbusChannelType::Bool => return ModbusValue::Bool(value != 0.0),
+            ModbusChannelType::F32 | ModbusChannelType::F64 => return ModbusValue::Real(value),
+            ModbusChannelType::String(_) => return ModbusValue::Str(format!("{value}")),
+            ModbusChannelType::U16 | ModbusChannelType::Bit(_) => (0.0, u16::MAX as f64),
+            ModbusChannelType::I16 => (i16::MIN as f64, i16::MAX as f64),
+            ModbusChannelType::U32 => (0.0, u32::MAX as f64),
+            ModbusChannelType::I32 => (i32::MIN as f64, i32::MAX as f64),
+            ModbusChannelType::U64 => (0.0, u64::MAX as f64),
+            ModbusChannelType::I64 => (i64::MIN as f64, i64::MAX as f64),
+            ModbusChannelType::Bcd16 => (0.0, 9_999.0),
+            ModbusChannelType::Bcd32 => (0.0, 99_999_999.0),
+        };
+
+        let value = value.round().clamp(min, max);
+        if min == 0.0 {
+            ModbusValue::UInt(value as u64)
+        } else {
+            ModbusValue::Int(value as i64)
+        }
+    }
+
+    // Number of 16 bit registers the type spans.
+    pub fn register_count(&self) -> u16 {
         match self {
-            ModbusChannelType::Int => {
-                write!(f, "INT")
+            ModbusChannelType::Bool
+            | ModbusChannelType::U16
+            | ModbusChannelType::I16
+            | ModbusChannelType::Bcd16
+            | ModbusChannelType::Bit(_) => 1,
+            ModbusChannelType::U32
+            | ModbusChannelType::I32
+            | ModbusChannelType::F32
+            | ModbusChannelType::Bcd32 => 2,
+            ModbusChannelType::U64 | ModbusChannelType::I64 | ModbusChannelType::F64 => 4,
+            ModbusChannelType::String(count) => *count,
+        }
+    }
+
+    // Strings must fit a single read request.
+    pub fn check(&self) -> Result<()> {
+        if let ModbusChannelType::String(count) = self {
+            if *count == 0 || *count > MAX_READ_REGISTERS {
+                anyhow::bail!("{self} must span 1 to {MAX_READ_REGISTERS} registers");
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decode the registers read for a channel of this type.
+    pub fn decode(&self, words: &[u16], order: ModbusByteOrder) -> Result<ModbusValue> {
+        let count = self.register_count() as usize;
+        if words.len() < count {
+            anyhow::bail!("{self} needs {count} registers, got {}", words.len());
+        }
+
+        let value = match self {
+            // A register read as a bool is true when non-zero.
+            ModbusChannelType::Bool => ModbusValue::Bool(words[0] != 0),
+            ModbusChannelType::U16 => ModbusValue::UInt(words[0] as u64),
+            ModbusChannelType::I16 => ModbusValue::Int(words[0] as i16 as i64),
+            ModbusChannelType::U32 => {
+                ModbusValue::UInt(decode_u32([words[0], words[1]], order) as u64)
+            }
+            ModbusChannelType::I32 => {
+                ModbusValue::Int(decode_u32([words[0], words[1]], order) as i32 as i64)
+            }
+            ModbusChannelType::U64 => {
+                ModbusValue::UInt(decode_u64([words[0], words[1], words[2], words[3]], order))
+            }
+            ModbusChannelType::I64 => {
+                ModbusValue::Int(decode_u64([words[0], words[1], words[2], words[3]], order) as i64)
+            }
+            ModbusChannelType::F32 => {
+                ModbusValue::Real(decode_f32([words[0], words[1]], order) as f64)
+            }
+            ModbusChannelType::F64 => {
+                ModbusValue::Real(decode_f64([words[0], words[1], words[2], words[3]], order))
+            }
+            ModbusChannelType::Bcd16 => ModbusValue::UInt(decode_bcd(words[0] as u64, 4)?),
+            ModbusChannelType::Bcd32 => {
+                let raw = decode_u32([words[0], words[1]], order);
+                ModbusValue::UInt(decode_bcd(raw as u64, 8)?)
+            }
+            ModbusChannelType::String(_) => ModbusValue::Str(decode_string(&words[..count], order)),
+            ModbusChannelType::Bit(bit) => {
+                if *bit > 15 {
+                    anyhow::bail!("Bit index {bit} is out of range");
+                }
+                ModbusValue::Bool((words[0] >> bit) & 1 == 1)
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+impl ModbusChannelType {
+    // Parse a value typed by the user.
+    pub fn parse_value(&self, text: &str) -> Result<ModbusValue> {
+        let text = text.trim();
+        let value = match self {
+            ModbusChannelType::Bool | ModbusChannelType::Bit(_) => {
+                match text.to_lowercase().as_str() {
+                    "1" | "true" | "on" => ModbusValue::Bool(true),
+                    "0" | "false" | "off" => ModbusValue::Bool(false),
+                    _ => anyhow::bail!("Invalid bool \"{text}\""),
+                }
+            }
+            ModbusChannelType::I16 | ModbusChannelType::I32 | ModbusChannelType::I64 => {
+                ModbusValue::Int(text.parse()?)
             }
-            ModbusChannelType::Real => {
-                write!(f, "REAL")
+            ModbusChannelType::U16
+            | ModbusChannelType::U32
+            | ModbusChannelType::U64
+            | ModbusChannelType::Bcd16
+            | ModbusChannelType::Bcd32 => ModbusValue::UInt(text.parse()?),
+            ModbusChannelType::F32 | ModbusChannelType::F64 => ModbusValue::Real(text.parse()?),
+            ModbusChannelType::String(_) => ModbusValue::Str(text.to_owned()),
+        };
+
+        Ok(value)
+    }
+
+    // Encode a value into the registers written for this type.
+    pub fn encode(&self, value: &ModbusValue, order: ModbusByteOrder) -> Result<Vec<u16>> {
+        // Integers must fit the target type.
+        fn int<T: TryFrom<i128>>(value: &ModbusValue, kind: &ModbusChannelType) -> Result<T> {
+            T::try_from(value.as_i128()?)
+                .map_err(|_| anyhow::anyhow!("{value} is out of range for {kind}"))
+        }
+
+        let words = match self {
+            ModbusChannelType::Bool => vec![u16::from(value.as_i128()? != 0)],
+            ModbusChannelType::U16 => vec![int::<u16>(value, self)?],
+            ModbusChannelType::I16 => vec![int::<i16>(value, self)? as u16],
+            ModbusChannelType::U32 => encode_u32(int(value, self)?, order),
+            ModbusChannelType::I32 => encode_u32(int::<i32>(value, self)? as u32, order),
+            ModbusChannelType::U64 => encode_u64(int(value, self)?, order),
+            ModbusChannelType::I64 => encode_u64(int::<i64>(value, self)? as u64, order),
+            ModbusChannelType::F32 | ModbusChannelType::F64 => {
+                let v = value
+                    .as_f64()
+                    .ok_or_else(|| anyhow::anyhow!("{value} is not a number"))?;
+                match self {
+                    ModbusChannelType::F32 => encode_f32(v as f32, order),
+                    _ => encode_f64(v, order),
+                }
             }
-            ModbusChannelType::Coil => {
-                write!(f, "COIL")
+            ModbusChannelType::Bcd16 => vec![encode_bcd(int(value, self)?, 4)? as u16],
+            ModbusChannelType::Bcd32 => encode_u32(encode_bcd(int(value, self)?, 8)? as u32, order),
+            ModbusChannelType::String(count) => {
+                encode_string(&value.to_string(), *count as usize, order)?
             }
+            ModbusChannelType::Bit(_) => anyhow::bail!("{self} channels can't be written"),
+        };
+
+        Ok(words)
+    }
+}
+
+impl Display for ModbusChannelType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusChannelType::Bool => write!(f, "BOOL"),
+            ModbusChannelType::U16 => write!(f, "U16"),
+            ModbusChannelType::I16 => write!(f, "I16"),
+            ModbusChannelType::U32 => write!(f, "U32"),
+            ModbusChannelType::I32 => write!(f, "I32"),
+            ModbusChannelType::U64 => write!(f, "U64"),
+            ModbusChannelType::I64 => write!(f, "I64"),
+            ModbusChannelType::F32 => write!(f, "F32"),
+            ModbusChannelType::F64 => write!(f, "F64"),
+            ModbusChannelType::Bcd16 => write!(f, "BCD16"),
+            ModbusChannelType::Bcd32 => write!(f, "BCD32"),
+            ModbusChannelType::String(count) => write!(f, "STRING[{count}]"),
+            ModbusChannelType::Bit(bit) => write!(f, "BIT.{bit}"),
         }
     }
 }
-#[derive(serde::Deserialize, serde::Serialize, Clone)]
+
+// The Modbus data table a channel is read from.
+#[derive(
+    serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash,
+)]
+pub enum ModbusRegisterSpace {
+    // Read/write bits (FC01).
+    Coil,
+    // Read-only bits (FC02).
+    DiscreteInput,
+    // Read-only words (FC04).
+    InputRegister,
+    // Read/write words (FC03).
+    #[default]
+    HoldingRegister,
+}
+
+impl ModbusRegisterSpace {
+    pub fn is_writable(&self) -> bool {
+        matches!(
+            self,
+            ModbusRegisterSpace::Coil | ModbusRegisterSpace::HoldingRegister
+        )
+    }
+
+    pub fn is_bit(&self) -> bool {
+        matches!(
+            self,
+            ModbusRegisterSpace::Coil | ModbusRegisterSpace::DiscreteInput
+        )
+    }
+
+    // Function code used to read the register space.
+    pub fn read_function_code(&self) -> u8 {
+        match self {
+            ModbusRegisterSpace::Coil => 0x01,
+            ModbusRegisterSpace::DiscreteInput => 0x02,
+            ModbusRegisterSpace::HoldingRegister => 0x03,
+            ModbusRegisterSpace::InputRegister => 0x04,
+        }
+    }
+}
+
+impl Display for ModbusRegisterSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusRegisterSpace::Coil => write!(f, "COIL"),
+            ModbusRegisterSpace::DiscreteInput => write!(f, "DI"),
+            ModbusRegisterSpace::InputRegister => write!(f, "IR"),
+            ModbusRegisterSpace::HoldingRegister => write!(f, "HR"),
+        }
+    }
+}
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
 pub enum ModbusDeviceConfig {
     Tcp(ModbusTcpConfig),
-    Serial,
+    Serial(ModbusSerialConfig),
+}
+
+impl ModbusDeviceConfig {
+    // Open the endpoint, TCP connections give up after the timeout.
+    // The bytes it carries are copied to the capture, if any.
+    pub async fn connect(
+        &self,
+        timeout: Duration,
+        capture: Option<TrafficCapture>,
+    ) -> Result<tokio_modbus::client::Context> {
+        match self {
+            ModbusDeviceConfig::Tcp(conf) => {
+                let socket_string = format!("{}:{}", conf.ip, conf.port);
+                let socket_addr = socket_string.parse::<SocketAddr>()?;
+
+                let stream = tokio::time::timeout(timeout, TcpStream::connect(socket_addr))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("Connection to {socket_addr} timed out"))??;
+                stream.set_nodelay(true)?;
+                let ctx = tcp::attach_slave(TrafficTap::new(stream, capture), Slave(conf.unit_id));
+
+                Ok(ctx)
+            }
+            ModbusDeviceConfig::Serial(conf) => {
+                let builder = tokio_serial::new(&conf.port, conf.baud_rate)
+                    .parity(conf.parity.into())
+                    .data_bits(conf.data_bits.into())
+                    .stop_bits(conf.stop_bits.into());
+                let port = SerialStream::open(&builder)?;
+
+                let ctx = rtu::attach_slave(TrafficTap::new(port, capture), Slave(conf.unit_id));
+
+                Ok(ctx)
+            }
+        }
+    }
 }
 
 impl Display for ModbusDeviceConfig {
@@ -77,18 +612,150 @@ impl Display for ModbusDeviceConfig {
             ModbusDeviceConfig::Tcp(_) => {
                 write!(f, "Modbus TCP")
             }
-            ModbusDeviceConfig::Serial => {
+            ModbusDeviceConfig::Serial(_) => {
                 write!(f, "Modbus Serial")
             }
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone)]
-
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
 pub struct ModbusTcpConfig {
     pub ip: String,
     pub port: usize,
+    // Unit ID of the slave behind a TCP-to-RTU gateway.
+    #[serde(default = "default_tcp_unit_id")]
+    pub unit_id: u8,
+}
+
+// 255 addresses the TCP device itself. New devices use it too, so
+// configs saved before unit IDs keep talking to the same slave.
+pub fn default_tcp_unit_id() -> u8 {
+    Slave::tcp_device().into()
+}
+
+// Timeout and retry policy of the device requests.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ModbusRequestConfig {
+    pub timeout_ms: u64,
+    // Extra attempts after a read timed out.
+    pub retries: u32,
+    // Wait before reconnecting after a transport failure.
+    pub reconnect_delay_ms: u64,
+}
+
+impl Default for ModbusRequestConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 1000,
+            retries: 2,
+            reconnect_delay_ms: 5000,
+        }
+    }
+}
+
+// Modbus RTU over a serial line (RS-232/RS-485).
+// The port can be any serial device path, including
+// one end of a pseudo-terminal pair on Linux.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ModbusSerialConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    pub parity: SerialParity,
+    pub data_bits: SerialDataBits,
+    pub stop_bits: SerialStopBits,
+    pub unit_id: u8,
+    // Silent interval in milliseconds inserted before every request.
+    pub frame_delay_ms: u64,
+}
+
+impl Default for ModbusSerialConfig {
+    fn default() -> Self {
+        Self {
+            port: "/dev/ttyUSB0".to_owned(),
+            baud_rate: 9600,
+            parity: SerialParity::None,
+            data_bits: SerialDataBits::Eight,
+            stop_bits: SerialStopBits::One,
+            unit_id: 1,
+            frame_delay_ms: 5,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialParity {
+    None,
+    Even,
+    Odd,
+}
+
+impl Display for SerialParity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialParity::None => write!(f, "None"),
+            SerialParity::Even => write!(f, "Even"),
+            SerialParity::Odd => write!(f, "Odd"),
+        }
+    }
+}
+
+impl From<SerialParity> for tokio_serial::Parity {
+    fn from(parity: SerialParity) -> Self {
+        match parity {
+            SerialParity::None => tokio_serial::Parity::None,
+            SerialParity::Even => tokio_serial::Parity::Even,
+            SerialParity::Odd => tokio_serial::Parity::Odd,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialDataBits {
+    Seven,
+    Eight,
+}
+
+impl Display for SerialDataBits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialDataBits::Seven => write!(f, "7"),
+            SerialDataBits::Eight => write!(f, "8"),
+        }
+    }
+}
+
+impl From<SerialDataBits> for tokio_serial::DataBits {
+    fn from(data_bits: SerialDataBits) -> Self {
+        match data_bits {
+            SerialDataBits::Seven => tokio_serial::DataBits::Seven,
+            SerialDataBits::Eight => tokio_serial::DataBits::Eight,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialStopBits {
+    One,
+    Two,
+}
+
+impl Display for SerialStopBits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialStopBits::One => write!(f, "1"),
+            SerialStopBits::Two => write!(f, "2"),
+        }
+    }
+}
+
+impl From<SerialStopBits> for tokio_serial::StopBits {
+    fn from(stop_bits: SerialStopBits) -> Self {
+        match stop_bits {
+            SerialStopBits::One => tokio_serial::StopBits::One,
+            SerialStopBits::Two => tokio_serial::StopBits::Two,
+        }
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
@@ -97,55 +764,639 @@ pub struct ModbusDevice {
     pub code: String,
     pub name: String,
     pub config: ModbusDeviceConfig,
+    #[serde(default)]
+    pub block_config: ModbusBlockConfig,
+    #[serde(default)]
+    pub scan_config: ModbusScanConfig,
+    #[serde(default)]
+    pub request_config: ModbusRequestConfig,
     pub channels: Vec<ModbusChannel>,
+    // Exception counts since the app started, not saved.
+    #[serde(skip)]
+    pub diagnostics: ModbusDiagnostics,
+    // Read once after the first successful poll,
+    // the error if the device couldn't tell.
+    #[serde(skip)]
+    pub identification: Option<std::result::Result<ModbusDeviceIdentification, String>>,
 }
 
 impl ModbusDevice {
-    pub async fn connect_to_device(&mut self) -> Result<tokio_modbus::client::Context> {
+    pub async fn connect_to_device(
+        &self,
+        capture: TrafficCapture,
+    ) -> Result<tokio_modbus::client::Context> {
+        self.config
+            .connect(self.request_timeout(), Some(capture))
+            .await
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_config.timeout_ms)
+    }
+
+    pub fn unit_id(&self) -> u8 {
         match &self.config {
-            ModbusDeviceConfig::Tcp(conf) => {
-                let socket_string = format!("{}:{}", conf.ip, conf.port);
-                let socket_addr = socket_string.parse::<SocketAddr>()?;
+            ModbusDeviceConfig::Tcp(conf) => conf.unit_id,
+            ModbusDeviceConfig::Serial(conf) => conf.unit_id,
+        }
+    }
 
-                let ctx = connect(socket_addr).await?;
+    // Devices with the same endpoint share one connection.
+    // For serial lines the first device to connect sets the
+    // line settings for all the others.
+    pub fn endpoint(&self) -> String {
+        match &self.config {
+            ModbusDeviceConfig::Tcp(conf) => format!("tcp://{}:{}", conf.ip, conf.port),
+            ModbusDeviceConfig::Serial(conf) => format!("serial://{}", conf.port),
+        }
+    }
 
-                Ok(ctx)
+    // RTU slaves need a silent interval between frames.
+    // TCP devices don't, so we return None for them.
+    pub fn frame_delay(&self) -> Option<Duration> {
+        match &self.config {
+            ModbusDeviceConfig::Tcp(_) => None,
+            ModbusDeviceConfig::Serial(conf) => Some(Duration::from_millis(conf.frame_delay_ms)),
+        }
+    }
+
+    // Poll the channels at the given indexes.
+    // Modbus exceptions and decode errors only mark the channels
+    // they concern, transport failures and timeouts are returned
+    // so the connection is dropped.
+    pub async fn poll(
+        &mut self,
+        ctx: &mut tokio_modbus::client::Context,
+        selection: &[usize],
+        traffic: &TrafficRecorder,
+    ) -> Result<()> {
+        let blocks = plan_read_blocks(&self.channels, selection, &self.block_config);
+        for index in selection {
+            if let Some(channel) = self.channels.get_mut(*index) {
+                if !channel.enabled {
+                    continue;
+                }
+                if let Err(e) = channel.channel_type.check() {
+                    channel.set_read_result(Err(e), SystemTime::now());
+                }
             }
-            // TODO
-            ModbusDeviceConfig::Serial => {
-                unimplemented!();
+        }
+
+        // The connection may be shared with other unit IDs.
+        ctx.set_slave(Slave(self.unit_id()));
+
+        for block in blocks {
+            let source_time = SystemTime::now();
+            let data = self
+                .read_with_retry(
+                    ctx,
+                    block.register_space,
+                    block.address,
+                    block.count,
+                    traffic,
+                )
+                .await?;
+            match data {
+                Ok(data) => {
+                    for index in &block.channels {
+                        let channel = &mut self.channels[*index];
+                        let offset = (channel.address - block.address) as usize;
+                        let result = decode_channel(channel, &data, offset);
+                        channel.set_read_result(result, source_time);
+                    }
+                }
+                Err(exception) if block.channels.len() == 1 => {
+                    let exception = ModbusException::new(
+                        block.register_space.read_function_code(),
+                        block.address,
+                        exception,
+                    );
+                    let channel = &mut self.channels[block.channels[0]];
+                    self.diagnostics.record(channel.name.clone(), &exception);
+                    channel.set_exception(exception, source_time);
+                }
+                // The block may span addresses the device doesn't
+                // serve, so we fall back to reading each channel alone.
+                Err(exception) => {
+                    let exception = ModbusException::new(
+                        block.register_space.read_function_code(),
+                        block.address,
+                        exception,
+                    );
+                    let source = format!("Block of {} channels", block.channels.len());
+                    self.diagnostics.record(source, &exception);
+
+                    for index in &block.channels {
+                        let channel = &self.channels[*index];
+                        let (space, address) = (channel.register_space, channel.address);
+                        let count = if space.is_bit() {
+                            1
+                        } else {
+                            channel.channel_type.register_count()
+                        };
+
+                        let source_time = SystemTime::now();
+                        let data = self
+                            .read_with_retry(ctx, space, address, count, traffic)
+                            .await?;
+                        let channel = &mut self.channels[*index];
+                        match data {
+                            Ok(data) => {
+                                let result = decode_channel(channel, &data, 0);
+                                channel.set_read_result(result, source_time);
+                            }
+                            Err(exception) => {
+                                let exception = ModbusException::new(
+                                    space.read_function_code(),
+                                    address,
+                                    exception,
+                                );
+                                self.diagnostics.record(channel.name.clone(), &exception);
+                                channel.set_exception(exception, source_time);
+                            }
+                        }
+                    }
+                }
             }
         }
+
+        Ok(())
     }
-    pub async fn poll(&mut self, ctx: &mut tokio_modbus::client::Context) -> Result<()> {
-        for channel in self.channels.iter_mut() {
-            match channel.channel_type {
-                ModbusChannelType::Int => {
-                    let value = ctx.read_holding_registers(channel.address, 1).await??;
 
-                    // Possibility to error-check for empty array.
-                    let value = value.iter().nth(0);
-                    channel.value = ModbusValue::Int(*value.unwrap());
+    // Reset the device and channel exception counts.
+    pub fn clear_diagnostics(&mut self) {
+        self.diagnostics.clear();
+        for channel in &mut self.channels {
+            channel.last_exception = None;
+            channel.exception_count = 0;
+        }
+    }
+
+    // Count a missed scan for the selected channels that got
+    // no response since the scan started.
+    pub fn set_missed_scan(&mut self, selection: &[usize], scan_start: SystemTime) {
+        let stale_after_scans = self.scan_config.stale_after_scans;
+
+        for index in selection {
+            let Some(channel) = self.channels.get_mut(*index) else {
+                continue;
+            };
+            if channel.receive_time.is_some_and(|time| time >= scan_start) {
+                continue;
+            }
+            channel.set_missed_scan(stale_after_scans);
+        }
+    }
+
+    // Read with the request timeout, retrying the requests that timed out.
+    // On TCP each retry goes over a new connection, so the late answer to
+    // the request that timed out can't be taken for the answer to the retry.
+    // Serial ports are opened exclusively and can't be opened twice, the
+    // retry is sent on the same port after the frame delay.
+    // Transport errors, and the last timeout, are returned as errors,
+    // Modbus exceptions are passed on.
+    async fn read_with_retry(
+        &mut self,
+        ctx: &mut tokio_modbus::client::Context,
+        space: ModbusRegisterSpace,
+        address: u16,
+        count: u16,
+        traffic: &TrafficRecorder,
+    ) -> Result<std::result::Result<ModbusBlockData, ExceptionCode>> {
+        let frame_delay = self.frame_delay();
+        let mut attempt = 0;
+
+        loop {
+            if let Some(delay) = frame_delay {
+                tokio::time::sleep(delay).await;
+            }
+
+            let time = traffic.start();
+            let start = std::time::Instant::now();
+            let request = read_block(ctx, space, address, count);
+            let result = tokio::time::timeout(self.request_timeout(), request).await;
+            let report = RequestReport::new(&result, start.elapsed());
+            self.record_request(
+                space.read_function_code(),
+                address,
+                count,
+                time,
+                report,
+                traffic,
+            );
+
+            match result {
+                Ok(result) => return result,
+                Err(_) if attempt < self.request_config.retries => {
+                    attempt += 1;
+                    if matches!(self.config, ModbusDeviceConfig::Tcp(_)) {
+                        // The old connection is closed first, it's shared
+                        // with the other unit IDs of the endpoint.
+                        ctx.disconnect().await.ok();
+                        *ctx = self.connect_to_device(traffic.capture()).await?;
+                        ctx.set_slave(Slave(self.unit_id()));
+                        self.diagnostics.stats.record_connect();
+                    }
                 }
-                ModbusChannelType::Real => {
-                    let values = ctx.read_holding_registers(channel.address, 2).await??;
-                    let value = u16_to_float(values[0], values[1]);
+                Err(_) => anyhow::bail!(
+                    "{space} {address}: Timed out after {} attempts",
+                    attempt + 1
+                ),
+            }
+        }
+    }
+
+    // Count the request in the link statistics and log it to the traffic monitor.
+    fn record_request(
+        &mut self,
+        function_code: u8,
+        address: u16,
+        count: u16,
+        time: SystemTime,
+        report: RequestReport,
+        traffic: &TrafficRecorder,
+    ) {
+        let request = TrafficRequest {
+            device: self.name.clone(),
+            unit_id: self.unit_id(),
+            function_code,
+            address,
+            count,
+        };
+        traffic.record(
+            request,
+            time,
+            report.duration,
+            report.exception,
+            report.error,
+        );
+        self.diagnostics.stats.record_request(report.outcome);
+    }
 
-                    channel.value = ModbusValue::Real(value);
+    // Write a value to a coil (FC05/FC15) or holding registers (FC06/FC16).
+    // Bit channels set their bit with a mask write (FC22), leaving the
+    // other bits of the register as they are. With read-back writes the
+    // registers are written with FC23 and the channel gets what they hold.
+    // The outer error is a transport failure, the inner one a Modbus exception.
+    pub async fn write_channel(
+        &mut self,
+        ctx: &mut tokio_modbus::client::Context,
+        channel_id: usize,
+        value: &ModbusValue,
+        traffic: &TrafficRecorder,
+    ) -> Result<std::result::Result<(), ExceptionCode>> {
+        let index = self
+            .channels
+            .iter()
+            .position(|channel| channel.id == channel_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown channel {channel_id}"))?;
+        let channel = &self.channels[index];
+        let multiple = self.block_config.use_multiple_write;
+        let address = channel.address;
+
+        // Checked before sending so only real requests are counted.
+        let call = match (channel.register_space, &channel.channel_type) {
+            (ModbusRegisterSpace::Coil, ModbusChannelType::Bool) => {
+                let coil = value.as_i128()? != 0;
+                if multiple {
+                    ModbusCall::WriteCoils(address, vec![coil])
+                } else {
+                    ModbusCall::WriteCoil(address, coil)
+                }
+            }
+            (ModbusRegisterSpace::Coil, _) => anyhow::bail!(
+                "{} channel {} can't be written to {}",
+                channel.channel_type,
+                channel.name,
+                channel.register_space
+            ),
+            (ModbusRegisterSpace::HoldingRegister, ModbusChannelType::Bit(bit)) => {
+                let mask = 1u16 << bit;
+                let or_mask = if value.as_i128()? != 0 { mask } else { 0 };
+                ModbusCall::MaskWriteRegister(address, !mask, or_mask)
+            }
+            (ModbusRegisterSpace::HoldingRegister, channel_type) => {
+                let raw = channel.to_raw(value)?;
+                let words = channel_type.encode(&raw, channel.byte_order)?;
+                if self.block_config.read_back_writes {
+                    let count = words.len() as u16;
+                    ModbusCall::ReadWriteRegisters(address, count, address, words)
+                } else if words.len() == 1 && !multiple {
+                    ModbusCall::WriteRegister(address, words[0])
+                } else {
+                    ModbusCall::WriteRegisters(address, words)
                 }
-                // TODO
-                ModbusChannelType::Coil => {}
             }
+            _ => anyhow::bail!("{} is read-only", channel.register_space),
+        };
+
+        let name = channel.name.clone();
+        let source_time = SystemTime::now();
+        let result = self.call(ctx, call, traffic).await;
+        match result {
+            Ok(Ok(ModbusCallResponse::Words(words))) => {
+                let channel = &mut self.channels[index];
+                let result = decode_channel(channel, &ModbusBlockData::Words(words), 0);
+                channel.set_read_result(result, source_time);
+                Ok(Ok(()))
+            }
+            Ok(result) => Ok(result.map(|_| ())),
+            Err(e) => Err(anyhow::anyhow!("{name}: {e}")),
         }
+    }
 
-        Ok(())
+    // Send a single request with the request timeout, without retries.
+    // The outer error is a transport failure or timeout,
+    // the inner one a Modbus exception.
+    pub async fn call(
+        &mut self,
+        ctx: &mut tokio_modbus::client::Context,
+        call: ModbusCall,
+        traffic: &TrafficRecorder,
+    ) -> Result<std::result::Result<ModbusCallResponse, ExceptionCode>> {
+        if let Some(delay) = self.frame_delay() {
+            tokio::time::sleep(delay).await;
+        }
+        ctx.set_slave(Slave(self.unit_id()));
+
+        let time = traffic.start();
+        let start = std::time::Instant::now();
+        let request = async {
+            let response = ctx.call(call.request()).await?;
+            Ok::<_, anyhow::Error>(response.map(ModbusCallResponse::from))
+        };
+        let result = tokio::time::timeout(self.request_timeout(), request).await;
+        let report = RequestReport::new(&result, start.elapsed());
+        self.record_request(
+            call.function_code(),
+            call.address(),
+            call.count(),
+            time,
+            report,
+            traffic,
+        );
+
+        result.map_err(|_| anyhow::anyhow!("{} timed out", call))?
+    }
+
+    pub fn supports_identification(&self) -> bool {
+        matches!(self.config, ModbusDeviceConfig::Tcp(_))
+    }
+
+    // Read the vendor, product and revision (FC43/14). The regular
+    // objects are asked first, the basic ones if the device has none.
+    // The RTU framing of the client can't read these responses,
+    // so only TCP devices are asked.
+    pub async fn read_identification(
+        &mut self,
+        ctx: &mut tokio_modbus::client::Context,
+        traffic: &TrafficRecorder,
+    ) -> Result<std::result::Result<ModbusDeviceIdentification, ExceptionCode>> {
+        if !self.supports_identification() {
+            anyhow::bail!("Device identification is only read over Modbus TCP");
+        }
+
+        let mut identification = ModbusDeviceIdentification::default();
+        let mut read_code = READ_DEVICE_ID_REGULAR;
+        let mut object_id = 0;
+
+        loop {
+            let call = ModbusCall::ReadDeviceIdentification(read_code, object_id);
+            let data = match self.call(ctx, call, traffic).await? {
+                Ok(ModbusCallResponse::Data(data)) => data,
+                Ok(_) => anyhow::bail!("Unexpected device identification response"),
+                Err(ExceptionCode::IllegalDataValue) if read_code == READ_DEVICE_ID_REGULAR => {
+                    read_code = READ_DEVICE_ID_BASIC;
+                    continue;
+                }
+                Err(exception) => return Ok(Err(exception)),
+            };
+
+            // A device repeating the same object would loop forever.
+            match identification.parse_response(&data)? {
+                Some(next_object_id) if next_object_id > object_id => object_id = next_object_id,
+                _ => return Ok(Ok(identification)),
+            }
+        }
+    }
+}
+
+// A request sent outside of the polling, by the write path or scripts.
+#[derive(Clone, Debug)]
+pub enum ModbusCall {
+    WriteCoil(u16, bool),
+    WriteCoils(u16, Vec<bool>),
+    WriteRegister(u16, u16),
+    WriteRegisters(u16, Vec<u16>),
+    // Address, AND mask and OR mask (FC22).
+    MaskWriteRegister(u16, u16, u16),
+    // Read address and count, then write address and words (FC23).
+    // The device writes before it reads.
+    ReadWriteRegisters(u16, u16, u16, Vec<u16>),
+    // Read device ID code and first object ID (FC43/14).
+    ReadDeviceIdentification(u8, u8),
+}
+
+impl ModbusCall {
+    fn request(&self) -> Request<'_> {
+        match self {
+            ModbusCall::WriteCoil(address, coil) => Request::WriteSingleCoil(*address, *coil),
+            ModbusCall::WriteCoils(address, coils) => {
+                Request::WriteMultipleCoils(*address, coils.into())
+            }
+            ModbusCall::WriteRegister(address, word) => {
+                Request::WriteSingleRegister(*address, *word)
+            }
+            ModbusCall::WriteRegisters(address, words) => {
+                Request::WriteMultipleRegisters(*address, words.into())
+            }
+            ModbusCall::MaskWriteRegister(address, and_mask, or_mask) => {
+                Request::MaskWriteRegister(*address, *and_mask, *or_mask)
+            }
+            ModbusCall::ReadWriteRegisters(read_address, count, write_address, words) => {
+                Request::ReadWriteMultipleRegisters(
+                    *read_address,
+                    *count,
+                    *write_address,
+                    words.into(),
+                )
+            }
+            ModbusCall::ReadDeviceIdentification(read_code, object_id) => Request::Custom(
+                ENCAPSULATED_INTERFACE_FUNCTION_CODE,
+                ModbusDeviceIdentification::request(*read_code, *object_id).into(),
+            ),
+        }
+    }
+
+    pub fn function_code(&self) -> u8 {
+        match self {
+            ModbusCall::WriteCoil(..) => 5,
+            ModbusCall::WriteCoils(..) => 15,
+            ModbusCall::WriteRegister(..) => 6,
+            ModbusCall::WriteRegisters(..) => 16,
+            ModbusCall::MaskWriteRegister(..) => 22,
+            ModbusCall::ReadWriteRegisters(..) => 23,
+            ModbusCall::ReadDeviceIdentification(..) => ENCAPSULATED_INTERFACE_FUNCTION_CODE,
+        }
+    }
+
+    // The written address, the object ID for identification reads.
+    fn address(&self) -> u16 {
+        match self {
+            ModbusCall::WriteCoil(address, _)
+            | ModbusCall::WriteCoils(address, _)
+            | ModbusCall::WriteRegister(address, _)
+            | ModbusCall::WriteRegisters(address, _)
+            | ModbusCall::MaskWriteRegister(address, ..) => *address,
+            ModbusCall::ReadWriteRegisters(_, _, write_address, _) => *write_address,
+            ModbusCall::ReadDeviceIdentification(_, object_id) => *object_id as u16,
+        }
+    }
+
+    fn count(&self) -> u16 {
+        match self {
+            ModbusCall::WriteCoils(_, coils) => coils.len() as u16,
+            ModbusCall::WriteRegisters(_, words) | ModbusCall::ReadWriteRegisters(.., words) => {
+                words.len() as u16
+            }
+            ModbusCall::ReadDeviceIdentification(..) => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl Display for ModbusCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FC{:02} {}", self.function_code(), self.address())
+    }
+}
+
+// What a call answered.
+#[derive(Clone, Debug)]
+pub enum ModbusCallResponse {
+    Done,
+    // Registers read by FC23.
+    Words(Vec<u16>),
+    // Data after the function code of FC43 responses.
+    Data(Vec<u8>),
+}
+
+impl From<Response> for ModbusCallResponse {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::ReadWriteMultipleRegisters(words) => ModbusCallResponse::Words(words),
+            Response::Custom(_, data) => ModbusCallResponse::Data(data.to_vec()),
+            _ => ModbusCallResponse::Done,
+        }
+    }
+}
+
+// How a timed request ended, for the statistics and the traffic log.
+struct RequestReport {
+    outcome: ModbusRequestOutcome,
+    duration: Duration,
+    exception: Option<u8>,
+    error: Option<String>,
+}
+
+impl RequestReport {
+    fn new<T>(
+        result: &std::result::Result<
+            Result<std::result::Result<T, ExceptionCode>>,
+            tokio::time::error::Elapsed,
+        >,
+        duration: Duration,
+    ) -> Self {
+        let (outcome, exception, error) = match result {
+            Ok(Ok(Ok(_))) => (ModbusRequestOutcome::Success(duration), None, None),
+            Ok(Ok(Err(exception))) => (
+                ModbusRequestOutcome::Exception(duration),
+                Some(u8::from(*exception)),
+                None,
+            ),
+            Ok(Err(e)) => (ModbusRequestOutcome::Failure, None, Some(format!("{e}"))),
+            Err(_) => (
+                ModbusRequestOutcome::Timeout,
+                None,
+                Some("Timeout".to_owned()),
+            ),
+        };
+
+        Self {
+            outcome,
+            duration,
+            exception,
+            error,
+        }
     }
 }
 
-fn u16_to_float(reg1: u16, reg2: u16) -> f32 {
-    let data_32bit_rep = ((reg1 as u32) << 16) | reg2 as u32;
-    let data_array = data_32bit_rep.to_ne_bytes();
-    f32::from_ne_bytes(data_array)
+// Raw data returned by a block read.
+pub(crate) enum ModbusBlockData {
+    Bits(Vec<bool>),
+    Words(Vec<u16>),
+}
+
+// Read a block of bits or words. The outer error is a transport
+// failure, the inner one a Modbus exception from the device.
+pub(crate) async fn read_block(
+    ctx: &mut tokio_modbus::client::Context,
+    space: ModbusRegisterSpace,
+    address: u16,
+    count: u16,
+) -> Result<std::result::Result<ModbusBlockData, ExceptionCode>> {
+    let data = match space {
+        ModbusRegisterSpace::Coil => ctx
+            .read_coils(address, count)
+            .await?
+            .map(ModbusBlockData::Bits),
+        ModbusRegisterSpace::DiscreteInput => ctx
+            .read_discrete_inputs(address, count)
+            .await?
+            .map(ModbusBlockData::Bits),
+        ModbusRegisterSpace::InputRegister => ctx
+            .read_input_registers(address, count)
+            .await?
+            .map(ModbusBlockData::Words),
+        ModbusRegisterSpace::HoldingRegister => ctx
+            .read_holding_registers(address, count)
+            .await?
+            .map(ModbusBlockData::Words),
+    };
+
+    Ok(data)
+}
+
+// Decode the raw channel value starting at `offset` in the block data.
+fn decode_channel(
+    channel: &ModbusChannel,
+    data: &ModbusBlockData,
+    offset: usize,
+) -> Result<ModbusValue> {
+    match data {
+        ModbusBlockData::Bits(bits) => {
+            let bit = bits
+                .get(offset)
+                .ok_or_else(|| anyhow::anyhow!("Short {} response", channel.register_space))?;
+
+            match channel.channel_type {
+                ModbusChannelType::Bool => Ok(ModbusValue::Bool(*bit)),
+                _ => anyhow::bail!(
+                    "{} channel {} can't be read from {}",
+                    channel.channel_type,
+                    channel.name,
+                    channel.register_space
+                ),
+            }
+        }
+        ModbusBlockData::Words(words) => {
+            let words = words
+                .get(offset..)
+                .ok_or_else(|| anyhow::anyhow!("Short {} response", channel.register_space))?;
+
+            channel.channel_type.decode(words, channel.byte_order)
+        }
+    }
 }
 
 pub fn init_mb_tcp_device(
@@ -154,7 +1405,11 @@ pub fn init_mb_tcp_device(
     name: String,
     num_channels: usize,
 ) -> ModbusDevice {
-    let tcp_config = ModbusTcpConfig { ip, port };
+    let tcp_config = ModbusTcpConfig {
+        ip,
+        port,
+        unit_id: default_tcp_unit_id(),
+    };
 
     let device_config = ModbusDeviceConfig::Tcp(tcp_config);
 
@@ -167,20 +1422,497 @@ pub fn init_mb_tcp_device(
             name: format!("MB{i}"),
             description: "Modbus channel. No description.".to_owned(),
             address: i as u16 * 2,
-            channel_type: ModbusChannelType::Real,
+            register_space: ModbusRegisterSpace::HoldingRegister,
+            channel_type: ModbusChannelType::F32,
+            byte_order: ModbusByteOrder::Abcd,
+            scaling: ModbusScaling::None,
+            clamp: None,
+            unit: String::new(),
+            scan_class: None,
             value: ModbusValue::Real(3.0),
+            quality: ChannelQuality::NotYetRead,
+            source_time: None,
+            receive_time: None,
+            missed_scans: 0,
+            last_exception: None,
+            exception_count: 0,
         };
 
         channels.push(channel);
     }
 
-    let device = ModbusDevice {
+    ModbusDevice {
         id: 0,
         code: "MB".to_owned(),
         name,
         config: device_config,
+        block_config: ModbusBlockConfig::default(),
+        scan_config: ModbusScanConfig::default(),
+        request_config: ModbusRequestConfig::default(),
         channels,
-    };
+        diagnostics: ModbusDiagnostics::default(),
+        identification: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_connection::*;
+    use crate::ModbusDeviceBuffer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // How the test slaves answer a read of (address, count):
+    // registers, an exception code, or nothing.
+    type Answer = fn(u16, u16) -> Option<std::result::Result<Vec<u16>, u8>>;
+
+    fn serial_config(port: &str) -> ModbusSerialConfig {
+        ModbusSerialConfig {
+            port: port.to_owned(),
+            baud_rate: 19200,
+            parity: SerialParity::Even,
+            data_bits: SerialDataBits::Seven,
+            stop_bits: SerialStopBits::Two,
+            unit_id: 7,
+            frame_delay_ms: 3,
+        }
+    }
+
+    // Modbus RTU CRC-16, sent low byte first.
+    fn crc16(frame: &[u8]) -> [u8; 2] {
+        let mut crc: u16 = 0xFFFF;
+        for byte in frame {
+            crc ^= *byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xA001
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        crc.to_le_bytes()
+    }
+
+    fn with_crc(frame: &[u8]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc);
+        frame
+    }
+
+    // FC03 answer PDU, or its exception.
+    fn answer_pdu(answer: std::result::Result<Vec<u16>, u8>) -> Vec<u8> {
+        match answer {
+            Ok(words) => {
+                let mut pdu = vec![0x03, words.len() as u8 * 2];
+                pdu.extend(words.iter().flat_map(|word| word.to_be_bytes()));
+                pdu
+            }
+            Err(code) => vec![0x83, code],
+        }
+    }
+
+    // A device with U16 channels at 0x10 and 0x80, read in two blocks.
+    fn test_device(config: ModbusDeviceConfig) -> ModbusDevice {
+        let mut device = init_mb_tcp_device("127.0.0.1".to_owned(), 502, "PLC_1".to_owned(), 2);
+        device.config = config;
+        device.request_config.timeout_ms = 100;
+        for (channel, address) in device.channels.iter_mut().zip([0x10, 0x80]) {
+            channel.channel_type = ModbusChannelType::U16;
+            channel.address = address;
+        }
+        device
+    }
+
+    // Answer the FC03 requests on the master end of a pty until
+    // it's quiet, returning it with the (address, count) read.
+    async fn serve_rtu(
+        mut master: SerialStream,
+        answer: Answer,
+    ) -> (SerialStream, Vec<(u16, u16)>) {
+        let mut requests = Vec::new();
+        let mut request = [0; 8];
+        while let Ok(read) =
+            tokio::time::timeout(Duration::from_millis(500), master.read_exact(&mut request)).await
+        {
+            read.unwrap();
+            let address = u16::from_be_bytes([request[2], request[3]]);
+            let count = u16::from_be_bytes([request[4], request[5]]);
+            requests.push((address, count));
+            if let Some(answer) = answer(address, count) {
+                let mut frame = vec![request[0]];
+                frame.extend(answer_pdu(answer));
+                master.write_all(&with_crc(&frame)).await.unwrap();
+            }
+        }
+        (master, requests)
+    }
+
+    // Read one FC03 request of a TCP connection and answer it.
+    // Returns false if the connection was closed instead.
+    async fn serve_tcp(stream: &mut tokio::net::TcpStream, answer: Answer) -> bool {
+        let mut request = [0; 12];
+        if stream.read_exact(&mut request).await.is_err() {
+            return false;
+        }
+        let address = u16::from_be_bytes([request[8], request[9]]);
+        let count = u16::from_be_bytes([request[10], request[11]]);
+        if let Some(answer) = answer(address, count) {
+            let pdu = answer_pdu(answer);
+            let mut frame = request[..4].to_vec();
+            frame.extend((pdu.len() as u16 + 1).to_be_bytes());
+            frame.push(request[6]);
+            frame.extend(pdu);
+            stream.write_all(&frame).await.unwrap();
+        }
+        true
+    }
+
+    // A pty pair, the slave end is opened by the device.
+    fn pty() -> (SerialStream, String) {
+        let (master, mut slave) = SerialStream::pair().unwrap();
+        let port = tokio_serial::SerialPort::name(&slave).unwrap();
+        // Release the slave end so connect can open it by path.
+        slave.set_exclusive(false).unwrap();
+        drop(slave);
+        (master, port)
+    }
+
+    #[test]
+    fn serial_config_survives_serde() {
+        let config = ModbusDeviceConfig::Serial(serial_config("/dev/ttyUSB1"));
+
+        let text = ron::to_string(&config).unwrap();
+
+        assert_eq!(ron::from_str::<ModbusDeviceConfig>(&text).unwrap(), config);
+    }
+
+    #[test]
+    fn serial_config_survives_device_buffer() {
+        let mut device = init_mb_tcp_device("127.0.0.1".to_owned(), 502, "PLC_1".to_owned(), 2);
+        device.config = ModbusDeviceConfig::Serial(serial_config("/dev/ttyUSB1"));
+
+        let buffer = ModbusDeviceBuffer::from_device(&device);
 
-    device
+        assert_eq!(buffer.to_device_config().unwrap(), device.config);
+    }
+
+    #[test]
+    fn scaled_values_are_typed_in_engineering_units() {
+        let mut channel = ModbusChannel::new(
+            1,
+            "Level".to_owned(),
+            ModbusRegisterSpace::HoldingRegister,
+            0,
+            ModbusChannelType::U16,
+            ModbusByteOrder::Abcd,
+        );
+        channel.scaling = ModbusScaling::GainOffset {
+            gain: 0.1,
+            offset: 0.0,
+        };
+        channel.clamp = Some((0.0, 50.0));
+
+        let value = channel.parse_value("12.5").unwrap();
+        assert_eq!(value, ModbusValue::Real(12.5));
+        assert_eq!(channel.to_raw(&value).unwrap(), ModbusValue::Real(125.0));
+        assert_eq!(channel.parse_value("80").unwrap(), ModbusValue::Real(50.0));
+        assert!(channel.parse_value("high").is_err());
+
+        channel.scaling = ModbusScaling::None;
+        assert_eq!(channel.parse_value("80").unwrap(), ModbusValue::UInt(50));
+    }
+
+    #[test]
+    fn a_clamp_doesnt_change_the_accepted_input() {
+        let mut channel = ModbusChannel::new(
+            1,
+            "Setpoint".to_owned(),
+            ModbusRegisterSpace::HoldingRegister,
+            0,
+            ModbusChannelType::I16,
+            ModbusByteOrder::Abcd,
+        );
+
+        for clamp in [None, Some((-10.5, 20.5))] {
+            channel.clamp = clamp;
+            assert!(channel.parse_value("12.7").is_err(), "{clamp:?}");
+            assert!(channel.parse_value("twelve").is_err(), "{clamp:?}");
+            assert_eq!(channel.parse_value(" 12 ").unwrap(), ModbusValue::Int(12));
+        }
+
+        // Integers are limited to the whole numbers inside the range.
+        assert_eq!(channel.parse_value("-50").unwrap(), ModbusValue::Int(-10));
+        assert_eq!(channel.parse_value("50").unwrap(), ModbusValue::Int(20));
+
+        channel.channel_type = ModbusChannelType::F32;
+        assert_eq!(
+            channel.parse_value("12.7").unwrap(),
+            ModbusValue::Real(12.7)
+        );
+        assert_eq!(channel.parse_value("50").unwrap(), ModbusValue::Real(20.5));
+    }
+
+    #[tokio::test]
+    async fn serial_connect_reads_over_pty() {
+        let (mut master, port) = pty();
+
+        let config = ModbusDeviceConfig::Serial(serial_config(&port));
+        let mut ctx = config.connect(Duration::from_secs(1), None).await.unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut request = [0; 8];
+            master.read_exact(&mut request).await.unwrap();
+            master
+                .write_all(&with_crc(&[7, 0x03, 0x02, 0x00, 0x2A]))
+                .await
+                .unwrap();
+            // Closing the master hangs up the slave, so hand it back.
+            (master, request)
+        });
+        let registers = tokio::time::timeout(
+            Duration::from_secs(5),
+            ctx.read_holding_registers(0x0010, 1),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        let (_master, request) = responder.await.unwrap();
+
+        assert_eq!(registers, vec![42]);
+        assert_eq!(
+            request.to_vec(),
+            with_crc(&[7, 0x03, 0x00, 0x10, 0x00, 0x01])
+        );
+    }
+
+    #[tokio::test]
+    async fn serial_timeouts_are_retried_on_the_same_port() {
+        let (master, port) = pty();
+        let mut device = test_device(ModbusDeviceConfig::Serial(serial_config(&port)));
+        device.request_config.retries = 2;
+        let traffic = TrafficMonitor::default().recorder();
+        let mut ctx = device.connect_to_device(traffic.capture()).await.unwrap();
+
+        let responder = tokio::spawn(serve_rtu(master, |_, _| None));
+        let result = device.poll(&mut ctx, &[0], &traffic).await;
+        let (_master, requests) = responder.await.unwrap();
+
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "HR 16: Timed out after 3 attempts"
+        );
+        assert_eq!(requests, vec![(0x10, 1); 3]);
+        assert_eq!(device.diagnostics.stats.timeouts, 3);
+        assert_eq!(device.diagnostics.stats.connects, 0);
+    }
+
+    #[tokio::test]
+    async fn exceptions_only_mark_their_channel() {
+        let (master, port) = pty();
+        let mut device = test_device(ModbusDeviceConfig::Serial(serial_config(&port)));
+        let traffic = TrafficMonitor::default().recorder();
+        let mut ctx = device.connect_to_device(traffic.capture()).await.unwrap();
+
+        let responder = tokio::spawn(serve_rtu(master, |address, _| match address {
+            0x10 => Some(Ok(vec![42])),
+            _ => Some(Err(0x02)),
+        }));
+        device.poll(&mut ctx, &[0, 1], &traffic).await.unwrap();
+        let (_master, requests) = responder.await.unwrap();
+
+        // Exceptions aren't retried.
+        assert_eq!(requests, vec![(0x10, 1), (0x80, 1)]);
+        assert_eq!(device.channels[0].value, ModbusValue::UInt(42));
+        assert_eq!(device.channels[0].quality, ChannelQuality::Good);
+        assert!(matches!(device.channels[1].quality, ChannelQuality::Bad(_)));
+        assert_eq!(
+            device.channels[1].last_exception.as_ref().unwrap().code,
+            0x02
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_timeouts_are_retried_on_a_new_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as usize;
+        let mut device = test_device(ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+            ip: "127.0.0.1".to_owned(),
+            port,
+            unit_id: 3,
+        }));
+        let connections = ModbusConnections::new(TrafficMonitor::default());
+
+        let server = tokio::spawn(async move {
+            let (mut first, _) = listener.accept().await.unwrap();
+            serve_tcp(&mut first, |_, _| None).await;
+            let (mut second, _) = listener.accept().await.unwrap();
+            serve_tcp(&mut second, |_, _| Some(Ok(vec![7]))).await;
+            // The connection of the timed out request was closed.
+            let mut byte = [0; 1];
+            first.read(&mut byte).await.unwrap() == 0
+        });
+        connections.poll(&mut device, &[0]).await.unwrap();
+
+        assert!(server.await.unwrap());
+        assert_eq!(device.channels[0].value, ModbusValue::UInt(7));
+        assert_eq!(device.diagnostics.stats.timeouts, 1);
+        assert_eq!(device.diagnostics.stats.connects, 2);
+    }
+
+    #[tokio::test]
+    async fn transport_errors_force_a_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as usize;
+        let mut device = test_device(ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+            ip: "127.0.0.1".to_owned(),
+            port,
+            unit_id: 3,
+        }));
+        device.request_config.retries = 2;
+        let connections = ModbusConnections::new(TrafficMonitor::default());
+
+        let server = tokio::spawn(async move {
+            // The first connection is dropped without an answer.
+            let (mut first, _) = listener.accept().await.unwrap();
+            let mut request = [0; 12];
+            first.read_exact(&mut request).await.unwrap();
+            drop(first);
+            let (mut second, _) = listener.accept().await.unwrap();
+            serve_tcp(&mut second, |_, _| Some(Ok(vec![9]))).await;
+        });
+
+        assert!(connections.poll(&mut device, &[0]).await.is_err());
+        // Not retried, the link is closed for the next poll.
+        assert_eq!(device.diagnostics.stats.requests, 1);
+        assert!(connections.link(&device).lock().await.is_none());
+
+        connections.poll(&mut device, &[0]).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(device.channels[0].value, ModbusValue::UInt(9));
+        assert_eq!(device.diagnostics.stats.connects, 2);
+    }
+
+    #[tokio::test]
+    async fn failed_blocks_are_read_channel_by_channel() {
+        let (master, port) = pty();
+        let mut device = test_device(ModbusDeviceConfig::Serial(serial_config(&port)));
+        device.channels.push(device.channels[0].clone());
+        for (index, channel) in device.channels.iter_mut().enumerate() {
+            channel.name = format!("MB{}", index + 1);
+            channel.address = 0x10 + index as u16;
+        }
+        let traffic = TrafficMonitor::default().recorder();
+        let mut ctx = device.connect_to_device(traffic.capture()).await.unwrap();
+
+        // 0x11 isn't served, so neither is the block.
+        let responder = tokio::spawn(serve_rtu(master, |address, count| {
+            match (address..address + count).contains(&0x11) {
+                true => Some(Err(0x02)),
+                false => Some(Ok(vec![address])),
+            }
+        }));
+        device.poll(&mut ctx, &[0, 1, 2], &traffic).await.unwrap();
+        let (_master, requests) = responder.await.unwrap();
+
+        assert_eq!(requests, vec![(0x10, 3), (0x10, 1), (0x11, 1), (0x12, 1)]);
+        let qualities: Vec<bool> = device
+            .channels
+            .iter()
+            .map(|channel| channel.quality.is_good())
+            .collect();
+        assert_eq!(qualities, vec![true, false, true]);
+        assert_eq!(device.channels[2].value, ModbusValue::UInt(0x12));
+
+        let exception = device.channels[1].last_exception.as_ref().unwrap();
+        assert_eq!((exception.function_code, exception.address), (3, 0x11));
+        let exception_counts: Vec<u64> = device
+            .channels
+            .iter()
+            .map(|channel| channel.exception_count)
+            .collect();
+        assert_eq!(exception_counts, vec![0, 1, 0]);
+
+        // The block and the channel are both in the device log.
+        let sources: Vec<&str> = device
+            .diagnostics
+            .log
+            .iter()
+            .map(|entry| entry.source.as_str())
+            .collect();
+        assert_eq!(sources, vec!["MB2", "Block of 3 channels"]);
+        assert_eq!(device.diagnostics.counts[&0x02], 2);
+    }
+
+    #[test]
+    fn calls_map_to_their_function_codes() {
+        let calls = [
+            (ModbusCall::WriteCoil(1, true), 5, 1),
+            (ModbusCall::WriteCoils(2, vec![true, false]), 15, 2),
+            (ModbusCall::WriteRegister(3, 7), 6, 1),
+            (ModbusCall::WriteRegisters(4, vec![1, 2, 3]), 16, 3),
+            (ModbusCall::MaskWriteRegister(5, 0xFFFE, 1), 22, 1),
+            (ModbusCall::ReadWriteRegisters(6, 4, 8, vec![1, 2]), 23, 2),
+            (ModbusCall::ReadDeviceIdentification(1, 0), 43, 0),
+        ];
+
+        for (call, function_code, count) in calls {
+            assert_eq!(call.function_code(), function_code, "{call:?}");
+            assert_eq!(call.request().function_code().value(), function_code);
+            assert_eq!(call.count(), count, "{call:?}");
+        }
+        // Read/write calls are logged at the written address.
+        let call = ModbusCall::ReadWriteRegisters(6, 4, 8, vec![1, 2]);
+        assert_eq!(call.address(), 8);
+        assert_eq!(call.to_string(), "FC23 8");
+        match ModbusCall::ReadDeviceIdentification(2, 3).request() {
+            Request::Custom(0x2B, data) => assert_eq!(data.as_ref(), &[0x0E, 2, 3]),
+            request => panic!("{request:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_back_writes_take_the_value_the_device_holds() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as usize;
+        let mut device = test_device(ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+            ip: "127.0.0.1".to_owned(),
+            port,
+            unit_id: 3,
+        }));
+        device.block_config.read_back_writes = true;
+        let connections = ModbusConnections::new(TrafficMonitor::default());
+
+        // The device caps the written register at 100.
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0; 7];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut pdu = vec![0; u16::from_be_bytes([header[4], header[5]]) as usize - 1];
+            stream.read_exact(&mut pdu).await.unwrap();
+            let written = u16::from_be_bytes([pdu[10], pdu[11]]);
+
+            let mut frame = header[..4].to_vec();
+            frame.extend(5u16.to_be_bytes());
+            frame.extend([header[6], 0x17, 2]);
+            frame.extend(written.min(100).to_be_bytes());
+            stream.write_all(&frame).await.unwrap();
+            pdu
+        });
+        let channel_id = device.channels[1].id;
+        connections
+            .write(&mut device, channel_id, &ModbusValue::UInt(250))
+            .await
+            .unwrap();
+
+        // Read and write 0x80, one register of value 250.
+        let pdu = server.await.unwrap();
+        assert_eq!(pdu, vec![0x17, 0, 0x80, 0, 1, 0, 0x80, 0, 1, 2, 0, 250]);
+        assert_eq!(device.channels[1].value, ModbusValue::UInt(100));
+        assert!(device.channels[1].quality.is_good());
+        assert_eq!(device.diagnostics.stats.requests, 1);
+    }
 }
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+use tokio_modbus::ExceptionCode;
+
+// Exceptions kept in the device log, older ones are dropped.
+const MAX_EXCEPTION_LOG: usize = 100;
+
+// Round trip times kept for the percentiles.
+const MAX_LATENCY_SAMPLES: usize = 1000;
+
+// A Modbus exception response and the request that caused it.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ModbusException {
+    pub function_code: u8,
+    pub address: u16,
+    pub code: u8,
+    pub time: SystemTime,
+}
+
+impl ModbusException {
+    pub fn new(function_code: u8, address: u16, exception: ExceptionCode) -> Self {
+        Self {
+            function_code,
+            address,
+            code: exception.into(),
+            time: SystemTime::now(),
+        }
+    }
+
+    pub fn description(&self) -> String {
+        exception_description(self.code)
+    }
+}
+
+// Meaning of an exception code, e.g. "Illegal data address".
+pub fn exception_description(code: u8) -> String {
+    match ExceptionCode::new(code) {
+        ExceptionCode::Custom(_) => "Unknown exception".to_owned(),
+        exception => format!("{exception}"),
+    }
+}
+
+impl Display for ModbusException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FC{:02} {}: {} ({:#04X})",
+            self.function_code,
+            self.address,
+            self.description(),
+            self.code
+        )
+    }
+}
+
+// An exception in the device log with what was being read.
+#[derive(Clone, Debug)]
+pub struct ModbusExceptionEntry {
+    // Channel name, or the channel count for block reads.
+    pub source: String,
+    pub exception: ModbusException,
+}
+
+// How a request to the device ended.
+pub enum ModbusRequestOutcome {
+    // Answered, with the round trip time.
+    Success(Duration),
+    Exception(Duration),
+    // Transport error, the connection is dropped.
+    Failure,
+    Timeout,
+}
+
+// Request counts and round trip times of a device link.
+#[derive(Clone, Debug, Default)]
+pub struct ModbusCommStats {
+    pub requests: u64,
+    pub successes: u64,
+    pub exceptions: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub connects: u64,
+    pub last_connected: Option<SystemTime>,
+    pub latency_min: Option<Duration>,
+    pub latency_max: Option<Duration>,
+    latency_total: Duration,
+    // Latest answered requests, for the percentiles.
+    latencies: VecDeque<Duration>,
+}
+
+impl ModbusCommStats {
+    pub fn record_request(&mut self, outcome: ModbusRequestOutcome) {
+        self.requests += 1;
+
+        let latency = match outcome {
+            ModbusRequestOutcome::Success(latency) => {
+                self.successes += 1;
+                latency
+            }
+            ModbusRequestOutcome::Exception(latency) => {
+                self.exceptions += 1;
+                latency
+            }
+            ModbusRequestOutcome::Failure => {
+                self.failures += 1;
+                return;
+            }
+            ModbusRequestOutcome::Timeout => {
+                self.timeouts += 1;
+                return;
+            }
+        };
+
+        self.latency_min = Some(self.latency_min.map_or(latency, |min| min.min(latency)));
+        self.latency_max = Some(self.latency_max.map_or(latency, |max| max.max(latency)));
+        self.latency_total += latency;
+        self.latencies.push_front(latency);
+        self.latencies.truncate(MAX_LATENCY_SAMPLES);
+    }
+
+    pub fn record_connect(&mut self) {
+        self.connects += 1;
+        self.last_connected = Some(SystemTime::now());
+    }
+
+    // Connections made after the first one.
+    pub fn reconnects(&self) -> u64 {
+        self.connects.saturating_sub(1)
+    }
+
+    pub fn latency_avg(&self) -> Option<Duration> {
+        let answered = self.successes + self.exceptions;
+        (answered > 0).then(|| self.latency_total.div_f64(answered as f64))
+    }
+
+    // Of the latest answered requests.
+    pub fn latency_p95(&self) -> Option<Duration> {
+        let mut latencies: Vec<Duration> = self.latencies.iter().copied().collect();
+        latencies.sort();
+
+        let index = (latencies.len() * 95).div_ceil(100).checked_sub(1)?;
+        latencies.get(index).copied()
+    }
+}
+
+// Exceptions and link statistics of a device since the app started.
+#[derive(Clone, Debug, Default)]
+pub struct ModbusDiagnostics {
+    // Newest first.
+    pub log: VecDeque<ModbusExceptionEntry>,
+    // Count per exception code.
+    pub counts: BTreeMap<u8, u64>,
+    pub stats: ModbusCommStats,
+}
+
+impl ModbusDiagnostics {
+    pub fn record(&mut self, source: String, exception: &ModbusException) {
+        *self.counts.entry(exception.code).or_default() += 1;
+
+        self.log.push_front(ModbusExceptionEntry {
+            source,
+            exception: exception.clone(),
+        });
+        self.log.truncate(MAX_EXCEPTION_LOG);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.log.clear();
+        self.counts.clear();
+        // The link is still up, we only reset the counts.
+        self.stats = ModbusCommStats {
+            last_connected: self.stats.last_connected,
+            ..Default::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn exception(code: ExceptionCode) -> ModbusException {
+        ModbusException::new(3, 100, code)
+    }
+
+    #[test]
+    fn p95_of_no_sample_is_none() {
+        let mut stats = ModbusCommStats::default();
+        stats.record_request(ModbusRequestOutcome::Timeout);
+        stats.record_request(ModbusRequestOutcome::Failure);
+
+        assert_eq!(stats.latency_p95(), None);
+        assert_eq!(stats.latency_avg(), None);
+        assert_eq!(stats.latency_min, None);
+    }
+
+    #[test]
+    fn p95_of_one_sample_is_that_sample() {
+        let mut stats = ModbusCommStats::default();
+        stats.record_request(ModbusRequestOutcome::Success(ms(12)));
+
+        assert_eq!(stats.latency_p95(), Some(ms(12)));
+        assert_eq!(stats.latency_avg(), Some(ms(12)));
+    }
+
+    #[test]
+    fn p95_only_uses_the_latest_window() {
+        let mut stats = ModbusCommStats::default();
+        // The first 100 fall out of the window.
+        for latency in 1..=1100 {
+            stats.record_request(ModbusRequestOutcome::Success(ms(latency)));
+        }
+
+        // The 950th of 101..=1100 ms.
+        assert_eq!(stats.latency_p95(), Some(ms(1050)));
+        // The other figures cover every request.
+        assert_eq!(stats.latency_min, Some(ms(1)));
+        assert_eq!(stats.latency_max, Some(ms(1100)));
+        assert_eq!(stats.latency_avg(), Some(Duration::from_micros(550_500)));
+    }
+
+    #[test]
+    fn outcomes_are_counted() {
+        let mut stats = ModbusCommStats::default();
+        stats.record_request(ModbusRequestOutcome::Success(ms(10)));
+        stats.record_request(ModbusRequestOutcome::Exception(ms(20)));
+        stats.record_request(ModbusRequestOutcome::Exception(ms(30)));
+        stats.record_request(ModbusRequestOutcome::Failure);
+        stats.record_request(ModbusRequestOutcome::Timeout);
+
+        assert_eq!(
+            (
+                stats.requests,
+                stats.successes,
+                stats.exceptions,
+                stats.failures,
+                stats.timeouts
+            ),
+            (5, 1, 2, 1, 1)
+        );
+        // Exceptions are answers, they count in the latency.
+        assert_eq!(stats.latency_avg(), Some(ms(20)));
+    }
+
+    #[test]
+    fn reconnects_dont_count_the_first_connection() {
+        let mut stats = ModbusCommStats::default();
+        assert_eq!(stats.reconnects(), 0);
+
+        stats.record_connect();
+        assert_eq!(stats.reconnects(), 0);
+        assert!(stats.last_connected.is_some());
+
+        stats.record_connect();
+        stats.record_connect();
+        assert_eq!((stats.connects, stats.reconnects()), (3, 2));
+    }
+
+    #[test]
+    fn exceptions_are_counted_by_code() {
+        let mut diagnostics = ModbusDiagnostics::default();
+        diagnostics.record(
+            "MB1".to_owned(),
+            &exception(ExceptionCode::IllegalDataAddress),
+        );
+        diagnostics.record(
+            "MB2".to_owned(),
+            &exception(ExceptionCode::IllegalDataAddress),
+        );
+        diagnostics.record(
+            "MB1".to_owned(),
+            &exception(ExceptionCode::ServerDeviceBusy),
+        );
+
+        assert_eq!(diagnostics.counts, BTreeMap::from([(0x02, 2), (0x06, 1)]));
+        assert_eq!(diagnostics.total(), 3);
+        // Newest first.
+        let sources: Vec<&str> = diagnostics.log.iter().map(|e| e.source.as_str()).collect();
+        assert_eq!(sources, vec!["MB1", "MB2", "MB1"]);
+    }
+
+    #[test]
+    fn the_exception_log_keeps_the_newest() {
+        let mut diagnostics = ModbusDiagnostics::default();
+        for index in 0..150 {
+            diagnostics.record(
+                format!("MB{index}"),
+                &exception(ExceptionCode::IllegalDataValue),
+            );
+        }
+
+        assert_eq!(diagnostics.log.len(), MAX_EXCEPTION_LOG);
+        assert_eq!(diagnostics.log[0].source, "MB149");
+        // Counted even when dropped from the log.
+        assert_eq!(diagnostics.total(), 150);
+    }
+
+    #[test]
+    fn clear_keeps_the_connection_time() {
+        let mut diagnostics = ModbusDiagnostics::default();
+        diagnostics.stats.record_connect();
+        diagnostics
+            .stats
+            .record_request(ModbusRequestOutcome::Exception(ms(5)));
+        diagnostics.record("MB1".to_owned(), &exception(ExceptionCode::IllegalFunction));
+        let last_connected = diagnostics.stats.last_connected;
+
+        diagnostics.clear();
+
+        assert_eq!(diagnostics.total(), 0);
+        assert!(diagnostics.log.is_empty());
+        assert_eq!(diagnostics.stats.requests, 0);
+        assert_eq!(diagnostics.stats.connects, 0);
+        assert_eq!(diagnostics.stats.last_connected, last_connected);
+    }
+
+    #[test]
+    fn exceptions_are_described() {
+        let exception = exception(ExceptionCode::IllegalDataAddress);
+
+        assert_eq!(
+            format!("{exception}"),
+            "FC03 100: Illegal data address (0x02)"
+        );
+        assert_eq!(exception_description(0x42), "Unknown exception");
+    }
+}
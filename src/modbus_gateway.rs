@@ -0,0 +1,669 @@
+use crate::app::{ModbusWrite, ThreadStatus};
+use crate::calculation_channel::*;
+use crate::channel_quality::*;
+use crate::modbus_decode::*;
+use crate::modbus_device::*;
+use crate::modbus_image::*;
+use anyhow::Result;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio_modbus::prelude::*;
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+
+// Where a republished value comes from.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum GatewaySource {
+    Channel { device_id: usize, channel_id: usize },
+    Calculation { id: usize },
+}
+
+impl Display for GatewaySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatewaySource::Channel {
+                device_id,
+                channel_id,
+            } => write!(f, "DEVICE {device_id} CHANNEL {channel_id}"),
+            GatewaySource::Calculation { id } => write!(f, "CALCULATION {id}"),
+        }
+    }
+}
+
+// A value republished at an address of the gateway.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct GatewayMapping {
+    // Name of the source, shown in the UI and write status.
+    pub name: String,
+    pub source: GatewaySource,
+    pub register_space: ModbusRegisterSpace,
+    pub address: u16,
+    pub channel_type: ModbusChannelType,
+    #[serde(default)]
+    pub byte_order: ModbusByteOrder,
+    // Client writes are forwarded to the source channel.
+    #[serde(default)]
+    pub writable: bool,
+}
+
+impl GatewayMapping {
+    fn count(&self) -> u16 {
+        if self.register_space.is_bit() {
+            1
+        } else {
+            self.channel_type.register_count()
+        }
+    }
+
+    // Serve the value, or mark it unavailable if it can't be trusted.
+    fn store(&self, image: &mut ModbusImage, value: &ModbusValue, quality: &ChannelQuality) {
+        let available = matches!(quality, ChannelQuality::Good | ChannelQuality::Uncertain(_));
+        image.set_available(self.register_space, self.address, self.count(), available);
+
+        let value = match value {
+            ModbusValue::Str(_) => value.clone(),
+            value => match value.as_f64() {
+                Some(value) => self.channel_type.from_f64(value),
+                None => return,
+            },
+        };
+        if let Ok(words) = self.channel_type.encode(&value, self.byte_order) {
+            image.store(self.register_space, self.address, &words);
+        }
+    }
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_owned()
+}
+
+// The server address and its register map.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct GatewayConfig {
+    pub enabled: bool,
+    // Local only by default, 0.0.0.0 serves every interface.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    pub port: u16,
+    // Forward client writes to the source devices.
+    pub forward_writes: bool,
+    pub mappings: Vec<GatewayMapping>,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: default_bind_address(),
+            port: 5020,
+            forward_writes: false,
+            mappings: Vec::new(),
+        }
+    }
+}
+
+impl GatewayConfig {
+    // Map every device channel and calculation one after the other,
+    // bits as coils and everything else as F32 holding registers.
+    pub fn map_all(
+        devices: &[ModbusDevice],
+        calculations: &[CalculationChannel],
+    ) -> Vec<GatewayMapping> {
+        let mut mappings = Vec::new();
+        let mut coil_address = 0;
+        let mut register_address = 0;
+
+        let mut push = |name: String, source: GatewaySource, is_bit: bool| {
+            let (register_space, channel_type, address) = if is_bit {
+                coil_address += 1;
+                (
+                    ModbusRegisterSpace::Coil,
+                    ModbusChannelType::Bool,
+                    coil_address - 1,
+                )
+            } else {
+                register_address += 2;
+                (
+                    ModbusRegisterSpace::HoldingRegister,
+                    ModbusChannelType::F32,
+                    register_address - 2,
+                )
+            };
+
+            mappings.push(GatewayMapping {
+                name,
+                source,
+                register_space,
+                address,
+                channel_type,
+                byte_order: ModbusByteOrder::Abcd,
+                writable: false,
+            });
+        };
+
+        for device in devices {
+            for channel in &device.channels {
+                // Strings don't fit a F32, they are left for manual mapping.
+                if matches!(channel.channel_type, ModbusChannelType::String(_)) {
+                    continue;
+                }
+                let source = GatewaySource::Channel {
+                    device_id: device.id,
+                    channel_id: channel.id,
+                };
+                let is_bit = matches!(channel.channel_type, ModbusChannelType::Bool);
+                push(format!("{}.{}", device.name, channel.name), source, is_bit);
+            }
+        }
+        for calculation in calculations {
+            let source = GatewaySource::Calculation { id: calculation.id };
+            push(calculation.name.clone(), source, false);
+        }
+
+        mappings
+    }
+}
+
+// Answers the requests of one client connection.
+struct GatewayService {
+    image: Arc<Mutex<ModbusImage>>,
+    mappings: Arc<Vec<GatewayMapping>>,
+    // None if writes aren't forwarded.
+    sender_write: Option<Sender<ModbusWrite>>,
+    running: Arc<AtomicBool>,
+}
+
+impl GatewayService {
+    // Split a client write into the writes of the mapped channels.
+    // The write must cover whole writable mappings.
+    fn resolve_writes(
+        &self,
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        words: &[u16],
+    ) -> Result<Vec<ModbusWrite>, ExceptionCode> {
+        let mut writes = Vec::new();
+        let mut offset = 0;
+
+        while offset < words.len() {
+            let start = address.wrapping_add(offset as u16);
+            let mapping = self
+                .mappings
+                .iter()
+                .find(|mapping| {
+                    mapping.register_space == register_space && mapping.address == start
+                })
+                .ok_or(ExceptionCode::IllegalDataAddress)?;
+            let GatewaySource::Channel {
+                device_id,
+                channel_id,
+            } = mapping.source
+            else {
+                return Err(ExceptionCode::IllegalDataAddress);
+            };
+            if !mapping.writable {
+                return Err(ExceptionCode::IllegalDataAddress);
+            }
+
+            let count = mapping.count() as usize;
+            let mapping_words = words
+                .get(offset..offset + count)
+                .ok_or(ExceptionCode::IllegalDataAddress)?;
+            let value = if register_space.is_bit() {
+                ModbusValue::Bool(mapping_words[0] != 0)
+            } else {
+                mapping
+                    .channel_type
+                    .decode(mapping_words, mapping.byte_order)
+                    .map_err(|_| ExceptionCode::IllegalDataValue)?
+            };
+
+            writes.push(ModbusWrite {
+                device_id,
+                channel_id,
+                channel_name: mapping.name.clone(),
+                value,
+            });
+            offset += count;
+        }
+
+        Ok(writes)
+    }
+
+    fn forward(
+        &self,
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        words: &[u16],
+    ) -> Result<(), ExceptionCode> {
+        let Some(sender_write) = &self.sender_write else {
+            return Err(ExceptionCode::IllegalFunction);
+        };
+
+        // The device answers later, a full queue means it's busy.
+        for write in self.resolve_writes(register_space, address, words)? {
+            sender_write
+                .try_send(write)
+                .map_err(|_| ExceptionCode::ServerDeviceBusy)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, request: Request<'static>) -> Result<Response, ExceptionCode> {
+        if !self.running.load(Ordering::Relaxed) {
+            return Err(ExceptionCode::GatewayPathUnavailable);
+        }
+
+        match request {
+            Request::WriteSingleCoil(address, coil) => {
+                self.forward(ModbusRegisterSpace::Coil, address, &[u16::from(coil)])?;
+                Ok(Response::WriteSingleCoil(address, coil))
+            }
+            Request::WriteMultipleCoils(address, coils) => {
+                let words: Vec<u16> = coils.iter().map(|coil| u16::from(*coil)).collect();
+                self.forward(ModbusRegisterSpace::Coil, address, &words)?;
+                Ok(Response::WriteMultipleCoils(address, coils.len() as u16))
+            }
+            Request::WriteSingleRegister(address, word) => {
+                self.forward(ModbusRegisterSpace::HoldingRegister, address, &[word])?;
+                Ok(Response::WriteSingleRegister(address, word))
+            }
+            Request::WriteMultipleRegisters(address, words) => {
+                self.forward(ModbusRegisterSpace::HoldingRegister, address, &words)?;
+                Ok(Response::WriteMultipleRegisters(
+                    address,
+                    words.len() as u16,
+                ))
+            }
+            // These would change the image without reaching the devices.
+            Request::MaskWriteRegister(..) | Request::ReadWriteMultipleRegisters(..) => {
+                Err(ExceptionCode::IllegalFunction)
+            }
+            request => self.image.lock().unwrap().handle(request),
+        }
+    }
+}
+
+impl tokio_modbus::server::Service for GatewayService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = std::future::Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        std::future::ready(self.handle(request))
+    }
+}
+
+// A running gateway server, stopped when dropped.
+pub struct ModbusGateway {
+    config: GatewayConfig,
+    image: Arc<Mutex<ModbusImage>>,
+    // Client connections outlive the server task,
+    // they are refused once this is false.
+    running: Arc<AtomicBool>,
+    server: JoinHandle<()>,
+}
+
+impl ModbusGateway {
+    // Client and server errors are reported as thread status.
+    pub async fn start(
+        config: GatewayConfig,
+        sender_write: Sender<ModbusWrite>,
+        sender_status: Sender<ThreadStatus>,
+    ) -> Result<Self> {
+        let ip: IpAddr = config
+            .bind_address
+            .trim()
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid bind address: {e}"))?;
+        let socket_addr = SocketAddr::new(ip, config.port);
+        let listener = TcpListener::bind(socket_addr).await?;
+        let server = Server::new(listener);
+
+        let image = Arc::new(Mutex::new(ModbusImage::default()));
+        let running = Arc::new(AtomicBool::new(true));
+        let service = Arc::new(GatewayService {
+            image: image.clone(),
+            mappings: Arc::new(config.mappings.clone()),
+            sender_write: config.forward_writes.then_some(sender_write),
+            running: running.clone(),
+        });
+
+        // Nothing is served before the first update.
+        {
+            let mut image = image.lock().unwrap();
+            for mapping in &config.mappings {
+                let words = vec![0; mapping.count() as usize];
+                image.store(mapping.register_space, mapping.address, &words);
+                image.set_available(
+                    mapping.register_space,
+                    mapping.address,
+                    mapping.count(),
+                    false,
+                );
+            }
+        }
+
+        let server = tokio::spawn(async move {
+            let on_connected = move |stream, socket_addr| {
+                let service = service.clone();
+                async move {
+                    accept_tcp_connection(stream, socket_addr, move |_| Ok(Some(service.clone())))
+                }
+            };
+            let client_status = sender_status.clone();
+            let on_process_error = move |e| {
+                // A full status channel drops the message rather than blocking the client.
+                client_status
+                    .try_send(ThreadStatus::Error(format!("Gateway: Client error: {e}")))
+                    .ok();
+            };
+
+            if let Err(e) = server.serve(&on_connected, on_process_error).await {
+                sender_status
+                    .send(ThreadStatus::Error(format!("Gateway: Server error: {e}")))
+                    .await
+                    .ok();
+            }
+        });
+
+        Ok(Self {
+            config,
+            image,
+            running,
+            server,
+        })
+    }
+
+    pub fn config(&self) -> &GatewayConfig {
+        &self.config
+    }
+
+    // Republish the channels of a polled device.
+    pub fn update_device(&self, device: &ModbusDevice) {
+        let mut image = self.image.lock().unwrap();
+
+        for mapping in &self.config.mappings {
+            let GatewaySource::Channel {
+                device_id,
+                channel_id,
+            } = mapping.source
+            else {
+                continue;
+            };
+            if device_id != device.id {
+                continue;
+            }
+
+            if let Some(channel) = device
+                .channels
+                .iter()
+                .find(|channel| channel.id == channel_id)
+            {
+                mapping.store(&mut image, &channel.value, &channel.quality);
+            }
+        }
+    }
+
+    // Republish the calculation results.
+    pub fn update_calculations(&self, calculations: &[CalculationChannel]) {
+        let mut image = self.image.lock().unwrap();
+
+        for mapping in &self.config.mappings {
+            let GatewaySource::Calculation { id } = mapping.source else {
+                continue;
+            };
+
+            if let Some(calculation) = calculations.iter().find(|calculation| calculation.id == id)
+            {
+                let quality = match &calculation.error {
+                    Some(error) => ChannelQuality::Bad(error.clone()),
+                    None => calculation.quality.clone(),
+                };
+                mapping.store(&mut image, &ModbusValue::Real(calculation.value), &quality);
+            }
+        }
+    }
+}
+
+impl Drop for ModbusGateway {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.server.abort();
+        self.image.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn mapping(
+        address: u16,
+        channel_type: ModbusChannelType,
+        source: GatewaySource,
+        writable: bool,
+    ) -> GatewayMapping {
+        GatewayMapping {
+            name: format!("HR{address}"),
+            source,
+            register_space: ModbusRegisterSpace::HoldingRegister,
+            address,
+            channel_type,
+            byte_order: ModbusByteOrder::Abcd,
+            writable,
+        }
+    }
+
+    fn channel(channel_id: usize) -> GatewaySource {
+        GatewaySource::Channel {
+            device_id: 1,
+            channel_id,
+        }
+    }
+
+    // HR 0 F32, HR 2 U16 and HR 3 F32 writable,
+    // HR 5 read-only and HR 6 a calculation.
+    fn gateway_service(forward_writes: bool) -> (GatewayService, Receiver<ModbusWrite>) {
+        let mappings = vec![
+            mapping(0, ModbusChannelType::F32, channel(1), true),
+            mapping(2, ModbusChannelType::U16, channel(2), true),
+            mapping(3, ModbusChannelType::F32, channel(3), true),
+            mapping(5, ModbusChannelType::U16, channel(4), false),
+            mapping(
+                6,
+                ModbusChannelType::U16,
+                GatewaySource::Calculation { id: 1 },
+                true,
+            ),
+        ];
+        let (sender_write, receiver_write) = mpsc::channel(16);
+        let service = GatewayService {
+            image: Arc::default(),
+            mappings: Arc::new(mappings),
+            sender_write: forward_writes.then_some(sender_write),
+            running: Arc::new(AtomicBool::new(true)),
+        };
+
+        (service, receiver_write)
+    }
+
+    fn f32_words(value: f32) -> Vec<u16> {
+        ModbusChannelType::F32
+            .encode(&ModbusValue::Real(value as f64), ModbusByteOrder::Abcd)
+            .unwrap()
+    }
+
+    fn write_registers(
+        service: &GatewayService,
+        address: u16,
+        words: Vec<u16>,
+    ) -> Result<Response, ExceptionCode> {
+        service.handle(Request::WriteMultipleRegisters(address, Cow::Owned(words)))
+    }
+
+    fn received(receiver: &mut Receiver<ModbusWrite>) -> Vec<(usize, ModbusValue)> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|write| (write.channel_id, write.value))
+            .collect()
+    }
+
+    #[test]
+    fn register_writes_are_split_across_mappings() {
+        let (service, mut receiver) = gateway_service(true);
+        let mut words = f32_words(1.5);
+        words.push(7);
+        words.extend(f32_words(-2.0));
+
+        assert_eq!(
+            write_registers(&service, 0, words),
+            Ok(Response::WriteMultipleRegisters(0, 5))
+        );
+        assert_eq!(
+            received(&mut receiver),
+            vec![
+                (1, ModbusValue::Real(1.5)),
+                (2, ModbusValue::UInt(7)),
+                (3, ModbusValue::Real(-2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn partial_writes_are_rejected_whole() {
+        let (service, mut receiver) = gateway_service(true);
+        let words = f32_words(1.5);
+
+        // The first or the second word of a F32 alone.
+        assert_eq!(
+            write_registers(&service, 0, words[..1].to_vec()),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            service.handle(Request::WriteSingleRegister(1, words[1])),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // HR 2 is whole but HR 3 isn't, neither is sent.
+        assert_eq!(
+            write_registers(&service, 2, vec![7, words[0]]),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn read_only_mappings_refuse_writes() {
+        let (service, mut receiver) = gateway_service(true);
+
+        assert_eq!(
+            service.handle(Request::WriteSingleRegister(5, 1)),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Calculations can't be written, even if marked writable.
+        assert_eq!(
+            service.handle(Request::WriteSingleRegister(6, 1)),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            service.handle(Request::WriteSingleRegister(20, 1)),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            service.handle(Request::MaskWriteRegister(2, 0, 1)),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert!(received(&mut receiver).is_empty());
+
+        // Nothing is writable when writes aren't forwarded.
+        let (service, _receiver) = gateway_service(false);
+        assert_eq!(
+            service.handle(Request::WriteSingleRegister(2, 1)),
+            Err(ExceptionCode::IllegalFunction)
+        );
+    }
+
+    #[test]
+    fn bad_sources_are_unavailable() {
+        let mapping = mapping(0, ModbusChannelType::F32, channel(1), false);
+        let mut image = ModbusImage::default();
+        let read = Request::ReadHoldingRegisters(0, 2);
+
+        mapping.store(&mut image, &ModbusValue::Real(1.5), &ChannelQuality::Good);
+        assert_eq!(
+            image.handle(read.clone()),
+            Ok(Response::ReadHoldingRegisters(f32_words(1.5)))
+        );
+
+        for quality in [
+            ChannelQuality::Bad("Timeout".to_owned()),
+            ChannelQuality::Stale,
+            ChannelQuality::NotYetRead,
+        ] {
+            mapping.store(&mut image, &ModbusValue::Real(2.5), &quality);
+            assert_eq!(
+                image.handle(read.clone()),
+                Err(ExceptionCode::GatewayTargetDevice),
+                "{quality}"
+            );
+        }
+
+        // Uncertain values are still served.
+        let quality = ChannelQuality::Uncertain("Clamped".to_owned());
+        mapping.store(&mut image, &ModbusValue::Real(3.5), &quality);
+        assert_eq!(
+            image.handle(read),
+            Ok(Response::ReadHoldingRegisters(f32_words(3.5)))
+        );
+    }
+
+    #[test]
+    fn map_all_addresses_dont_overlap() {
+        let mut devices: Vec<ModbusDevice> = (1..=2)
+            .map(|id| {
+                let mut device =
+                    init_mb_tcp_device("127.0.0.1".to_owned(), 502, format!("Device_{id}"), 5);
+                device.id = id;
+                device
+            })
+            .collect();
+        let channel_types = [
+            ModbusChannelType::Bool,
+            ModbusChannelType::U16,
+            ModbusChannelType::F64,
+            ModbusChannelType::String(4),
+            ModbusChannelType::Bool,
+        ];
+        for (channel, channel_type) in devices[0].channels.iter_mut().zip(channel_types) {
+            channel.channel_type = channel_type;
+        }
+        let calculations = init_channel_list("Device_1", 3);
+
+        let mappings = GatewayConfig::map_all(&devices, &calculations);
+
+        // Every channel but the string, and every calculation.
+        assert_eq!(mappings.len(), 9 + 3);
+        let coils = mappings
+            .iter()
+            .filter(|mapping| mapping.register_space == ModbusRegisterSpace::Coil)
+            .count();
+        assert_eq!(coils, 2);
+
+        for (index, a) in mappings.iter().enumerate() {
+            for b in &mappings[index + 1..] {
+                let overlap = a.register_space == b.register_space
+                    && a.address < b.address + b.count()
+                    && b.address < a.address + a.count();
+                assert!(!overlap, "{} and {} overlap", a.name, b.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+// Function code 43 with the MEI type 14 reads the device identification.
+pub const ENCAPSULATED_INTERFACE_FUNCTION_CODE: u8 = 0x2B;
+pub const READ_DEVICE_IDENTIFICATION_MEI: u8 = 0x0E;
+
+// Read device ID codes: the basic objects 0 to 2 are mandatory,
+// the regular ones 3 to 6 optional.
+pub const READ_DEVICE_ID_BASIC: u8 = 0x01;
+pub const READ_DEVICE_ID_REGULAR: u8 = 0x02;
+
+// Vendor and product information answered to FC43/14.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ModbusDeviceIdentification {
+    // Object values by object ID.
+    pub objects: BTreeMap<u8, String>,
+}
+
+impl ModbusDeviceIdentification {
+    pub fn new(vendor_name: &str, product_code: &str, revision: &str) -> Self {
+        let objects = [(0, vendor_name), (1, product_code), (2, revision)]
+            .into_iter()
+            .map(|(id, value)| (id, value.to_owned()))
+            .collect();
+
+        Self { objects }
+    }
+
+    pub fn vendor_name(&self) -> &str {
+        self.object(0)
+    }
+
+    pub fn product_code(&self) -> &str {
+        self.object(1)
+    }
+
+    pub fn revision(&self) -> &str {
+        self.object(2)
+    }
+
+    // Empty if the device didn't send it.
+    pub fn object(&self, id: u8) -> &str {
+        self.objects
+            .get(&id)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    // The request data after the function code.
+    pub fn request(read_device_id_code: u8, object_id: u8) -> Vec<u8> {
+        vec![
+            READ_DEVICE_IDENTIFICATION_MEI,
+            read_device_id_code,
+            object_id,
+        ]
+    }
+
+    // Add the objects of a response, and return the object ID
+    // to ask for next if the device has more to send.
+    pub fn parse_response(&mut self, data: &[u8]) -> Result<Option<u8>> {
+        let [mei, _read_code, _conformity, more_follows, next_object_id, count, objects @ ..] =
+            data
+        else {
+            anyhow::bail!("Device identification response is too short");
+        };
+        if *mei != READ_DEVICE_IDENTIFICATION_MEI {
+            anyhow::bail!("Unexpected MEI type {mei:#04X}");
+        }
+
+        let mut rest = objects;
+        for _ in 0..*count {
+            let [id, len, tail @ ..] = rest else {
+                anyhow::bail!("Device identification object is truncated");
+            };
+            let len = *len as usize;
+            if tail.len() < len {
+                anyhow::bail!("Device identification object {id} is truncated");
+            }
+
+            let value = String::from_utf8_lossy(&tail[..len]).into_owned();
+            self.objects.insert(*id, value);
+            rest = &tail[len..];
+        }
+
+        Ok((*more_follows == 0xFF).then_some(*next_object_id))
+    }
+
+    // Answer a request with all the objects of the requested category
+    // in one response, the way the simulator does it.
+    pub fn response(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let [READ_DEVICE_IDENTIFICATION_MEI, read_code, object_id] = request else {
+            return None;
+        };
+        let last_id = match *read_code {
+            READ_DEVICE_ID_BASIC => 2,
+            READ_DEVICE_ID_REGULAR => 0x7F,
+            _ => return None,
+        };
+
+        let objects: Vec<(&u8, &String)> = self
+            .objects
+            .range(*object_id..=last_id)
+            .filter(|(_, value)| value.len() <= u8::MAX as usize)
+            .collect();
+        let conformity = if self.objects.keys().any(|id| *id > 2) {
+            READ_DEVICE_ID_REGULAR
+        } else {
+            READ_DEVICE_ID_BASIC
+        };
+
+        let mut data = vec![
+            READ_DEVICE_IDENTIFICATION_MEI,
+            *read_code,
+            conformity,
+            0x00,
+            0x00,
+            objects.len() as u8,
+        ];
+        for (id, value) in objects {
+            data.push(*id);
+            data.push(value.len() as u8);
+            data.extend_from_slice(value.as_bytes());
+        }
+
+        Some(data)
+    }
+}
+
+impl Display for ModbusDeviceIdentification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.vendor_name(),
+            self.product_code(),
+            self.revision()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identification() -> ModbusDeviceIdentification {
+        let mut identification = ModbusDeviceIdentification::new("Acme", "PLC-9", "1.2");
+        identification.objects.insert(5, "Model X".to_owned());
+        identification
+    }
+
+    // Read the objects back from the answer to a request.
+    fn round_trip(
+        identification: &ModbusDeviceIdentification,
+        read_code: u8,
+        object_id: u8,
+    ) -> ModbusDeviceIdentification {
+        let request = ModbusDeviceIdentification::request(read_code, object_id);
+        let response = identification.response(&request).unwrap();
+        let mut parsed = ModbusDeviceIdentification::default();
+        assert_eq!(parsed.parse_response(&response).unwrap(), None);
+        parsed
+    }
+
+    #[test]
+    fn regular_reads_return_every_object() {
+        let identification = identification();
+
+        let parsed = round_trip(&identification, READ_DEVICE_ID_REGULAR, 0);
+
+        assert_eq!(parsed, identification);
+        assert_eq!(parsed.to_string(), "Acme PLC-9 1.2");
+        assert_eq!(parsed.object(5), "Model X");
+    }
+
+    #[test]
+    fn basic_reads_stop_at_the_revision() {
+        let parsed = round_trip(&identification(), READ_DEVICE_ID_BASIC, 1);
+
+        assert_eq!(parsed.vendor_name(), "");
+        assert_eq!(parsed.product_code(), "PLC-9");
+        assert_eq!(parsed.revision(), "1.2");
+        assert_eq!(parsed.object(5), "");
+    }
+
+    #[test]
+    fn conformity_tells_if_there_are_regular_objects() {
+        let request = ModbusDeviceIdentification::request(READ_DEVICE_ID_BASIC, 0);
+        let basic = ModbusDeviceIdentification::new("Acme", "PLC-9", "1.2");
+
+        assert_eq!(basic.response(&request).unwrap()[2], READ_DEVICE_ID_BASIC);
+        assert_eq!(
+            identification().response(&request).unwrap()[2],
+            READ_DEVICE_ID_REGULAR
+        );
+    }
+
+    #[test]
+    fn unknown_requests_are_not_answered() {
+        let identification = identification();
+
+        // Individual object access (code 4) isn't served.
+        assert!(identification.response(&[0x0E, 0x04, 0x00]).is_none());
+        assert!(identification.response(&[0x0D, 0x01, 0x00]).is_none());
+        assert!(identification.response(&[0x0E, 0x01]).is_none());
+    }
+
+    #[test]
+    fn more_follows_gives_the_next_object() {
+        let data = [
+            0x0E, 0x02, 0x02, 0xFF, 0x03, 0x01, 0x00, 0x04, b'A', b'c', b'm', b'e',
+        ];
+        let mut parsed = ModbusDeviceIdentification::default();
+
+        assert_eq!(parsed.parse_response(&data).unwrap(), Some(3));
+        assert_eq!(parsed.vendor_name(), "Acme");
+    }
+
+    #[test]
+    fn malformed_responses_are_rejected() {
+        let mut response = identification()
+            .response(&ModbusDeviceIdentification::request(
+                READ_DEVICE_ID_BASIC,
+                0,
+            ))
+            .unwrap();
+        let error = |data: &[u8]| {
+            let mut parsed = ModbusDeviceIdentification::default();
+            parsed.parse_response(data).unwrap_err().to_string()
+        };
+
+        assert_eq!(
+            error(&response[..5]),
+            "Device identification response is too short"
+        );
+        // Cut in the middle of the revision value.
+        assert_eq!(
+            error(&response[..response.len() - 1]),
+            "Device identification object 2 is truncated"
+        );
+        // Cut after the revision ID, before its length.
+        let revision = response.len() - 4;
+        assert_eq!(
+            error(&response[..revision]),
+            "Device identification object is truncated"
+        );
+
+        response[0] = 0x0D;
+        assert_eq!(error(&response), "Unexpected MEI type 0x0D");
+    }
+}
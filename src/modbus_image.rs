@@ -0,0 +1,323 @@
+use crate::modbus_device::*;
+use std::collections::{HashMap, HashSet};
+use tokio_modbus::prelude::*;
+
+// The four Modbus data tables served to clients,
+// only mapped addresses exist.
+#[derive(Default)]
+pub struct ModbusImage {
+    coils: HashMap<u16, bool>,
+    discrete_inputs: HashMap<u16, bool>,
+    input_registers: HashMap<u16, u16>,
+    holding_registers: HashMap<u16, u16>,
+    // Mapped addresses without a trustworthy value.
+    unavailable: HashSet<(ModbusRegisterSpace, u16)>,
+}
+
+impl ModbusImage {
+    // Store the words of a value, or its first word as a bit.
+    pub fn store(&mut self, register_space: ModbusRegisterSpace, address: u16, words: &[u16]) {
+        let Some(first) = words.first() else {
+            return;
+        };
+
+        match register_space {
+            ModbusRegisterSpace::Coil => {
+                self.coils.insert(address, *first != 0);
+            }
+            ModbusRegisterSpace::DiscreteInput => {
+                self.discrete_inputs.insert(address, *first != 0);
+            }
+            ModbusRegisterSpace::InputRegister => {
+                for (offset, word) in words.iter().enumerate() {
+                    self.input_registers
+                        .insert(address.wrapping_add(offset as u16), *word);
+                }
+            }
+            ModbusRegisterSpace::HoldingRegister => {
+                for (offset, word) in words.iter().enumerate() {
+                    self.holding_registers
+                        .insert(address.wrapping_add(offset as u16), *word);
+                }
+            }
+        }
+    }
+
+    // Reads of unavailable addresses are answered with
+    // a gateway target exception.
+    pub fn set_available(
+        &mut self,
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        count: u16,
+        available: bool,
+    ) {
+        for offset in 0..count {
+            let key = (register_space, address.wrapping_add(offset));
+            if available {
+                self.unavailable.remove(&key);
+            } else {
+                self.unavailable.insert(key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // Answer a read or write request from the image.
+    pub fn handle(&mut self, request: Request<'static>) -> Result<Response, ExceptionCode> {
+        match request {
+            Request::ReadCoils(address, count) => {
+                self.check_available(ModbusRegisterSpace::Coil, address, count)?;
+                read_range(&self.coils, address, count).map(Response::ReadCoils)
+            }
+            Request::ReadDiscreteInputs(address, count) => {
+                self.check_available(ModbusRegisterSpace::DiscreteInput, address, count)?;
+                read_range(&self.discrete_inputs, address, count).map(Response::ReadDiscreteInputs)
+            }
+            Request::ReadInputRegisters(address, count) => {
+                self.check_available(ModbusRegisterSpace::InputRegister, address, count)?;
+                read_range(&self.input_registers, address, count).map(Response::ReadInputRegisters)
+            }
+            Request::ReadHoldingRegisters(address, count) => {
+                self.check_available(ModbusRegisterSpace::HoldingRegister, address, count)?;
+                read_range(&self.holding_registers, address, count)
+                    .map(Response::ReadHoldingRegisters)
+            }
+            Request::WriteSingleCoil(address, coil) => {
+                write_range(&mut self.coils, address, &[coil])?;
+                Ok(Response::WriteSingleCoil(address, coil))
+            }
+            Request::WriteMultipleCoils(address, coils) => {
+                write_range(&mut self.coils, address, &coils)?;
+                Ok(Response::WriteMultipleCoils(address, coils.len() as u16))
+            }
+            Request::WriteSingleRegister(address, word) => {
+                write_range(&mut self.holding_registers, address, &[word])?;
+                Ok(Response::WriteSingleRegister(address, word))
+            }
+            Request::WriteMultipleRegisters(address, words) => {
+                write_range(&mut self.holding_registers, address, &words)?;
+                Ok(Response::WriteMultipleRegisters(
+                    address,
+                    words.len() as u16,
+                ))
+            }
+            Request::MaskWriteRegister(address, and_mask, or_mask) => {
+                let word = read_range(&self.holding_registers, address, 1)?[0];
+                let word = (word & and_mask) | (or_mask & !and_mask);
+                write_range(&mut self.holding_registers, address, &[word])?;
+                Ok(Response::MaskWriteRegister(address, and_mask, or_mask))
+            }
+            // The write happens before the read.
+            Request::ReadWriteMultipleRegisters(read_address, count, write_address, words) => {
+                self.check_available(ModbusRegisterSpace::HoldingRegister, read_address, count)?;
+                read_range(&self.holding_registers, read_address, count)?;
+                write_range(&mut self.holding_registers, write_address, &words)?;
+                read_range(&self.holding_registers, read_address, count)
+                    .map(Response::ReadWriteMultipleRegisters)
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+
+    fn check_available(
+        &self,
+        register_space: ModbusRegisterSpace,
+        address: u16,
+        count: u16,
+    ) -> Result<(), ExceptionCode> {
+        let unavailable = (0..count).any(|offset| {
+            self.unavailable
+                .contains(&(register_space, address.wrapping_add(offset)))
+        });
+        if unavailable {
+            return Err(ExceptionCode::GatewayTargetDevice);
+        }
+
+        Ok(())
+    }
+}
+
+// Every address of the range must be mapped.
+fn read_range<T: Copy>(
+    table: &HashMap<u16, T>,
+    address: u16,
+    count: u16,
+) -> Result<Vec<T>, ExceptionCode> {
+    (0..count)
+        .map(|offset| {
+            table
+                .get(&address.wrapping_add(offset))
+                .copied()
+                .ok_or(ExceptionCode::IllegalDataAddress)
+        })
+        .collect()
+}
+
+fn write_range<T: Copy>(
+    table: &mut HashMap<u16, T>,
+    address: u16,
+    values: &[T],
+) -> Result<(), ExceptionCode> {
+    let mapped =
+        (0..values.len() as u16).all(|offset| table.contains_key(&address.wrapping_add(offset)));
+    if !mapped {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+
+    for (offset, value) in values.iter().enumerate() {
+        table.insert(address.wrapping_add(offset as u16), *value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    // HR 10-12 and coils 0-1 mapped.
+    fn image() -> ModbusImage {
+        let mut image = ModbusImage::default();
+        image.store(ModbusRegisterSpace::HoldingRegister, 10, &[1, 2, 3]);
+        image.store(ModbusRegisterSpace::Coil, 0, &[1]);
+        image.store(ModbusRegisterSpace::Coil, 1, &[0]);
+        image
+    }
+
+    #[test]
+    fn reads_return_the_stored_values() {
+        let mut image = image();
+
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 3)),
+            Ok(Response::ReadHoldingRegisters(vec![1, 2, 3]))
+        );
+        assert_eq!(
+            image.handle(Request::ReadCoils(0, 2)),
+            Ok(Response::ReadCoils(vec![true, false]))
+        );
+    }
+
+    #[test]
+    fn unmapped_addresses_are_illegal() {
+        let mut image = image();
+
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(11, 3)),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            image.handle(Request::ReadInputRegisters(10, 1)),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Nothing is written when part of the range is unmapped.
+        assert_eq!(
+            image.handle(Request::WriteMultipleRegisters(12, Cow::Owned(vec![7, 8]))),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(12, 1)),
+            Ok(Response::ReadHoldingRegisters(vec![3]))
+        );
+    }
+
+    #[test]
+    fn writes_update_the_image() {
+        let mut image = image();
+
+        assert_eq!(
+            image.handle(Request::WriteSingleRegister(10, 100)),
+            Ok(Response::WriteSingleRegister(10, 100))
+        );
+        assert_eq!(
+            image.handle(Request::WriteMultipleRegisters(
+                11,
+                Cow::Owned(vec![200, 300])
+            )),
+            Ok(Response::WriteMultipleRegisters(11, 2))
+        );
+        assert_eq!(
+            image.handle(Request::WriteMultipleCoils(
+                0,
+                Cow::Owned(vec![false, true])
+            )),
+            Ok(Response::WriteMultipleCoils(0, 2))
+        );
+
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 3)),
+            Ok(Response::ReadHoldingRegisters(vec![100, 200, 300]))
+        );
+        assert_eq!(
+            image.handle(Request::ReadCoils(0, 2)),
+            Ok(Response::ReadCoils(vec![false, true]))
+        );
+    }
+
+    #[test]
+    fn mask_write_keeps_the_bits_outside_the_mask() {
+        let mut image = image();
+        image.store(ModbusRegisterSpace::HoldingRegister, 10, &[0x12]);
+
+        // The example of the Modbus specification.
+        image
+            .handle(Request::MaskWriteRegister(10, 0xF2, 0x25))
+            .unwrap();
+
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 1)),
+            Ok(Response::ReadHoldingRegisters(vec![0x17]))
+        );
+    }
+
+    #[test]
+    fn read_write_writes_before_reading() {
+        let mut image = image();
+
+        assert_eq!(
+            image.handle(Request::ReadWriteMultipleRegisters(
+                10,
+                3,
+                11,
+                Cow::Owned(vec![9])
+            )),
+            Ok(Response::ReadWriteMultipleRegisters(vec![1, 9, 3]))
+        );
+    }
+
+    #[test]
+    fn unavailable_addresses_answer_with_a_gateway_exception() {
+        let mut image = image();
+        image.set_available(ModbusRegisterSpace::HoldingRegister, 11, 1, false);
+
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 2)),
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 1)),
+            Ok(Response::ReadHoldingRegisters(vec![1]))
+        );
+
+        image.set_available(ModbusRegisterSpace::HoldingRegister, 11, 1, true);
+        assert_eq!(
+            image.handle(Request::ReadHoldingRegisters(10, 2)),
+            Ok(Response::ReadHoldingRegisters(vec![1, 2]))
+        );
+    }
+
+    #[test]
+    fn unsupported_functions_are_illegal() {
+        let mut image = image();
+
+        assert_eq!(
+            image.handle(Request::ReportServerId),
+            Err(ExceptionCode::IllegalFunction)
+        );
+    }
+}
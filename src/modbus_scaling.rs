@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+// Conversion from the raw decoded value to engineering units.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+pub enum ModbusScaling {
+    #[default]
+    None,
+    // Map raw_min..raw_max onto eu_min..eu_max.
+    Linear {
+        raw_min: f64,
+        raw_max: f64,
+        eu_min: f64,
+        eu_max: f64,
+    },
+    // raw * gain + offset
+    GainOffset {
+        gain: f64,
+        offset: f64,
+    },
+}
+
+impl ModbusScaling {
+    pub fn is_none(&self) -> bool {
+        matches!(self, ModbusScaling::None)
+    }
+
+    pub fn scale(&self, raw: f64) -> f64 {
+        match self {
+            ModbusScaling::None => raw,
+            ModbusScaling::Linear {
+                raw_min,
+                raw_max,
+                eu_min,
+                eu_max,
+            } => {
+                let raw_span = raw_max - raw_min;
+                if raw_span == 0.0 {
+                    return *eu_min;
+                }
+                eu_min + (raw - raw_min) * (eu_max - eu_min) / raw_span
+            }
+            ModbusScaling::GainOffset { gain, offset } => raw * gain + offset,
+        }
+    }
+
+    // The raw value to write for an engineering value.
+    pub fn unscale(&self, eu: f64) -> f64 {
+        match self {
+            ModbusScaling::None => eu,
+            ModbusScaling::Linear {
+                raw_min,
+                raw_max,
+                eu_min,
+                eu_max,
+            } => {
+                let eu_span = eu_max - eu_min;
+                if eu_span == 0.0 {
+                    return *raw_min;
+                }
+                raw_min + (eu - eu_min) * (raw_max - raw_min) / eu_span
+            }
+            ModbusScaling::GainOffset { gain, offset } => {
+                if *gain == 0.0 {
+                    return 0.0;
+                }
+                (eu - offset) / gain
+            }
+        }
+    }
+}
+
+impl Display for ModbusScaling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusScaling::None => write!(f, "None"),
+            ModbusScaling::Linear {
+                raw_min,
+                raw_max,
+                eu_min,
+                eu_max,
+            } => write!(f, "{raw_min}..{raw_max} -> {eu_min}..{eu_max}"),
+            ModbusScaling::GainOffset { gain, offset } => write!(f, "x{gain} + {offset}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALINGS: [ModbusScaling; 4] = [
+        ModbusScaling::None,
+        // 4-20 mA input on a 0-27648 analog card.
+        ModbusScaling::Linear {
+            raw_min: 0.0,
+            raw_max: 27648.0,
+            eu_min: 4.0,
+            eu_max: 20.0,
+        },
+        // Inverted range.
+        ModbusScaling::Linear {
+            raw_min: 0.0,
+            raw_max: 1000.0,
+            eu_min: 100.0,
+            eu_max: -100.0,
+        },
+        ModbusScaling::GainOffset {
+            gain: 0.1,
+            offset: -40.0,
+        },
+    ];
+
+    #[test]
+    fn unscale_inverts_scale() {
+        for scaling in &SCALINGS {
+            for raw in [0.0, 1.0, 500.0, 13824.0, 27648.0, -250.0] {
+                let eu = scaling.scale(raw);
+                assert!(
+                    (scaling.unscale(eu) - raw).abs() < 1e-9,
+                    "{scaling}: {raw} -> {eu}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scale_maps_the_range_ends() {
+        assert_eq!(SCALINGS[1].scale(0.0), 4.0);
+        assert_eq!(SCALINGS[1].scale(27648.0), 20.0);
+        assert_eq!(SCALINGS[1].scale(13824.0), 12.0);
+        assert_eq!(SCALINGS[2].scale(1000.0), -100.0);
+        assert_eq!(SCALINGS[3].scale(650.0), 25.0);
+    }
+
+    #[test]
+    fn empty_ranges_dont_divide_by_zero() {
+        let flat = ModbusScaling::Linear {
+            raw_min: 10.0,
+            raw_max: 10.0,
+            eu_min: 1.0,
+            eu_max: 1.0,
+        };
+        let zero_gain = ModbusScaling::GainOffset {
+            gain: 0.0,
+            offset: 5.0,
+        };
+
+        assert_eq!(flat.scale(20.0), 1.0);
+        assert_eq!(flat.unscale(2.0), 10.0);
+        assert_eq!(zero_gain.unscale(7.0), 0.0);
+    }
+}
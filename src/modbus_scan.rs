@@ -0,0 +1,276 @@
+use crate::modbus_device::*;
+use std::time::Duration;
+use tokio::time::Instant;
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_stale_after_scans() -> u32 {
+    3
+}
+
+// A named poll interval channels can be assigned to,
+// e.g. 100 ms for flows or 60 s for nameplate data.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ModbusScanClass {
+    pub name: String,
+    pub interval_ms: u64,
+}
+
+// Device poll interval and its scan classes.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ModbusScanConfig {
+    // Interval of the channels without a scan class.
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default)]
+    pub scan_classes: Vec<ModbusScanClass>,
+    // Missed scans before a channel is marked stale.
+    #[serde(default = "default_stale_after_scans")]
+    pub stale_after_scans: u32,
+}
+
+impl Default for ModbusScanConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: default_poll_interval_ms(),
+            scan_classes: Vec::new(),
+            stale_after_scans: default_stale_after_scans(),
+        }
+    }
+}
+
+impl ModbusScanConfig {
+    // Channels with an unknown scan class use the device interval.
+    pub fn interval(&self, channel: &ModbusChannel) -> Duration {
+        let interval_ms = channel
+            .scan_class
+            .as_ref()
+            .and_then(|name| {
+                self.scan_classes
+                    .iter()
+                    .find(|scan_class| &scan_class.name == name)
+            })
+            .map(|scan_class| scan_class.interval_ms)
+            .unwrap_or(self.poll_interval_ms);
+
+        // A zero interval would spin the poller.
+        Duration::from_millis(interval_ms.max(1))
+    }
+}
+
+// Channels polled at the same interval.
+struct ScanGroup {
+    interval: Duration,
+    channels: Vec<usize>,
+    next_due: Instant,
+}
+
+// Timers of every scan group of a device. Each group keeps
+// its own deadline so slow groups don't delay the fast ones.
+pub struct ScanSchedule {
+    groups: Vec<ScanGroup>,
+}
+
+impl ScanSchedule {
+    // All the groups are due right away.
+    pub fn new(device: &ModbusDevice) -> Self {
+        let mut schedule = Self { groups: Vec::new() };
+        schedule.update(device);
+
+        schedule
+    }
+
+    // Regroup the channels after a scan config or channel change.
+    // Intervals that were already scheduled keep their deadline,
+    // new ones are due right away.
+    pub fn update(&mut self, device: &ModbusDevice) {
+        let now = Instant::now();
+        let mut groups: Vec<ScanGroup> = Vec::new();
+
+        for (index, channel) in device.channels.iter().enumerate() {
+            if !channel.enabled {
+                continue;
+            }
+
+            let interval = device.scan_config.interval(channel);
+            match groups.iter_mut().find(|group| group.interval == interval) {
+                Some(group) => group.channels.push(index),
+                None => {
+                    let next_due = self
+                        .groups
+                        .iter()
+                        .find(|group| group.interval == interval)
+                        .map_or(now, |group| group.next_due);
+                    groups.push(ScanGroup {
+                        interval,
+                        channels: vec![index],
+                        next_due,
+                    });
+                }
+            }
+        }
+
+        self.groups = groups;
+    }
+
+    // Poll the group of a channel right away, e.g. after a write.
+    pub fn poll_now(&mut self, channel: usize) {
+        let now = Instant::now();
+        if let Some(group) = self
+            .groups
+            .iter_mut()
+            .find(|group| group.channels.contains(&channel))
+        {
+            group.next_due = group.next_due.min(now);
+        }
+    }
+
+    // The earliest deadline, None if there is nothing to poll.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.groups.iter().map(|group| group.next_due).min()
+    }
+
+    // Channels of the groups due at `now`, fastest group first.
+    // Their next scan is scheduled one interval later, or one
+    // interval from now if they fell behind.
+    pub fn take_due(&mut self, now: Instant) -> Vec<usize> {
+        self.groups.sort_by_key(|group| group.interval);
+
+        let mut channels = Vec::new();
+        for group in self.groups.iter_mut() {
+            if group.next_due > now {
+                continue;
+            }
+
+            channels.extend_from_slice(&group.channels);
+            group.next_due += group.interval;
+            if group.next_due <= now {
+                group.next_due = now + group.interval;
+            }
+        }
+
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Channels 0-1 on the device interval, 2 fast, 3 slow, 4 disabled.
+    fn device() -> ModbusDevice {
+        let mut device = init_mb_tcp_device("127.0.0.1".to_owned(), 502, "PLC".to_owned(), 5);
+        device.scan_config = ModbusScanConfig {
+            poll_interval_ms: 1000,
+            scan_classes: vec![
+                ModbusScanClass {
+                    name: "Fast".to_owned(),
+                    interval_ms: 100,
+                },
+                ModbusScanClass {
+                    name: "Slow".to_owned(),
+                    interval_ms: 60_000,
+                },
+            ],
+            ..Default::default()
+        };
+        device.channels[2].scan_class = Some("Fast".to_owned());
+        device.channels[3].scan_class = Some("Slow".to_owned());
+        device.channels[4].enabled = false;
+
+        device
+    }
+
+    #[test]
+    fn every_group_is_due_at_start() {
+        let mut schedule = ScanSchedule::new(&device());
+        let now = Instant::now();
+
+        assert!(schedule.next_due().unwrap() <= now);
+        assert_eq!(schedule.take_due(now), vec![2, 0, 1, 3]);
+        // The fast group is next, one interval after the schedule was made.
+        let next_due = schedule.next_due().unwrap();
+        assert!(next_due > now && next_due <= now + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn groups_come_due_at_their_own_interval() {
+        let mut schedule = ScanSchedule::new(&device());
+        let start = Instant::now();
+        schedule.take_due(start);
+
+        assert!(schedule
+            .take_due(start + Duration::from_millis(99))
+            .is_empty());
+        assert_eq!(
+            schedule.take_due(start + Duration::from_millis(100)),
+            vec![2]
+        );
+        assert_eq!(
+            schedule.take_due(start + Duration::from_millis(1000)),
+            vec![2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn late_groups_skip_the_missed_scans() {
+        let mut schedule = ScanSchedule::new(&device());
+        let start = Instant::now();
+        schedule.take_due(start);
+
+        let late = start + Duration::from_millis(2500);
+        assert_eq!(schedule.take_due(late), vec![2, 0, 1]);
+        assert_eq!(schedule.next_due(), Some(late + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn unknown_scan_classes_use_the_device_interval() {
+        let mut device = device();
+        device.channels[2].scan_class = Some("Missing".to_owned());
+
+        let mut schedule = ScanSchedule::new(&device);
+
+        assert_eq!(schedule.take_due(Instant::now()), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn nothing_to_poll_without_enabled_channels() {
+        let mut device = device();
+        device
+            .channels
+            .iter_mut()
+            .for_each(|channel| channel.enabled = false);
+
+        assert_eq!(ScanSchedule::new(&device).next_due(), None);
+    }
+
+    #[test]
+    fn update_keeps_the_deadlines_of_known_intervals() {
+        let mut device = device();
+        let mut schedule = ScanSchedule::new(&device);
+        let start = Instant::now();
+        schedule.take_due(start);
+
+        device.channels[0].scan_class = Some("Fast".to_owned());
+        schedule.update(&device);
+
+        assert!(schedule.take_due(start).is_empty());
+        assert_eq!(
+            schedule.take_due(start + Duration::from_millis(100)),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn poll_now_only_moves_the_group_of_the_channel() {
+        let mut schedule = ScanSchedule::new(&device());
+        let start = Instant::now();
+        schedule.take_due(start);
+
+        schedule.poll_now(3);
+
+        assert_eq!(schedule.take_due(Instant::now()), vec![3]);
+    }
+}
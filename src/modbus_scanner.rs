@@ -0,0 +1,407 @@
+use crate::modbus_decode::*;
+use crate::modbus_device::*;
+use anyhow::Result;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_modbus::prelude::*;
+
+// What a probed address answered.
+#[derive(Clone, Debug)]
+pub enum ScanOutcome {
+    Bit(bool),
+    Word(u16),
+    // Exception code, see `exception_description`.
+    Exception(u8),
+}
+
+impl Display for ScanOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanOutcome::Bit(bit) => write!(f, "{bit}"),
+            ScanOutcome::Word(word) => write!(f, "{word:#06X}"),
+            ScanOutcome::Exception(code) => write!(f, "EXCEPTION {code:#04X}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScanResult {
+    pub unit_id: u8,
+    pub register_space: ModbusRegisterSpace,
+    pub address: u16,
+    pub outcome: ScanOutcome,
+}
+
+// Sent from the scan thread to the GUI.
+pub enum ScanEvent {
+    Result(ScanResult),
+    Progress { done: usize, total: usize },
+    // The unit didn't answer, its remaining ranges are skipped.
+    UnitSilent(u8),
+    // The error that ended the scan, if any.
+    Finished(Option<String>),
+}
+
+// What to probe and where.
+#[derive(Clone)]
+pub struct ScannerConfig {
+    pub config: ModbusDeviceConfig,
+    pub first_unit_id: u8,
+    pub last_unit_id: u8,
+    pub register_spaces: Vec<ModbusRegisterSpace>,
+    // Inclusive address range.
+    pub start_address: u16,
+    pub end_address: u16,
+    // Addresses read per request, ranges with exceptions
+    // are probed again one address at a time.
+    pub chunk_size: u16,
+    pub timeout_ms: u64,
+}
+
+// Read with the timeout, None if the unit didn't answer.
+async fn probe(
+    ctx: &mut tokio_modbus::client::Context,
+    space: ModbusRegisterSpace,
+    address: u16,
+    count: u16,
+    timeout: Duration,
+) -> Result<Option<std::result::Result<ModbusBlockData, ExceptionCode>>> {
+    match tokio::time::timeout(timeout, read_block(ctx, space, address, count)).await {
+        Ok(result) => Ok(Some(result?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn results(
+    unit_id: u8,
+    space: ModbusRegisterSpace,
+    address: u16,
+    data: &ModbusBlockData,
+) -> Vec<ScanResult> {
+    let outcomes: Vec<ScanOutcome> = match data {
+        ModbusBlockData::Bits(bits) => bits.iter().map(|bit| ScanOutcome::Bit(*bit)).collect(),
+        ModbusBlockData::Words(words) => {
+            words.iter().map(|word| ScanOutcome::Word(*word)).collect()
+        }
+    };
+
+    outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(offset, outcome)| ScanResult {
+            unit_id,
+            register_space: space,
+            address: address.wrapping_add(offset as u16),
+            outcome,
+        })
+        .collect()
+}
+
+// Probe every unit, register space and address range of the config.
+pub async fn run_scan(
+    scan: ScannerConfig,
+    sender: &Sender<ScanEvent>,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let timeout = Duration::from_millis(scan.timeout_ms.max(1));
+    let chunk_size = scan.chunk_size.clamp(1, 125) as u32;
+    let start = scan.start_address as u32;
+    let end = (scan.end_address as u32).max(start);
+
+    let chunks_per_space = (end - start + 1).div_ceil(chunk_size) as usize;
+    let units = (scan.first_unit_id..=scan.last_unit_id).count();
+    let total = units * scan.register_spaces.len() * chunks_per_space;
+    let mut done = 0;
+
+    let mut ctx = scan.config.connect(timeout, None).await?;
+
+    for unit_id in scan.first_unit_id..=scan.last_unit_id {
+        ctx.set_slave(Slave(unit_id));
+        let unit_done = done + scan.register_spaces.len() * chunks_per_space;
+
+        'unit: for space in &scan.register_spaces {
+            let mut address = start;
+            while address <= end {
+                if cancel.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+
+                let count = chunk_size.min(end - address + 1) as u16;
+                let chunk_address = address as u16;
+                let events = match probe(&mut ctx, *space, chunk_address, count, timeout).await? {
+                    None => {
+                        // A late answer would mix with the next request.
+                        ctx = scan.config.connect(timeout, None).await?;
+                        sender.send(ScanEvent::UnitSilent(unit_id)).ok();
+                        break 'unit;
+                    }
+                    Some(Ok(data)) => results(unit_id, *space, chunk_address, &data),
+                    Some(Err(exception)) if count == 1 => vec![ScanResult {
+                        unit_id,
+                        register_space: *space,
+                        address: chunk_address,
+                        outcome: ScanOutcome::Exception(exception.into()),
+                    }],
+                    // Find the addresses that do answer.
+                    Some(Err(_)) => {
+                        let mut events = Vec::new();
+                        for offset in 0..count {
+                            let single = chunk_address.wrapping_add(offset);
+                            match probe(&mut ctx, *space, single, 1, timeout).await? {
+                                Some(Ok(data)) => {
+                                    events.extend(results(unit_id, *space, single, &data))
+                                }
+                                Some(Err(exception)) => events.push(ScanResult {
+                                    unit_id,
+                                    register_space: *space,
+                                    address: single,
+                                    outcome: ScanOutcome::Exception(exception.into()),
+                                }),
+                                None => ctx = scan.config.connect(timeout, None).await?,
+                            }
+                        }
+                        events
+                    }
+                };
+
+                for event in events {
+                    sender.send(ScanEvent::Result(event)).ok();
+                }
+                done += 1;
+                sender.send(ScanEvent::Progress { done, total }).ok();
+                address += count as u32;
+            }
+        }
+
+        done = unit_done;
+        sender.send(ScanEvent::Progress { done, total }).ok();
+    }
+
+    Ok(())
+}
+
+// A scan running on its own thread, cancelled when dropped.
+pub struct ScannerHandle {
+    cancel: Arc<AtomicBool>,
+    pub receiver: Receiver<ScanEvent>,
+}
+
+impl ScannerHandle {
+    pub fn spawn(scan: ScannerConfig) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let thread_cancel = cancel.clone();
+        std::thread::spawn(move || {
+            let result = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(anyhow::Error::from)
+                .and_then(|runtime| runtime.block_on(run_scan(scan, &sender, &thread_cancel)));
+            sender
+                .send(ScanEvent::Finished(result.err().map(|e| format!("{e}"))))
+                .ok();
+        });
+
+        Self { cancel, receiver }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ScannerHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// Ways to read the words at an address: U16 and I16 from the
+// first word, F32 in every byte order if there's a second one.
+// NaN and infinite floats are left out, they're rarely meant.
+pub fn candidate_decodings(words: &[u16]) -> Vec<(ModbusChannelType, ModbusByteOrder, String)> {
+    let mut candidates = Vec::new();
+
+    if let Some(word) = words.first() {
+        candidates.push((
+            ModbusChannelType::U16,
+            ModbusByteOrder::Abcd,
+            format!("{word}"),
+        ));
+        candidates.push((
+            ModbusChannelType::I16,
+            ModbusByteOrder::Abcd,
+            format!("{}", *word as i16),
+        ));
+    }
+    if let [first, second, ..] = words {
+        for byte_order in [
+            ModbusByteOrder::Abcd,
+            ModbusByteOrder::Cdab,
+            ModbusByteOrder::Badc,
+            ModbusByteOrder::Dcba,
+        ] {
+            // Shown as f32 so the digits match what the device holds.
+            let value = decode_f32([*first, *second], byte_order);
+            if !value.is_finite() {
+                continue;
+            }
+            candidates.push((ModbusChannelType::F32, byte_order, format!("{value}")));
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_simulator::*;
+
+    #[test]
+    fn floats_are_decoded_in_every_byte_order() {
+        for byte_order in [
+            ModbusByteOrder::Abcd,
+            ModbusByteOrder::Cdab,
+            ModbusByteOrder::Badc,
+            ModbusByteOrder::Dcba,
+        ] {
+            let words = ModbusChannelType::F32
+                .encode(&ModbusValue::Real(-12.25), byte_order)
+                .unwrap();
+
+            let candidates = candidate_decodings(&words);
+
+            assert_eq!(candidates.len(), 6);
+            assert!(
+                candidates.contains(&(ModbusChannelType::F32, byte_order, "-12.25".to_owned())),
+                "{byte_order}: {candidates:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn single_words_are_only_integers() {
+        assert_eq!(
+            candidate_decodings(&[0xFFFE]),
+            vec![
+                (
+                    ModbusChannelType::U16,
+                    ModbusByteOrder::Abcd,
+                    "65534".to_owned()
+                ),
+                (
+                    ModbusChannelType::I16,
+                    ModbusByteOrder::Abcd,
+                    "-2".to_owned()
+                ),
+            ]
+        );
+        assert!(candidate_decodings(&[]).is_empty());
+    }
+
+    #[test]
+    fn nan_and_infinite_floats_are_dropped() {
+        // NaN or infinite read as ABCD, finite in the other orders.
+        for words in [[0x7FC0, 0x0000], [0x7F80, 0x0000], [0xFF80, 0x0000]] {
+            let orders: Vec<ModbusByteOrder> = candidate_decodings(&words)
+                .into_iter()
+                .filter(|(channel_type, ..)| *channel_type == ModbusChannelType::F32)
+                .map(|(_, byte_order, _)| byte_order)
+                .collect();
+            assert_eq!(
+                orders,
+                vec![
+                    ModbusByteOrder::Cdab,
+                    ModbusByteOrder::Badc,
+                    ModbusByteOrder::Dcba
+                ]
+            );
+        }
+
+        // NaN in every order.
+        assert_eq!(candidate_decodings(&[0xFFFF, 0xFFFF]).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn scans_find_the_served_addresses() {
+        // A free port for the simulator.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let registers = (10..13)
+            .map(|address| SimulatorRegister {
+                name: format!("HR{address}"),
+                register_space: ModbusRegisterSpace::HoldingRegister,
+                address,
+                channel_type: ModbusChannelType::U16,
+                byte_order: ModbusByteOrder::Abcd,
+                behaviour: SimulatorBehaviour::Constant(address as f64 * 100.0),
+            })
+            .collect();
+        let config = SimulatorConfig {
+            port,
+            update_interval_ms: 1000,
+            registers,
+            ..Default::default()
+        };
+        let scan = ScannerConfig {
+            config: ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+                ip: "127.0.0.1".to_owned(),
+                port: port as usize,
+                unit_id: 1,
+            }),
+            first_unit_id: 1,
+            last_unit_id: 1,
+            register_spaces: vec![ModbusRegisterSpace::HoldingRegister],
+            start_address: 8,
+            end_address: 14,
+            chunk_size: 4,
+            timeout_ms: 1000,
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+        // The simulator isn't Send, both run on the test task.
+        let scanner = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let result = run_scan(scan, &sender, &AtomicBool::new(false)).await;
+            stop.send(()).ok();
+            result
+        };
+        let stopped = async move {
+            stopped.await.ok();
+        };
+        let (simulator, scanner) = tokio::join!(run_simulator(config, stopped, |_| {}), scanner);
+        simulator.unwrap();
+        scanner.unwrap();
+
+        let mut progress = Vec::new();
+        let mut outcomes = Vec::new();
+        for event in receiver.try_iter() {
+            match event {
+                ScanEvent::Result(result) => {
+                    outcomes.push((result.address, format!("{}", result.outcome)))
+                }
+                ScanEvent::Progress { done, total } => progress.push((done, total)),
+                _ => {}
+            }
+        }
+
+        // Both chunks failed and were probed one address at a time.
+        let expected: Vec<(u16, String)> = (8..=14)
+            .map(|address| match address {
+                10..13 => (address, format!("{:#06X}", address * 100)),
+                _ => (address, "EXCEPTION 0x02".to_owned()),
+            })
+            .collect();
+        assert_eq!(outcomes, expected);
+        assert_eq!(progress.last(), Some(&(2, 2)));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::modbus_device::*;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+// Modbus functions of the calculation scripts:
+//   mask_write(device, address, and_mask, or_mask)
+//   read_write(device, read_address, read_count, write_address, values) -> array
+//   identification(device) -> map of vendor_name, product_code, revision...
+// Devices are named as in the device list, e.g. "Device_1". mask_write and
+// read_write are sent to the task polling the device, so they show in its
+// statistics and traffic. Test Evaluate waits for the answer. The periodic
+// evaluation doesn't: it queues the request and returns the last answer,
+// read_write fails until the first one comes. identification uses the
+// last polled state.
+#[derive(Clone)]
+pub struct ScriptModbus {
+    sender: Sender<ScriptCall>,
+    devices: Arc<RwLock<Vec<ModbusDevice>>>,
+    // Last answers and requests in flight of the periodic evaluation,
+    // None when the scripts wait for the answers.
+    answers: Option<Arc<Mutex<ScriptAnswers>>>,
+}
+
+// A script request for the device task, answered on the reply channel.
+pub struct ScriptCall {
+    pub device_id: usize,
+    pub call: ModbusCall,
+    pub reply: oneshot::Sender<Result<ModbusCallResponse, String>>,
+}
+
+// Keyed by device and request, so each call of a script has its own answer.
+#[derive(Default)]
+struct ScriptAnswers {
+    last: HashMap<String, Result<ModbusCallResponse, String>>,
+    pending: HashMap<String, oneshot::Receiver<Result<ModbusCallResponse, String>>>,
+}
+
+impl ScriptModbus {
+    // For one-shot runs, the scripts wait for the answers. They must
+    // run outside of the runtime, e.g. on a blocking task.
+    pub fn new(sender: Sender<ScriptCall>) -> Self {
+        Self {
+            sender,
+            devices: Arc::default(),
+            answers: None,
+        }
+    }
+
+    // For the periodic evaluation, the scripts never wait.
+    pub fn periodic(sender: Sender<ScriptCall>) -> Self {
+        Self {
+            answers: Some(Arc::default()),
+            ..Self::new(sender)
+        }
+    }
+
+    // The latest device states, for the names and the identification.
+    pub fn set_devices(&self, devices: Vec<ModbusDevice>) {
+        *self.devices.write().unwrap() = devices;
+    }
+
+    pub fn register(&self, engine: &mut Engine) {
+        let modbus = self.clone();
+        engine.register_fn(
+            "mask_write",
+            move |device: &str, address: i64, and_mask: i64, or_mask: i64| {
+                let call = ModbusCall::MaskWriteRegister(
+                    to_u16(address)?,
+                    to_u16(and_mask)?,
+                    to_u16(or_mask)?,
+                );
+                // Nothing to check, the periodic evaluation goes on without the answer.
+                modbus.call(device, call).map(|_| ())
+            },
+        );
+
+        let modbus = self.clone();
+        engine.register_fn(
+            "read_write",
+            move |device: &str,
+                  read_address: i64,
+                  read_count: i64,
+                  write_address: i64,
+                  values: Array|
+                  -> Result<Array, Box<EvalAltResult>> {
+                let words = values
+                    .into_iter()
+                    .map(|value| to_u16(value.as_int()?))
+                    .collect::<Result<Vec<u16>, _>>()?;
+                let call = ModbusCall::ReadWriteRegisters(
+                    to_u16(read_address)?,
+                    to_u16(read_count)?,
+                    to_u16(write_address)?,
+                    words,
+                );
+
+                match modbus.call(device, call)? {
+                    Some(ModbusCallResponse::Words(words)) => Ok(words
+                        .into_iter()
+                        .map(|word| Dynamic::from_int(word as i64))
+                        .collect()),
+                    Some(_) => Err("Unexpected read/write response".into()),
+                    None => {
+                        Err(format!("{device}: Waiting for the first read/write answer").into())
+                    }
+                }
+            },
+        );
+
+        let modbus = self.clone();
+        engine.register_fn(
+            "identification",
+            move |device: &str| -> Result<Map, Box<EvalAltResult>> {
+                let device = modbus.device(device)?;
+                let identification = match &device.identification {
+                    Some(Ok(identification)) => identification,
+                    Some(Err(e)) => return Err(format!("{}: {e}", device.name).into()),
+                    None => return Err(format!("{} wasn't identified yet", device.name).into()),
+                };
+
+                let names = [
+                    "vendor_name",
+                    "product_code",
+                    "revision",
+                    "vendor_url",
+                    "product_name",
+                    "model_name",
+                    "user_application_name",
+                ];
+                let mut map = Map::new();
+                for (id, value) in &identification.objects {
+                    let name = match names.get(*id as usize) {
+                        Some(name) => name.to_string(),
+                        None => format!("object_{id}"),
+                    };
+                    map.insert(name.into(), value.clone().into());
+                }
+
+                Ok(map)
+            },
+        );
+    }
+
+    fn device(&self, name: &str) -> Result<ModbusDevice, Box<EvalAltResult>> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .find(|device| device.name == name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown device {name}").into())
+    }
+
+    // Send the call to the device task. Test Evaluate waits for the answer,
+    // the periodic evaluation gets the last one, if any.
+    fn call(
+        &self,
+        device: &str,
+        call: ModbusCall,
+    ) -> Result<Option<ModbusCallResponse>, Box<EvalAltResult>> {
+        let device_id = self.device(device)?.id;
+        let Some(answers) = &self.answers else {
+            let (reply, answer) = oneshot::channel();
+            let call = ScriptCall {
+                device_id,
+                call,
+                reply,
+            };
+            self.sender
+                .blocking_send(call)
+                .map_err(|_| "Modbus requests can't be sent from this script")?;
+            return match answer.blocking_recv() {
+                Ok(answer) => answer.map(Some).map_err(|e| e.into()),
+                Err(_) => Err(format!("{device}: The request was dropped").into()),
+            };
+        };
+
+        let key = format!("{device_id} {call:?}");
+        let mut answers = answers.lock().unwrap();
+        if let Some(pending) = answers.pending.get_mut(&key) {
+            let answer = match pending.try_recv() {
+                Ok(answer) => Some(answer),
+                Err(oneshot::error::TryRecvError::Empty) => None,
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    Some(Err(format!("{device}: The request was dropped")))
+                }
+            };
+            if let Some(answer) = answer {
+                answers.pending.remove(&key);
+                answers.last.insert(key.clone(), answer);
+            }
+        }
+
+        // One request in flight per call. A full queue skips this round.
+        if !answers.pending.contains_key(&key) {
+            let (reply, answer) = oneshot::channel();
+            let call = ScriptCall {
+                device_id,
+                call,
+                reply,
+            };
+            if self.sender.try_send(call).is_ok() {
+                answers.pending.insert(key.clone(), answer);
+            }
+        }
+
+        match answers.last.get(&key) {
+            Some(Ok(response)) => Ok(Some(response.clone())),
+            Some(Err(e)) => Err(e.clone().into()),
+            None => Ok(None),
+        }
+    }
+}
+
+fn to_u16(value: i64) -> Result<u16, Box<EvalAltResult>> {
+    u16::try_from(value).map_err(|_| format!("{value} is not a 16 bit value").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn script_modbus(sender: Sender<ScriptCall>, periodic: bool) -> ScriptModbus {
+        let modbus = match periodic {
+            true => ScriptModbus::periodic(sender),
+            false => ScriptModbus::new(sender),
+        };
+        let mut device = init_mb_tcp_device("127.0.0.1".to_owned(), 502, "Device_1".to_owned(), 1);
+        device.id = 4;
+        modbus.set_devices(vec![device]);
+        modbus
+    }
+
+    fn read_write() -> ModbusCall {
+        ModbusCall::ReadWriteRegisters(0, 1, 0, vec![5])
+    }
+
+    #[test]
+    fn periodic_calls_return_the_last_answer() {
+        let (sender, mut receiver) = mpsc::channel(4);
+        let modbus = script_modbus(sender, true);
+        let mut engine = Engine::new();
+        modbus.register(&mut engine);
+        let script = r#"read_write("Device_1", 0, 1, 0, [5])"#;
+
+        let error = engine.eval::<Array>(script).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Waiting for the first read/write answer"));
+        let request = receiver.try_recv().unwrap();
+        assert_eq!(request.device_id, 4);
+
+        // One request in flight at a time.
+        assert!(modbus.call("Device_1", read_write()).unwrap().is_none());
+        assert!(receiver.try_recv().is_err());
+
+        request
+            .reply
+            .send(Ok(ModbusCallResponse::Words(vec![5])))
+            .unwrap();
+        let words = engine.eval::<Array>(script).unwrap();
+        assert_eq!(words[0].as_int().unwrap(), 5);
+
+        // The next request went out with the answer, its error
+        // replaces the last answer once it comes.
+        let request = receiver.try_recv().unwrap();
+        request
+            .reply
+            .send(Err("FC23 0 timed out".to_owned()))
+            .unwrap();
+        let error = modbus.call("Device_1", read_write()).unwrap_err();
+        assert!(error.to_string().contains("FC23 0 timed out"));
+    }
+
+    #[test]
+    fn periodic_calls_are_kept_apart() {
+        let (sender, mut receiver) = mpsc::channel(4);
+        let modbus = script_modbus(sender, true);
+
+        modbus
+            .call("Device_1", ModbusCall::MaskWriteRegister(1, 0, 1))
+            .unwrap();
+        modbus.call("Device_1", read_write()).unwrap();
+
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_ok());
+        assert!(modbus.call("Device_2", read_write()).is_err());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn one_shot_calls_wait_for_the_answer() {
+        let (sender, mut receiver) = mpsc::channel(4);
+        let modbus = script_modbus(sender, false);
+
+        let device_task = std::thread::spawn(move || {
+            let request = receiver.blocking_recv().unwrap();
+            request.reply.send(Ok(ModbusCallResponse::Done)).ok();
+        });
+        let response = modbus.call("Device_1", read_write()).unwrap();
+        device_task.join().unwrap();
+
+        assert!(matches!(response, Some(ModbusCallResponse::Done)));
+        // Nothing answers anymore.
+        let error = modbus.call("Device_1", read_write()).unwrap_err();
+        assert!(error.to_string().contains("can't be sent"));
+    }
+}
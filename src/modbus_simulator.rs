@@ -0,0 +1,436 @@
+use crate::modbus_decode::*;
+use crate::modbus_device::*;
+use crate::modbus_identification::*;
+use crate::modbus_image::*;
+use anyhow::Result;
+use rand::Rng;
+use rhai::{Engine, Scope, AST};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_modbus::prelude::*;
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+
+// How a simulated register changes over time.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub enum SimulatorBehaviour {
+    // Set once at startup, clients may overwrite it.
+    Constant(f64),
+    // Rises from min to max over the period, then starts over.
+    Ramp {
+        min: f64,
+        max: f64,
+        period_ms: u64,
+    },
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period_ms: u64,
+    },
+    // Moves by up to `step` each update, kept within min and max.
+    RandomWalk {
+        min: f64,
+        max: f64,
+        step: f64,
+    },
+    // Adds `step` each update, back to 0 past max.
+    Counter {
+        step: f64,
+        max: f64,
+    },
+    // A rhai expression of `t` (seconds since start)
+    // and `value` (the previous value).
+    Script(String),
+}
+
+impl Display for SimulatorBehaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimulatorBehaviour::Constant(value) => write!(f, "CONSTANT {value}"),
+            SimulatorBehaviour::Ramp {
+                min,
+                max,
+                period_ms,
+            } => write!(f, "RAMP {min}..{max} / {period_ms} ms"),
+            SimulatorBehaviour::Sine {
+                offset,
+                amplitude,
+                period_ms,
+            } => write!(f, "SINE {offset} ± {amplitude} / {period_ms} ms"),
+            SimulatorBehaviour::RandomWalk { min, max, step } => {
+                write!(f, "RANDOM WALK {min}..{max} ± {step}")
+            }
+            SimulatorBehaviour::Counter { step, max } => write!(f, "COUNTER +{step} to {max}"),
+            SimulatorBehaviour::Script(script) => write!(f, "SCRIPT {script}"),
+        }
+    }
+}
+
+// A value served by the simulator.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct SimulatorRegister {
+    pub name: String,
+    pub register_space: ModbusRegisterSpace,
+    pub address: u16,
+    pub channel_type: ModbusChannelType,
+    #[serde(default)]
+    pub byte_order: ModbusByteOrder,
+    pub behaviour: SimulatorBehaviour,
+}
+
+impl SimulatorRegister {
+    // The register words, or the bit for coils and discrete inputs.
+    fn encode(&self, value: f64) -> Result<Vec<u16>> {
+        // Bit channels are views of a register, served as a plain word.
+        let channel_type = match &self.channel_type {
+            ModbusChannelType::Bit(_) => ModbusChannelType::U16,
+            channel_type => channel_type.clone(),
+        };
+
+        channel_type.encode(&channel_type.from_f64(value), self.byte_order)
+    }
+}
+
+// The simulator port and register map.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct SimulatorConfig {
+    pub port: u16,
+    pub update_interval_ms: u64,
+    pub registers: Vec<SimulatorRegister>,
+    // Answered to FC43/14 requests.
+    #[serde(default = "default_identification")]
+    pub identification: ModbusDeviceIdentification,
+}
+
+fn default_identification() -> ModbusDeviceIdentification {
+    ModbusDeviceIdentification::new("Colossal", "SIMULATOR", env!("CARGO_PKG_VERSION"))
+}
+
+impl Default for SimulatorConfig {
+    // Serves the channels of the default device.
+    fn default() -> Self {
+        let behaviours = [
+            SimulatorBehaviour::Sine {
+                offset: 50.0,
+                amplitude: 25.0,
+                period_ms: 60_000,
+            },
+            SimulatorBehaviour::Ramp {
+                min: 0.0,
+                max: 100.0,
+                period_ms: 30_000,
+            },
+            SimulatorBehaviour::RandomWalk {
+                min: 0.0,
+                max: 10.0,
+                step: 0.1,
+            },
+            SimulatorBehaviour::Counter {
+                step: 1.0,
+                max: 1000.0,
+            },
+            SimulatorBehaviour::Constant(3.0),
+        ];
+
+        let registers = (1..=10)
+            .map(|i| SimulatorRegister {
+                name: format!("MB{i}"),
+                register_space: ModbusRegisterSpace::HoldingRegister,
+                address: i as u16 * 2,
+                channel_type: ModbusChannelType::F32,
+                byte_order: ModbusByteOrder::Abcd,
+                behaviour: behaviours[(i - 1) % behaviours.len()].clone(),
+            })
+            .collect();
+
+        Self {
+            port: 5502,
+            update_interval_ms: 100,
+            registers,
+            identification: default_identification(),
+        }
+    }
+}
+
+impl SimulatorConfig {
+    // Read a register map saved as RON.
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let config: Self = ron::from_str(&text).map_err(|e| anyhow::anyhow!("{path}: {e}"))?;
+        for register in &config.registers {
+            register
+                .channel_type
+                .check()
+                .map_err(|e| anyhow::anyhow!("{path}: {}: {e}", register.name))?;
+        }
+
+        Ok(config)
+    }
+}
+
+// Answers the requests of one client connection.
+struct SimulatorService {
+    memory: Arc<Mutex<ModbusImage>>,
+    identification: Arc<ModbusDeviceIdentification>,
+}
+
+impl SimulatorService {
+    fn handle(&self, request: Request<'static>) -> Result<Response, ExceptionCode> {
+        match request {
+            Request::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, data) => self
+                .identification
+                .response(&data)
+                .map(|data| Response::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, data.into()))
+                .ok_or(ExceptionCode::IllegalDataValue),
+            request => self.memory.lock().unwrap().handle(request),
+        }
+    }
+}
+
+impl tokio_modbus::server::Service for SimulatorService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = std::future::Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        std::future::ready(self.handle(request))
+    }
+}
+
+// Computes the register values on every update.
+struct SimulatorEngine {
+    registers: Vec<SimulatorRegister>,
+    values: Vec<f64>,
+    engine: Engine,
+    // Compiled scripts, by register index.
+    scripts: HashMap<usize, AST>,
+    start: Instant,
+}
+
+impl SimulatorEngine {
+    fn new(registers: Vec<SimulatorRegister>) -> Result<Self> {
+        let engine = Engine::new();
+        let mut scripts = HashMap::new();
+        for (index, register) in registers.iter().enumerate() {
+            if let SimulatorBehaviour::Script(script) = &register.behaviour {
+                let ast = engine
+                    .compile_expression(script)
+                    .map_err(|e| anyhow::anyhow!("{}: {e}", register.name))?;
+                scripts.insert(index, ast);
+            }
+        }
+
+        let values = registers
+            .iter()
+            .map(|register| match register.behaviour {
+                SimulatorBehaviour::Constant(value) => value,
+                SimulatorBehaviour::Ramp { min, .. } => min,
+                SimulatorBehaviour::RandomWalk { min, max, .. } => (min + max) / 2.0,
+                _ => 0.0,
+            })
+            .collect();
+
+        Ok(Self {
+            registers,
+            values,
+            engine,
+            scripts,
+            start: Instant::now(),
+        })
+    }
+
+    // Compute the next values and store the changed ones.
+    // Constants are only stored once so written values stick.
+    fn update(&mut self, memory: &Mutex<ModbusImage>, first: bool) {
+        let t = self.start.elapsed().as_secs_f64();
+        let mut rng = rand::thread_rng();
+        let mut memory = memory.lock().unwrap();
+
+        for (index, register) in self.registers.iter().enumerate() {
+            let previous = self.values[index];
+            let value = match &register.behaviour {
+                SimulatorBehaviour::Constant(_) if !first => continue,
+                SimulatorBehaviour::Constant(value) => *value,
+                SimulatorBehaviour::Ramp {
+                    min,
+                    max,
+                    period_ms,
+                } => {
+                    let period = (*period_ms).max(1) as f64 / 1000.0;
+                    min + (max - min) * (t % period) / period
+                }
+                SimulatorBehaviour::Sine {
+                    offset,
+                    amplitude,
+                    period_ms,
+                } => {
+                    let period = (*period_ms).max(1) as f64 / 1000.0;
+                    offset + amplitude * (std::f64::consts::TAU * t / period).sin()
+                }
+                SimulatorBehaviour::RandomWalk { min, max, step } => {
+                    let step = step.abs();
+                    let delta = if step > 0.0 {
+                        rng.gen_range(-step..=step)
+                    } else {
+                        0.0
+                    };
+                    (previous + delta).clamp(*min, *max)
+                }
+                SimulatorBehaviour::Counter { step, max } => {
+                    let value = previous + step;
+                    if value > *max {
+                        0.0
+                    } else {
+                        value
+                    }
+                }
+                SimulatorBehaviour::Script(_) => {
+                    let mut scope = Scope::new();
+                    scope.push("t", t);
+                    scope.push("value", previous);
+
+                    let result = self
+                        .engine
+                        .eval_ast_with_scope::<rhai::Dynamic>(&mut scope, &self.scripts[&index]);
+                    match result.map(|value| value.as_float().or(value.as_int().map(|v| v as f64)))
+                    {
+                        Ok(Ok(value)) => value,
+                        // Bad results keep the previous value.
+                        _ => previous,
+                    }
+                }
+            };
+
+            self.values[index] = value;
+            if let Ok(words) = register.encode(value) {
+                memory.store(register.register_space, register.address, &words);
+            }
+        }
+    }
+}
+
+// Serve the register map until `stop` completes.
+// Errors of single client connections go to `on_client_error`.
+pub async fn run_simulator(
+    config: SimulatorConfig,
+    stop: impl Future<Output = ()>,
+    on_client_error: impl FnOnce(std::io::Error) + Clone + Send + 'static,
+) -> Result<()> {
+    let mut engine = SimulatorEngine::new(config.registers)?;
+    let memory = Arc::new(Mutex::new(ModbusImage::default()));
+    engine.update(&memory, true);
+
+    let socket_addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    let listener = TcpListener::bind(socket_addr).await?;
+    let server = Server::new(listener);
+
+    let service_memory = memory.clone();
+    let identification = Arc::new(config.identification);
+    let on_connected = move |stream, socket_addr| {
+        let memory = service_memory.clone();
+        let identification = identification.clone();
+        async move {
+            accept_tcp_connection(stream, socket_addr, move |_| {
+                Ok(Some(SimulatorService {
+                    memory: memory.clone(),
+                    identification: identification.clone(),
+                }))
+            })
+        }
+    };
+    let update_interval = Duration::from_millis(config.update_interval_ms.max(1));
+    let updates = async {
+        let mut interval = tokio::time::interval(update_interval);
+        loop {
+            interval.tick().await;
+            engine.update(&memory, false);
+        }
+    };
+
+    tokio::select! {
+        result = server.serve(&on_connected, on_client_error) => result?,
+        _ = updates => {}
+        _ = stop => {}
+    }
+
+    Ok(())
+}
+
+// A simulator running on its own thread, stopped when dropped.
+pub struct SimulatorHandle {
+    stop: Option<tokio::sync::oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+    // Last client connection error, the simulator keeps serving.
+    client_error: Arc<Mutex<Option<String>>>,
+}
+
+impl SimulatorHandle {
+    pub fn spawn(config: SimulatorConfig) -> Self {
+        let (stop, stop_receiver) = tokio::sync::oneshot::channel();
+        let client_error = Arc::new(Mutex::new(None));
+        let on_client_error = {
+            let client_error = client_error.clone();
+            move |e: std::io::Error| {
+                *client_error.lock().unwrap() = Some(format!("Client error: {e}"));
+            }
+        };
+        let thread = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(run_simulator(
+                    config,
+                    async {
+                        stop_receiver.await.ok();
+                    },
+                    on_client_error,
+                ))
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+            client_error,
+        }
+    }
+
+    // The client error raised since the last call, if any.
+    pub fn take_client_error(&self) -> Option<String> {
+        self.client_error.lock().unwrap().take()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    // Stop the simulator and return how it ended.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => anyhow::bail!("Simulator thread panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SimulatorHandle {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
@@ -0,0 +1,530 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Frames kept by the monitor, older ones are dropped.
+const MAX_TRAFFIC_ENTRIES: usize = 2000;
+
+// pcap link type reserved for private use, Modbus frames
+// are stored as is with the MBAP header or RTU CRC.
+const PCAP_LINKTYPE_USER0: u32 = 147;
+
+// One request and its response as they went over the wire.
+#[derive(Clone, Debug)]
+pub struct TrafficEntry {
+    pub time: SystemTime,
+    pub device: String,
+    pub unit_id: u8,
+    pub function_code: u8,
+    pub address: u16,
+    pub count: u16,
+    // Raw frames, including the MBAP header or the RTU CRC.
+    pub sent: Vec<u8>,
+    pub received: Vec<u8>,
+    pub duration: Duration,
+    pub exception: Option<u8>,
+    // Timeout or transport error.
+    pub error: Option<String>,
+}
+
+impl TrafficEntry {
+    pub fn is_error(&self) -> bool {
+        self.exception.is_some() || self.error.is_some()
+    }
+
+    pub fn result(&self) -> String {
+        match (&self.error, self.exception) {
+            (Some(error), _) => error.clone(),
+            (None, Some(code)) => format!("EXCEPTION {code:#04X}"),
+            (None, None) => "OK".to_owned(),
+        }
+    }
+
+    // Case-insensitive match on the device, function code, address and result.
+    pub fn matches(&self, filter: &str) -> bool {
+        if filter.is_empty() {
+            return true;
+        }
+
+        let filter = filter.to_lowercase();
+        let fields = [
+            self.device.to_lowercase(),
+            format!("fc{:02}", self.function_code),
+            format!("{}", self.address),
+            self.result().to_lowercase(),
+        ];
+        fields.iter().any(|field| field.contains(&filter))
+    }
+}
+
+// Bytes in hex, e.g. "00 01 00 00 00 06".
+pub fn format_bytes(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 3);
+    for (index, byte) in bytes.iter().enumerate() {
+        if index > 0 {
+            text.push(' ');
+        }
+        write!(text, "{byte:02X}").ok();
+    }
+    text
+}
+
+// UTC time of day with milliseconds, e.g. "14:03:27.512".
+pub fn format_time_of_day(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// The bounded traffic log, shared by the polling thread and the GUI.
+#[derive(Clone)]
+pub struct TrafficMonitor {
+    entries: Arc<Mutex<VecDeque<TrafficEntry>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Default for TrafficMonitor {
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_TRAFFIC_ENTRIES))),
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl TrafficMonitor {
+    pub fn record(&self, entry: TrafficEntry) {
+        if self.is_paused() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_TRAFFIC_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // A copy of the log, oldest first.
+    pub fn entries(&self) -> Vec<TrafficEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    // A recorder for a new connection.
+    pub fn recorder(&self) -> TrafficRecorder {
+        TrafficRecorder {
+            monitor: self.clone(),
+            capture: TrafficCapture::default(),
+        }
+    }
+}
+
+// Save entries as text, one request and response per block.
+pub fn export_traffic_text(path: &str, entries: &[TrafficEntry]) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+    for entry in entries {
+        writeln!(
+            file,
+            "{} {} unit {} FC{:02} address {} count {} {:.1} ms {}",
+            format_time_of_day(entry.time),
+            entry.device,
+            entry.unit_id,
+            entry.function_code,
+            entry.address,
+            entry.count,
+            entry.duration.as_secs_f64() * 1000.0,
+            entry.result()
+        )?;
+        writeln!(file, "  TX: {}", format_bytes(&entry.sent))?;
+        writeln!(file, "  RX: {}", format_bytes(&entry.received))?;
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+// Save entries as a pcap file, each request and response a packet.
+pub fn export_traffic_pcap(path: &str, entries: &[TrafficEntry]) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+    // Global header: magic, version 2.4, UTC, accuracy, snap length, link type.
+    file.write_all(&0xA1B2_C3D4u32.to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&4u16.to_le_bytes())?;
+    file.write_all(&0i32.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&65_535u32.to_le_bytes())?;
+    file.write_all(&PCAP_LINKTYPE_USER0.to_le_bytes())?;
+
+    for entry in entries {
+        let packets = [
+            (entry.time, &entry.sent),
+            (entry.time + entry.duration, &entry.received),
+        ];
+        for (time, bytes) in packets {
+            if bytes.is_empty() {
+                continue;
+            }
+
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            file.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+            file.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+            file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            file.write_all(bytes)?;
+        }
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+// Bytes written to and read from a connection since the last request.
+#[derive(Clone, Default)]
+pub struct TrafficCapture {
+    bytes: Arc<Mutex<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl TrafficCapture {
+    // Take the (sent, received) bytes, leaving the capture empty.
+    fn take(&self) -> (Vec<u8>, Vec<u8>) {
+        std::mem::take(&mut *self.bytes.lock().unwrap())
+    }
+}
+
+impl std::fmt::Debug for TrafficCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TrafficCapture")
+    }
+}
+
+// What a request was about, the frames are added by the recorder.
+pub struct TrafficRequest {
+    pub device: String,
+    pub unit_id: u8,
+    pub function_code: u8,
+    pub address: u16,
+    pub count: u16,
+}
+
+// Turns the bytes of one connection into monitor entries.
+#[derive(Clone)]
+pub struct TrafficRecorder {
+    monitor: TrafficMonitor,
+    capture: TrafficCapture,
+}
+
+impl TrafficRecorder {
+    pub fn capture(&self) -> TrafficCapture {
+        self.capture.clone()
+    }
+
+    // Drop the bytes left over from before the request,
+    // e.g. the late answer to a request that timed out.
+    pub fn start(&self) -> SystemTime {
+        self.capture.take();
+        SystemTime::now()
+    }
+
+    pub fn record(
+        &self,
+        request: TrafficRequest,
+        time: SystemTime,
+        duration: Duration,
+        exception: Option<u8>,
+        error: Option<String>,
+    ) {
+        let (sent, received) = self.capture.take();
+        self.monitor.record(TrafficEntry {
+            time,
+            device: request.device,
+            unit_id: request.unit_id,
+            function_code: request.function_code,
+            address: request.address,
+            count: request.count,
+            sent,
+            received,
+            duration,
+            exception,
+            error,
+        });
+    }
+}
+
+// A transport that copies its bytes to a capture.
+#[derive(Debug)]
+pub struct TrafficTap<T> {
+    inner: T,
+    capture: Option<TrafficCapture>,
+}
+
+impl<T> TrafficTap<T> {
+    pub fn new(inner: T, capture: Option<TrafficCapture>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TrafficTap<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let (Poll::Ready(Ok(())), Some(capture)) = (&poll, &self.capture) {
+            let read = &buf.filled()[filled..];
+            capture.bytes.lock().unwrap().1.extend_from_slice(read);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TrafficTap<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let (Poll::Ready(Ok(written)), Some(capture)) = (&poll, &self.capture) {
+            capture
+                .bytes
+                .lock()
+                .unwrap()
+                .0
+                .extend_from_slice(&buf[..*written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn entry(address: u16) -> TrafficEntry {
+        TrafficEntry {
+            time: UNIX_EPOCH + Duration::from_micros(1_700_000_000_250_000),
+            device: "PLC_1".to_owned(),
+            unit_id: 1,
+            function_code: 3,
+            address,
+            count: 1,
+            sent: vec![0, 1, 0, 0, 0, 6, 1, 3, 0, 0, 0, 1],
+            received: vec![0, 1, 0, 0, 0, 5, 1, 3, 2, 0, 42],
+            duration: Duration::from_micros(1500),
+            exception: None,
+            error: None,
+        }
+    }
+
+    // A file in the temp directory, removed when the test is done.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("colossal_{}_{name}", std::process::id());
+            Self(std::env::temp_dir().join(name))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn the_oldest_entries_are_dropped() {
+        let monitor = TrafficMonitor::default();
+        for address in 0..MAX_TRAFFIC_ENTRIES as u16 + 5 {
+            monitor.record(entry(address));
+        }
+
+        let entries = monitor.entries();
+        assert_eq!(entries.len(), MAX_TRAFFIC_ENTRIES);
+        assert_eq!(entries[0].address, 5);
+        assert_eq!(
+            entries.last().unwrap().address,
+            MAX_TRAFFIC_ENTRIES as u16 + 4
+        );
+    }
+
+    #[test]
+    fn nothing_is_recorded_while_paused() {
+        let monitor = TrafficMonitor::default();
+        monitor.record(entry(1));
+
+        monitor.set_paused(true);
+        monitor.record(entry(2));
+        // Recorders share the pause of their monitor.
+        monitor.recorder().record(
+            TrafficRequest {
+                device: "PLC_1".to_owned(),
+                unit_id: 1,
+                function_code: 3,
+                address: 3,
+                count: 1,
+            },
+            SystemTime::now(),
+            Duration::ZERO,
+            None,
+            None,
+        );
+        assert_eq!(monitor.len(), 1);
+
+        monitor.set_paused(false);
+        monitor.record(entry(4));
+        let addresses: Vec<u16> = monitor.entries().iter().map(|e| e.address).collect();
+        assert_eq!(addresses, vec![1, 4]);
+    }
+
+    #[test]
+    fn pcap_has_a_header_and_a_packet_per_frame() {
+        let file = TempFile::new("traffic.pcap");
+        let mut failed = entry(2);
+        failed.received.clear();
+        failed.error = Some("Timeout".to_owned());
+
+        export_traffic_pcap(file.path(), &[entry(1), failed]).unwrap();
+        let bytes = std::fs::read(&file.0).unwrap();
+
+        assert_eq!(u32_at(&bytes, 0), 0xA1B2_C3D4);
+        assert_eq!(&bytes[4..8], &[2, 0, 4, 0]);
+        assert_eq!(u32_at(&bytes, 16), 65_535);
+        assert_eq!(u32_at(&bytes, 20), PCAP_LINKTYPE_USER0);
+        assert_eq!(PCAP_LINKTYPE_USER0, 147);
+
+        // Request, response, then the request without an answer.
+        let mut offset = 24;
+        let mut packets = Vec::new();
+        while offset < bytes.len() {
+            let seconds = u32_at(&bytes, offset);
+            let micros = u32_at(&bytes, offset + 4);
+            let length = u32_at(&bytes, offset + 8) as usize;
+            assert_eq!(u32_at(&bytes, offset + 12) as usize, length);
+            let data = bytes[offset + 16..offset + 16 + length].to_vec();
+            packets.push((seconds, micros, data));
+            offset += 16 + length;
+        }
+        let entry = entry(1);
+        assert_eq!(
+            packets,
+            vec![
+                (1_700_000_000, 250_000, entry.sent.clone()),
+                (1_700_000_000, 251_500, entry.received),
+                (1_700_000_000, 250_000, entry.sent),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_export_shows_why_a_request_failed() {
+        let file = TempFile::new("traffic.txt");
+        let mut timeout = entry(7);
+        timeout.received.clear();
+        timeout.error = Some("Timed out".to_owned());
+        let mut exception = entry(8);
+        exception.exception = Some(0x02);
+        exception.received = vec![0, 1, 0, 0, 0, 3, 1, 0x83, 2];
+
+        export_traffic_text(file.path(), &[timeout, exception]).unwrap();
+        let text = std::fs::read_to_string(&file.0).unwrap();
+
+        assert_eq!(
+            text,
+            "22:13:20.250 PLC_1 unit 1 FC03 address 7 count 1 1.5 ms Timed out\n\
+             \x20 TX: 00 01 00 00 00 06 01 03 00 00 00 01\n\
+             \x20 RX: \n\
+             22:13:20.250 PLC_1 unit 1 FC03 address 8 count 1 1.5 ms EXCEPTION 0x02\n\
+             \x20 TX: 00 01 00 00 00 06 01 03 00 00 00 01\n\
+             \x20 RX: 00 01 00 00 00 03 01 83 02\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_tap_captures_each_request() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let monitor = TrafficMonitor::default();
+        let recorder = monitor.recorder();
+        let mut tap = TrafficTap::new(client, Some(recorder.capture()));
+
+        // A late answer from before the request is dropped.
+        server.write_all(&[0xFF]).await.unwrap();
+        tap.read_exact(&mut [0]).await.unwrap();
+        let time = recorder.start();
+
+        tap.write_all(&[1, 2, 3]).await.unwrap();
+        server.read_exact(&mut [0; 3]).await.unwrap();
+        server.write_all(&[4, 5]).await.unwrap();
+        tap.read_exact(&mut [0; 2]).await.unwrap();
+        let request = TrafficRequest {
+            device: "PLC_1".to_owned(),
+            unit_id: 1,
+            function_code: 3,
+            address: 0,
+            count: 1,
+        };
+        recorder.record(request, time, Duration::ZERO, None, None);
+
+        let entries = monitor.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sent, vec![1, 2, 3]);
+        assert_eq!(entries[0].received, vec![4, 5]);
+        assert!(!entries[0].is_error());
+    }
+}
@@ -1,4 +1,14 @@
 mod ui_buffer;
+pub mod ui_calculations;
+pub mod ui_gateway;
 pub mod ui_panels;
+pub mod ui_scanner;
+pub mod ui_simulator;
+pub mod ui_traffic;
 pub use ui_buffer::*;
+pub use ui_calculations::*;
+pub use ui_gateway::*;
 pub use ui_panels::*;
+pub use ui_scanner::*;
+pub use ui_simulator::*;
+pub use ui_traffic::*;
@@ -1,6 +1,14 @@
 use std::fmt::Display;
 
-use crate::ModbusDeviceConfig;
+use anyhow::Result;
+
+use crate::app::ChannelSettings;
+use crate::{
+    default_tcp_unit_id, ModbusBlockConfig, ModbusChannel, ModbusDevice, ModbusDeviceConfig,
+    ModbusRegisterSpace, ModbusRequestConfig, ModbusScaling, ModbusScanClass, ModbusScanConfig,
+    ModbusSerialConfig, ModbusTcpConfig, ScannerConfig, SerialDataBits, SerialParity,
+    SerialStopBits,
+};
 
 #[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub enum ModbusDeviceType {
@@ -17,6 +25,7 @@ impl Display for ModbusDeviceType {
     }
 }
 #[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
 pub struct ModbusDeviceBuffer {
     pub id: usize,
     pub code: String,
@@ -24,10 +33,42 @@ pub struct ModbusDeviceBuffer {
     pub ip: String,
     pub port: String,
     pub device_type: ModbusDeviceType,
+    // Serial line settings.
+    pub serial_port: String,
+    pub baud_rate: String,
+    pub parity: SerialParity,
+    pub data_bits: SerialDataBits,
+    pub stop_bits: SerialStopBits,
+    pub frame_delay_ms: String,
+    // Modbus unit ID, for TCP and serial devices.
+    pub unit_id: String,
+    // Block read settings.
+    pub max_block_size: String,
+    pub max_gap: String,
+    pub use_multiple_write: bool,
+    pub read_back_writes: bool,
+    pub poll_interval_ms: String,
+    pub scan_classes: Vec<ScanClassBuffer>,
+    // Request timeout and retry settings.
+    pub timeout_ms: String,
+    pub retries: String,
+    pub reconnect_delay_ms: String,
+}
+
+// Text fields of a device scan class.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+#[serde(default)]
+pub struct ScanClassBuffer {
+    pub name: String,
+    pub interval_ms: String,
 }
 
 impl Default for ModbusDeviceBuffer {
     fn default() -> Self {
+        let serial_config = ModbusSerialConfig::default();
+        let block_config = ModbusBlockConfig::default();
+        let request_config = ModbusRequestConfig::default();
+
         Self {
             id: 1,
             code: "MB".to_owned(),
@@ -35,6 +76,460 @@ impl Default for ModbusDeviceBuffer {
             ip: "127.0.0.1".to_owned(),
             port: "5502".to_owned(),
             device_type: ModbusDeviceType::Tcp,
+            serial_port: serial_config.port,
+            baud_rate: serial_config.baud_rate.to_string(),
+            parity: serial_config.parity,
+            data_bits: serial_config.data_bits,
+            stop_bits: serial_config.stop_bits,
+            unit_id: default_tcp_unit_id().to_string(),
+            frame_delay_ms: serial_config.frame_delay_ms.to_string(),
+            max_block_size: block_config.max_block_size.to_string(),
+            max_gap: block_config.max_gap.to_string(),
+            use_multiple_write: block_config.use_multiple_write,
+            read_back_writes: block_config.read_back_writes,
+            poll_interval_ms: ModbusScanConfig::default().poll_interval_ms.to_string(),
+            scan_classes: Vec::new(),
+            timeout_ms: request_config.timeout_ms.to_string(),
+            retries: request_config.retries.to_string(),
+            reconnect_delay_ms: request_config.reconnect_delay_ms.to_string(),
+        }
+    }
+}
+
+impl ModbusDeviceBuffer {
+    // Fill the buffer from an existing device config.
+    pub fn from_device(device: &ModbusDevice) -> Self {
+        let mut buffer = Self {
+            id: device.id,
+            code: device.code.clone(),
+            name: device.name.clone(),
+            unit_id: device.unit_id().to_string(),
+            max_block_size: device.block_config.max_block_size.to_string(),
+            max_gap: device.block_config.max_gap.to_string(),
+            use_multiple_write: device.block_config.use_multiple_write,
+            read_back_writes: device.block_config.read_back_writes,
+            poll_interval_ms: device.scan_config.poll_interval_ms.to_string(),
+            scan_classes: device
+                .scan_config
+                .scan_classes
+                .iter()
+                .map(|scan_class| ScanClassBuffer {
+                    name: scan_class.name.clone(),
+                    interval_ms: scan_class.interval_ms.to_string(),
+                })
+                .collect(),
+            timeout_ms: device.request_config.timeout_ms.to_string(),
+            retries: device.request_config.retries.to_string(),
+            reconnect_delay_ms: device.request_config.reconnect_delay_ms.to_string(),
+            ..Default::default()
+        };
+
+        match &device.config {
+            ModbusDeviceConfig::Tcp(conf) => {
+                buffer.device_type = ModbusDeviceType::Tcp;
+                buffer.ip = conf.ip.clone();
+                buffer.port = conf.port.to_string();
+            }
+            ModbusDeviceConfig::Serial(conf) => {
+                buffer.device_type = ModbusDeviceType::Serial;
+                buffer.serial_port = conf.port.clone();
+                buffer.baud_rate = conf.baud_rate.to_string();
+                buffer.parity = conf.parity;
+                buffer.data_bits = conf.data_bits;
+                buffer.stop_bits = conf.stop_bits;
+                buffer.frame_delay_ms = conf.frame_delay_ms.to_string();
+            }
+        }
+
+        buffer
+    }
+
+    // Parse the text fields into a device config.
+    pub fn to_device_config(&self) -> Result<ModbusDeviceConfig> {
+        let unit_id = self
+            .unit_id
+            .trim()
+            .parse::<u8>()
+            .map_err(|e| anyhow::anyhow!("Invalid unit ID: {e}"))?;
+
+        match self.device_type {
+            ModbusDeviceType::Tcp => {
+                let port = self
+                    .port
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|e| anyhow::anyhow!("Invalid port: {e}"))?;
+
+                Ok(ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+                    ip: self.ip.trim().to_owned(),
+                    port,
+                    unit_id,
+                }))
+            }
+            ModbusDeviceType::Serial => {
+                let baud_rate = self
+                    .baud_rate
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| anyhow::anyhow!("Invalid baud rate: {e}"))?;
+                let frame_delay_ms = self
+                    .frame_delay_ms
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| anyhow::anyhow!("Invalid frame delay: {e}"))?;
+
+                Ok(ModbusDeviceConfig::Serial(ModbusSerialConfig {
+                    port: self.serial_port.trim().to_owned(),
+                    baud_rate,
+                    parity: self.parity,
+                    data_bits: self.data_bits,
+                    stop_bits: self.stop_bits,
+                    unit_id,
+                    frame_delay_ms,
+                }))
+            }
+        }
+    }
+
+    // Parse the block read settings.
+    pub fn to_block_config(&self) -> Result<ModbusBlockConfig> {
+        let max_block_size = self
+            .max_block_size
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| anyhow::anyhow!("Invalid max block size: {e}"))?;
+        let max_gap = self
+            .max_gap
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| anyhow::anyhow!("Invalid max gap: {e}"))?;
+
+        Ok(ModbusBlockConfig {
+            max_block_size,
+            max_gap,
+            use_multiple_write: self.use_multiple_write,
+            read_back_writes: self.read_back_writes,
+        })
+    }
+
+    // Parse the device poll interval.
+    pub fn to_poll_interval_ms(&self) -> Result<u64> {
+        let poll_interval_ms = self
+            .poll_interval_ms
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid poll interval: {e}"))?;
+
+        if poll_interval_ms == 0 {
+            anyhow::bail!("Poll interval must be at least 1 ms");
+        }
+
+        Ok(poll_interval_ms)
+    }
+
+    // Parse the scan classes, names must be unique.
+    pub fn to_scan_classes(&self) -> Result<Vec<ModbusScanClass>> {
+        let mut scan_classes: Vec<ModbusScanClass> = Vec::new();
+
+        for buffer in &self.scan_classes {
+            let name = buffer.name.trim();
+            if name.is_empty() {
+                anyhow::bail!("Scan classes need a name");
+            }
+            if scan_classes
+                .iter()
+                .any(|scan_class| scan_class.name == name)
+            {
+                anyhow::bail!("Scan class {name} is defined twice");
+            }
+
+            let interval_ms = buffer
+                .interval_ms
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("Invalid interval of scan class {name}: {e}"))?;
+            if interval_ms == 0 {
+                anyhow::bail!("Scan class {name} interval must be at least 1 ms");
+            }
+
+            scan_classes.push(ModbusScanClass {
+                name: name.to_owned(),
+                interval_ms,
+            });
+        }
+
+        Ok(scan_classes)
+    }
+
+    // Parse the request timeout and retry settings.
+    pub fn to_request_config(&self) -> Result<ModbusRequestConfig> {
+        let timeout_ms = self
+            .timeout_ms
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid timeout: {e}"))?;
+        let retries = self
+            .retries
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| anyhow::anyhow!("Invalid retries: {e}"))?;
+        let reconnect_delay_ms = self
+            .reconnect_delay_ms
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid reconnect delay: {e}"))?;
+
+        if timeout_ms == 0 {
+            anyhow::bail!("Timeout must be at least 1 ms");
+        }
+
+        Ok(ModbusRequestConfig {
+            timeout_ms,
+            retries,
+            reconnect_delay_ms,
+        })
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy)]
+pub enum ScalingType {
+    None,
+    Linear,
+    GainOffset,
+}
+
+impl Display for ScalingType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScalingType::None => write!(f, "None"),
+            ScalingType::Linear => write!(f, "Linear"),
+            ScalingType::GainOffset => write!(f, "Gain/Offset"),
         }
     }
 }
+
+// Text fields of the selected channel settings.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ModbusChannelBuffer {
+    pub scaling_type: ScalingType,
+    pub raw_min: String,
+    pub raw_max: String,
+    pub eu_min: String,
+    pub eu_max: String,
+    pub gain: String,
+    pub offset: String,
+    // Engineering limits, only used when enabled.
+    pub use_clamp: bool,
+    pub clamp_min: String,
+    pub clamp_max: String,
+    pub unit: String,
+}
+
+impl Default for ModbusChannelBuffer {
+    fn default() -> Self {
+        Self {
+            scaling_type: ScalingType::None,
+            raw_min: "0".to_owned(),
+            raw_max: "65535".to_owned(),
+            eu_min: "0".to_owned(),
+            eu_max: "100".to_owned(),
+            gain: "1".to_owned(),
+            offset: "0".to_owned(),
+            use_clamp: false,
+            clamp_min: "0".to_owned(),
+            clamp_max: "100".to_owned(),
+            unit: String::new(),
+        }
+    }
+}
+
+impl ModbusChannelBuffer {
+    // Fill the buffer from an existing channel.
+    pub fn from_channel(channel: &ModbusChannel) -> Self {
+        let mut buffer = Self {
+            unit: channel.unit.clone(),
+            ..Default::default()
+        };
+
+        match channel.scaling {
+            ModbusScaling::None => {}
+            ModbusScaling::Linear {
+                raw_min,
+                raw_max,
+                eu_min,
+                eu_max,
+            } => {
+                buffer.scaling_type = ScalingType::Linear;
+                buffer.raw_min = raw_min.to_string();
+                buffer.raw_max = raw_max.to_string();
+                buffer.eu_min = eu_min.to_string();
+                buffer.eu_max = eu_max.to_string();
+            }
+            ModbusScaling::GainOffset { gain, offset } => {
+                buffer.scaling_type = ScalingType::GainOffset;
+                buffer.gain = gain.to_string();
+                buffer.offset = offset.to_string();
+            }
+        }
+
+        if let Some((min, max)) = channel.clamp {
+            buffer.use_clamp = true;
+            buffer.clamp_min = min.to_string();
+            buffer.clamp_max = max.to_string();
+        }
+
+        buffer
+    }
+
+    // Parse the text fields into the settings of a channel.
+    // The scan class is assigned in the channels table.
+    pub fn to_channel_settings(&self, channel: &ModbusChannel) -> Result<ChannelSettings> {
+        fn number(text: &str, name: &str) -> Result<f64> {
+            let value = text
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| anyhow::anyhow!("Invalid {name}: {e}"))?;
+            if !value.is_finite() {
+                anyhow::bail!("Invalid {name}: {value}");
+            }
+
+            Ok(value)
+        }
+
+        let scaling = match self.scaling_type {
+            ScalingType::None => ModbusScaling::None,
+            ScalingType::Linear => {
+                let raw_min = number(&self.raw_min, "raw min")?;
+                let raw_max = number(&self.raw_max, "raw max")?;
+                let eu_min = number(&self.eu_min, "EU min")?;
+                let eu_max = number(&self.eu_max, "EU max")?;
+                if raw_min == raw_max || eu_min == eu_max {
+                    anyhow::bail!("Scaling ranges must not be empty");
+                }
+                ModbusScaling::Linear {
+                    raw_min,
+                    raw_max,
+                    eu_min,
+                    eu_max,
+                }
+            }
+            ScalingType::GainOffset => {
+                let gain = number(&self.gain, "gain")?;
+                if gain == 0.0 {
+                    anyhow::bail!("Gain must not be zero");
+                }
+                ModbusScaling::GainOffset {
+                    gain,
+                    offset: number(&self.offset, "offset")?,
+                }
+            }
+        };
+
+        let clamp = if self.use_clamp {
+            let min = number(&self.clamp_min, "clamp min")?;
+            let max = number(&self.clamp_max, "clamp max")?;
+            if max < min {
+                anyhow::bail!("Clamp range must end after it starts");
+            }
+            Some((min, max))
+        } else {
+            None
+        };
+
+        Ok(ChannelSettings {
+            scaling,
+            clamp,
+            unit: self.unit.trim().to_owned(),
+            ..ChannelSettings::from_channel(channel)
+        })
+    }
+}
+
+// Text fields of the register scanner.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ScannerBuffer {
+    pub ip: String,
+    pub port: String,
+    pub first_unit_id: String,
+    pub last_unit_id: String,
+    pub start_address: String,
+    pub end_address: String,
+    pub chunk_size: String,
+    pub timeout_ms: String,
+    // Register spaces to probe.
+    pub coils: bool,
+    pub discrete_inputs: bool,
+    pub input_registers: bool,
+    pub holding_registers: bool,
+}
+
+impl Default for ScannerBuffer {
+    fn default() -> Self {
+        Self {
+            ip: "127.0.0.1".to_owned(),
+            port: "502".to_owned(),
+            first_unit_id: "1".to_owned(),
+            last_unit_id: "1".to_owned(),
+            start_address: "0".to_owned(),
+            end_address: "99".to_owned(),
+            chunk_size: "10".to_owned(),
+            timeout_ms: "500".to_owned(),
+            coils: false,
+            discrete_inputs: false,
+            input_registers: true,
+            holding_registers: true,
+        }
+    }
+}
+
+impl ScannerBuffer {
+    // Parse the text fields into a scan of a TCP endpoint.
+    pub fn to_scanner_config(&self) -> Result<ScannerConfig> {
+        fn parse<T: std::str::FromStr>(text: &str, name: &str) -> Result<T>
+        where
+            T::Err: Display,
+        {
+            text.trim()
+                .parse::<T>()
+                .map_err(|e| anyhow::anyhow!("Invalid {name}: {e}"))
+        }
+
+        let first_unit_id = parse::<u8>(&self.first_unit_id, "first unit ID")?;
+        let last_unit_id = parse::<u8>(&self.last_unit_id, "last unit ID")?;
+        let start_address = parse::<u16>(&self.start_address, "start address")?;
+        let end_address = parse::<u16>(&self.end_address, "end address")?;
+        if last_unit_id < first_unit_id || end_address < start_address {
+            anyhow::bail!("Ranges must end after they start");
+        }
+
+        let register_spaces: Vec<ModbusRegisterSpace> = [
+            (self.coils, ModbusRegisterSpace::Coil),
+            (self.discrete_inputs, ModbusRegisterSpace::DiscreteInput),
+            (self.input_registers, ModbusRegisterSpace::InputRegister),
+            (self.holding_registers, ModbusRegisterSpace::HoldingRegister),
+        ]
+        .into_iter()
+        .filter_map(|(selected, register_space)| selected.then_some(register_space))
+        .collect();
+        if register_spaces.is_empty() {
+            anyhow::bail!("Select at least one register space");
+        }
+
+        Ok(ScannerConfig {
+            config: ModbusDeviceConfig::Tcp(ModbusTcpConfig {
+                ip: self.ip.trim().to_owned(),
+                port: parse(&self.port, "port")?,
+                unit_id: first_unit_id,
+            }),
+            first_unit_id,
+            last_unit_id,
+            register_spaces,
+            start_address,
+            end_address,
+            chunk_size: parse(&self.chunk_size, "chunk size")?,
+            timeout_ms: parse(&self.timeout_ms, "timeout")?,
+        })
+    }
+}
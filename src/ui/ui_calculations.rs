@@ -0,0 +1,647 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
+
+use egui::text::{CCursor, CCursorRange, LayoutJob};
+use egui::{Color32, Frame, Margin, Stroke, TextFormat};
+use egui_extras::{Column, TableBuilder};
+use regex::Regex;
+
+use crate::app::ThreadCommand;
+use crate::ui::quality_color;
+use crate::ColossalApp;
+use crate::{
+    CalculationChannel, CalculationEngine, CalculationError, ChannelQuality, ModbusDevice,
+    ScriptModbus,
+};
+
+// Results of the calculation channels, updated by the polling thread each scan.
+pub fn ui_calculation_channels_table(
+    app: &mut ColossalApp,
+    ui: &mut egui::Ui,
+) -> anyhow::Result<()> {
+    let table_frame = Frame {
+        stroke: Stroke::new(1.0, Color32::LIGHT_YELLOW),
+        inner_margin: Margin::symmetric(10, 10),
+        ..Default::default()
+    };
+
+    table_frame.show(ui, |ui| {
+        ui.vertical_centered_justified(|ui| {
+            ui.label(format!(
+                "{} Calculation Channels",
+                egui_phosphor::regular::CALCULATOR
+            ))
+        });
+        ui.separator();
+
+        TableBuilder::new(ui)
+            .id_salt("calculation_channels_table")
+            .striped(true)
+            .resizable(true)
+            .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+            .column(Column::exact(80.))
+            .column(Column::initial(250.))
+            .column(Column::exact(100.))
+            .column(Column::exact(80.))
+            .column(Column::remainder())
+            .vscroll(true)
+            .auto_shrink(false)
+            .min_scrolled_height(0.0)
+            .max_scroll_height(150.0)
+            .header(30.0, |mut header| {
+                for title in ["NAME", "EXPRESSION", "VALUE", "QUALITY", "ERROR"] {
+                    header.col(|ui| {
+                        ui.strong(title);
+                    });
+                }
+            })
+            .body(|body| {
+                let channels = &app.calculation_channels;
+                body.rows(20.0, channels.len(), |mut row| {
+                    let channel = &channels[row.index()];
+
+                    row.col(|ui| {
+                        if channel.enabled {
+                            ui.label(&channel.name);
+                        } else {
+                            ui.weak(&channel.name).on_hover_text("Disabled");
+                        }
+                    });
+                    // Scripts on several lines are shown on one.
+                    row.col(|ui| {
+                        ui.monospace(channel.calculation.replace('\n', " "))
+                            .on_hover_text(&channel.calculation);
+                    });
+                    let color = quality_color(&channel.quality);
+                    row.col(|ui| {
+                        ui.colored_label(color, format!("{}", channel.value));
+                    });
+                    row.col(|ui| {
+                        ui.colored_label(color, format!("{}", channel.quality))
+                            .on_hover_text(format!("{}", channel.quality));
+                    });
+                    row.col(|ui| {
+                        if let Some(error) = &channel.error {
+                            ui.colored_label(Color32::LIGHT_RED, error)
+                                .on_hover_text(error);
+                        }
+                    });
+                });
+            });
+    });
+
+    Ok(())
+}
+
+// Tokens of a calculation for the highlighting: strings, comments,
+// numbers and identifiers. Unterminated strings and comments run to the end.
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?s)"(?:[^"\\]|\\.)*"?|//[^\n]*|/\*.*?(?:\*/|$)|\d+(?:\.\d+)?(?:[eE][+-]?\d+)?|[A-Za-z_][A-Za-z0-9_]*"#,
+    )
+    .unwrap()
+});
+
+const KEYWORDS: [&str; 20] = [
+    "let", "const", "if", "else", "switch", "while", "loop", "for", "in", "do", "until", "break",
+    "continue", "return", "throw", "try", "catch", "fn", "true", "false",
+];
+
+// Draft of the calculation channels, sent to the polling thread when applied.
+pub struct CalculationEditor {
+    channels: Vec<CalculationChannel>,
+    loaded: bool,
+    modified: bool,
+    // Id of the calculation being edited.
+    selected: Option<usize>,
+    // Compiles the draft as it's typed.
+    engine: CalculationEngine,
+    recompile: bool,
+    // Device and channel count the draft was compiled with.
+    device_channels: usize,
+    // Character index of the cursor in the expression.
+    cursor: usize,
+    test_result: Option<Result<String, String>>,
+}
+
+impl Default for CalculationEditor {
+    fn default() -> Self {
+        // The editor only compiles, nothing receives the requests.
+        let (sender_script_call, _) = tokio::sync::mpsc::channel(1);
+        Self {
+            channels: Vec::new(),
+            loaded: false,
+            modified: false,
+            selected: None,
+            engine: CalculationEngine::new(ScriptModbus::periodic(sender_script_call)),
+            recompile: true,
+            device_channels: 0,
+            cursor: 0,
+            test_result: None,
+        }
+    }
+}
+
+impl CalculationEditor {
+    // The answer of the polling thread to Test Evaluate.
+    pub fn set_test_result(&mut self, result: Result<String, String>) {
+        self.test_result = Some(result);
+    }
+
+    fn revert(&mut self, channels: &[CalculationChannel]) {
+        self.channels = channels.to_vec();
+        self.loaded = true;
+        self.modified = false;
+        self.recompile = true;
+        self.test_result = None;
+        if !self
+            .channels
+            .iter()
+            .any(|channel| Some(channel.id) == self.selected)
+        {
+            self.selected = None;
+        }
+    }
+
+    fn add(&mut self, applied: &[CalculationChannel]) {
+        let id = self
+            .channels
+            .iter()
+            .chain(applied)
+            .map(|channel| channel.id)
+            .max()
+            .unwrap_or_default()
+            + 1;
+        self.channels.push(CalculationChannel {
+            enabled: true,
+            id,
+            name: format!("CH{id}"),
+            calculation: "0.0".to_owned(),
+            value: 0.0,
+            quality: ChannelQuality::NotYetRead,
+            error: None,
+        });
+        self.selected = Some(id);
+        self.changed();
+    }
+
+    fn changed(&mut self) {
+        self.modified = true;
+        self.recompile = true;
+        self.test_result = None;
+    }
+}
+
+// Create, rename, delete and edit the calculation channels.
+pub fn ui_calculation_editor(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
+
+    let header = format!(
+        "{} Calculation Editor ({} channels)",
+        egui_phosphor::regular::PENCIL_SIMPLE,
+        app.calculation_channels.len()
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("calculation_editor")
+        .show(ui, |ui| {
+            let editor = &mut app.calculation_editor;
+            if !editor.loaded {
+                editor.revert(&app.calculation_channels);
+            }
+
+            // Compile the draft against the configured device channels.
+            let device_channels = app
+                .modbus_devices
+                .iter()
+                .map(|device| device.channels.len() + 1)
+                .sum();
+            if editor.recompile || device_channels != editor.device_channels {
+                editor
+                    .engine
+                    .update_devices(&live_devices(&app.modbus_devices, &app.received_devices));
+                // Cycles are shown with each calculation.
+                editor.engine.configure(&editor.channels).ok();
+                editor.recompile = false;
+                editor.device_channels = device_channels;
+            }
+
+            ui.horizontal(|ui| {
+                if ui
+                    .button(format!("{} Add", egui_phosphor::regular::PLUS))
+                    .clicked()
+                {
+                    editor.add(&app.calculation_channels);
+                }
+
+                ui.add_enabled_ui(editor.modified, |ui| {
+                    // The polling thread compiles and evaluates the new channels.
+                    if ui
+                        .button(format!("{} Apply", egui_phosphor::regular::FLOPPY_DISK))
+                        .clicked()
+                    {
+                        let mut channels = editor.channels.clone();
+                        for channel in &mut channels {
+                            if let Some(current) = app
+                                .calculation_channels
+                                .iter()
+                                .find(|current| current.id == channel.id)
+                            {
+                                channel.value = current.value;
+                                channel.quality = current.quality.clone();
+                                channel.error = current.error.clone();
+                            }
+                        }
+
+                        result = app
+                            .sender_main_to_thread
+                            .try_send(ThreadCommand::Calculations(channels.clone()))
+                            .map_err(|e| anyhow::anyhow!("Calculation update error: {e}"));
+                        if result.is_ok() {
+                            app.calculation_channels = channels;
+                            editor.modified = false;
+                        }
+                    }
+                    if ui
+                        .button(format!(
+                            "{} Revert",
+                            egui_phosphor::regular::ARROW_COUNTER_CLOCKWISE
+                        ))
+                        .clicked()
+                    {
+                        editor.revert(&app.calculation_channels);
+                    }
+                });
+                if editor.modified {
+                    ui.weak("Not applied");
+                }
+            });
+            ui.separator();
+
+            ui_calculation_list(editor, ui);
+
+            if editor.selected.is_some() {
+                ui.separator();
+                ui_expression_editor(app, ui);
+            }
+        });
+
+    result
+}
+
+// One row per calculation with its compilation status.
+fn ui_calculation_list(editor: &mut CalculationEditor, ui: &mut egui::Ui) {
+    let mut removed = None;
+
+    egui::ScrollArea::vertical()
+        .id_salt("calculation_list")
+        .max_height(200.0)
+        .show(ui, |ui| {
+            egui::Grid::new("calculation_list")
+                .num_columns(5)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("ENABLED");
+                    ui.strong("NAME");
+                    ui.strong("STATUS");
+                    ui.strong("EDIT");
+                    ui.strong("DELETE");
+                    ui.end_row();
+
+                    for index in 0..editor.channels.len() {
+                        let id = editor.channels[index].id;
+                        let name_used = editor
+                            .channels
+                            .iter()
+                            .filter(|channel| channel.name == editor.channels[index].name)
+                            .count()
+                            > 1;
+
+                        let channel = &mut editor.channels[index];
+                        let mut changed = ui.checkbox(&mut channel.enabled, "").changed();
+                        changed |= ui
+                            .add(egui::TextEdit::singleline(&mut channel.name).desired_width(100.))
+                            .changed();
+
+                        // Calculations are referenced by name, so it has to be a unique identifier.
+                        let warning = if !is_tag_name(&channel.name) {
+                            Some("Not a valid tag name".to_owned())
+                        } else if name_used {
+                            Some("Another calculation has this name".to_owned())
+                        } else {
+                            editor.engine.check(id).err().map(|e| format!("{e}"))
+                        };
+                        match warning {
+                            Some(warning) => {
+                                ui.colored_label(
+                                    Color32::LIGHT_RED,
+                                    format!("{} Error", egui_phosphor::regular::WARNING),
+                                )
+                                .on_hover_text(warning);
+                            }
+                            None => {
+                                ui.colored_label(
+                                    Color32::LIGHT_GREEN,
+                                    format!("{} OK", egui_phosphor::regular::CHECK),
+                                );
+                            }
+                        }
+
+                        let selected = editor.selected == Some(id);
+                        if ui
+                            .selectable_label(selected, egui_phosphor::regular::PENCIL_SIMPLE)
+                            .clicked()
+                        {
+                            editor.selected = if selected { None } else { Some(id) };
+                            editor.test_result = None;
+                        }
+                        if ui.button(egui_phosphor::regular::TRASH).clicked() {
+                            removed = Some(index);
+                        }
+                        ui.end_row();
+
+                        if changed {
+                            editor.changed();
+                        }
+                    }
+                });
+        });
+
+    if let Some(index) = removed {
+        let channel = editor.channels.remove(index);
+        if editor.selected == Some(channel.id) {
+            editor.selected = None;
+        }
+        editor.changed();
+    }
+}
+
+// The expression of the selected calculation, with highlighting,
+// tag completion, the compilation error and a test evaluation.
+fn ui_expression_editor(app: &mut ColossalApp, ui: &mut egui::Ui) {
+    let (known, completions) = tag_names(&app.modbus_devices, &app.calculation_editor.channels);
+    let editor = &mut app.calculation_editor;
+    let Some(index) = editor
+        .channels
+        .iter()
+        .position(|channel| Some(channel.id) == editor.selected)
+    else {
+        return;
+    };
+    let id = editor.channels[index].id;
+
+    ui.label(format!("Expression of {}", editor.channels[index].name));
+    let error = editor.engine.check(id).err();
+    let error_offset = error
+        .as_ref()
+        .and_then(|e| e.position)
+        .and_then(|(line, column)| text_offset(&editor.channels[index].calculation, line, column));
+
+    let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+        let mut job = highlight(ui, text, &known, error_offset);
+        job.wrap.max_width = wrap_width;
+        ui.fonts(|fonts| fonts.layout_job(job))
+    };
+    let mut output = egui::TextEdit::multiline(&mut editor.channels[index].calculation)
+        .id_salt("calculation_expression")
+        .code_editor()
+        .desired_rows(4)
+        .desired_width(f32::INFINITY)
+        .layouter(&mut layouter)
+        .show(ui);
+    if output.response.changed() {
+        editor.changed();
+    }
+    if let Some(range) = output.cursor_range {
+        editor.cursor = range.primary.ccursor.index;
+    }
+
+    // Tags starting with the word before the cursor.
+    let calculation = &editor.channels[index].calculation;
+    let prefix = word_before(calculation, editor.cursor);
+    let mut completed = None;
+    if !prefix.is_empty() {
+        let matches: Vec<&String> = completions
+            .iter()
+            .filter(|tag| tag.starts_with(prefix) && tag.len() > prefix.len())
+            .take(8)
+            .collect();
+        if !matches.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                ui.weak("Complete");
+                for tag in matches {
+                    if ui.small_button(tag).clicked() {
+                        completed = Some(tag.clone());
+                    }
+                }
+            });
+        }
+    }
+    if let Some(tag) = completed {
+        let prefix_chars = prefix.chars().count();
+        let start = editor.cursor - prefix_chars;
+        let calculation = &mut editor.channels[index].calculation;
+        let byte = |chars: usize| {
+            calculation
+                .char_indices()
+                .nth(chars)
+                .map_or(calculation.len(), |(offset, _)| offset)
+        };
+        let range = byte(start)..byte(editor.cursor);
+        calculation.replace_range(range, &tag);
+
+        editor.cursor = start + tag.chars().count();
+        let cursor = CCursorRange::one(CCursor::new(editor.cursor));
+        output.state.cursor.set_char_range(Some(cursor));
+        output.state.store(ui.ctx(), output.response.id);
+        output.response.request_focus();
+        editor.changed();
+    }
+
+    match &error {
+        Some(CalculationError {
+            message,
+            position: Some((line, column)),
+        }) => {
+            ui.colored_label(
+                Color32::LIGHT_RED,
+                format!(
+                    "{} Line {line}, column {column}: {message}",
+                    egui_phosphor::regular::WARNING
+                ),
+            );
+        }
+        Some(error) => {
+            ui.colored_label(
+                Color32::LIGHT_RED,
+                format!("{} {error}", egui_phosphor::regular::WARNING),
+            );
+        }
+        None => {
+            ui.colored_label(
+                Color32::LIGHT_GREEN,
+                format!("{} Compiles", egui_phosphor::regular::CHECK),
+            );
+        }
+    }
+
+    ui.horizontal(|ui| {
+        if ui
+            .button(format!("{} Test Evaluate", egui_phosphor::regular::PLAY))
+            .on_hover_text("Evaluate with the current device values, without applying.")
+            .clicked()
+        {
+            // The other calculations are seen with their last results.
+            let mut channels = editor.channels.clone();
+            for channel in &mut channels {
+                if let Some(current) = app
+                    .calculation_channels
+                    .iter()
+                    .find(|current| current.id == channel.id)
+                {
+                    channel.value = current.value;
+                    channel.quality = current.quality.clone();
+                }
+            }
+            // Sent to the polling thread, the script may send requests.
+            editor.test_result = match app
+                .sender_main_to_thread
+                .try_send(ThreadCommand::TestCalculation(channels, index))
+            {
+                Ok(_) => Some(Ok("Evaluating...".to_owned())),
+                Err(e) => Some(Err(format!("Test error: {e}"))),
+            };
+        }
+
+        match &editor.test_result {
+            Some(Ok(value)) => {
+                ui.label(value);
+            }
+            Some(Err(e)) => {
+                ui.colored_label(Color32::LIGHT_RED, e);
+            }
+            None => {}
+        }
+    });
+}
+
+// The configured devices with their last polled values.
+fn live_devices(
+    devices: &[ModbusDevice],
+    received: &BTreeMap<usize, ModbusDevice>,
+) -> Vec<ModbusDevice> {
+    devices
+        .iter()
+        .map(|device| received.get(&device.id).unwrap_or(device).clone())
+        .collect()
+}
+
+// The names highlighted as tags, and the completions: qualified
+// channels and calculation names.
+fn tag_names(
+    devices: &[ModbusDevice],
+    calculations: &[CalculationChannel],
+) -> (BTreeSet<String>, Vec<String>) {
+    let mut known = BTreeSet::new();
+    let mut completions = Vec::new();
+
+    for device in devices {
+        known.insert(device.name.clone());
+        known.insert(device.code.clone());
+        for channel in &device.channels {
+            known.insert(channel.name.clone());
+            if is_tag_name(&channel.name) {
+                completions.push(format!("{}.{}", device.name, channel.name));
+            } else {
+                completions.push(format!("{}[\"{}\"]", device.name, channel.name));
+            }
+        }
+    }
+    for calculation in calculations {
+        known.insert(calculation.name.clone());
+        completions.push(calculation.name.clone());
+    }
+
+    (known, completions)
+}
+
+fn is_tag_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The tag characters right before a character index.
+fn word_before(text: &str, cursor: usize) -> &str {
+    let end = text
+        .char_indices()
+        .nth(cursor)
+        .map_or(text.len(), |(offset, _)| offset);
+    let start = text[..end]
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .map_or(0, |offset| offset + 1);
+    &text[start..end]
+}
+
+// Byte offset of a line and column, counted from 1.
+fn text_offset(text: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let line_text = text.get(line_start..)?.split('\n').next()?;
+    line_text
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|(offset, _)| line_start + offset)
+}
+
+// Color the calculation tokens, underlining the one with the error.
+fn highlight(
+    ui: &egui::Ui,
+    text: &str,
+    known: &BTreeSet<String>,
+    error_offset: Option<usize>,
+) -> LayoutJob {
+    let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+    let default_color = ui.visuals().text_color();
+    let mut job = LayoutJob::default();
+
+    let mut append = |range: std::ops::Range<usize>, color: Color32| {
+        if range.is_empty() {
+            return;
+        }
+        let mut format = TextFormat::simple(font_id.clone(), color);
+        if error_offset.is_some_and(|offset| range.contains(&offset)) {
+            format.underline = Stroke::new(1.5, Color32::LIGHT_RED);
+        }
+        job.append(&text[range], 0.0, format);
+    };
+
+    let mut end = 0;
+    for token in TOKEN.find_iter(text) {
+        append(end..token.start(), default_color);
+
+        let word = token.as_str();
+        let color = if word.starts_with('"') {
+            Color32::from_rgb(206, 145, 120)
+        } else if word.starts_with("//") || word.starts_with("/*") {
+            Color32::GRAY
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            Color32::from_rgb(181, 206, 168)
+        } else if KEYWORDS.contains(&word) {
+            Color32::from_rgb(86, 156, 214)
+        } else if known.contains(word) {
+            Color32::from_rgb(78, 201, 176)
+        } else {
+            default_color
+        };
+        append(token.range(), color);
+        end = token.end();
+    }
+    append(end..text.len(), default_color);
+
+    job
+}
@@ -0,0 +1,153 @@
+use crate::app::ThreadCommand;
+use crate::ColossalApp;
+use crate::{GatewayConfig, ModbusByteOrder, ModbusChannelType, ModbusRegisterSpace};
+
+// Gateway server settings and its register map.
+pub fn ui_gateway(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
+
+    let header = format!(
+        "{} Gateway ({} mappings)",
+        egui_phosphor::regular::SHARE_NETWORK,
+        app.gateway_config.mappings.len()
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("gateway")
+        .show(ui, |ui| {
+            let config = &mut app.gateway_config;
+
+            egui::Grid::new("gateway_config")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.checkbox(&mut config.enabled, "Enabled");
+                    ui.end_row();
+                    ui.label("Bind address");
+                    ui.text_edit_singleline(&mut config.bind_address)
+                        .on_hover_text(
+                            "127.0.0.1 for this computer only, 0.0.0.0 for every network.",
+                        );
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut config.port));
+                    ui.end_row();
+                    ui.checkbox(&mut config.forward_writes, "Forward writes to devices");
+                    ui.end_row();
+
+                    if ui
+                        .button(format!("{} Map All", egui_phosphor::regular::LIST_PLUS))
+                        .on_hover_text("Map every channel and calculation, replacing the map.")
+                        .clicked()
+                    {
+                        config.mappings =
+                            GatewayConfig::map_all(&app.modbus_devices, &app.calculation_channels);
+                    }
+
+                    // The server restarts with the new config.
+                    if ui
+                        .button(format!("{} Apply", egui_phosphor::regular::FLOPPY_DISK))
+                        .clicked()
+                    {
+                        result = app
+                            .sender_main_to_thread
+                            .try_send(ThreadCommand::GatewayConfig(config.clone()))
+                            .map_err(|e| anyhow::anyhow!("Gateway update error: {e}"));
+                    }
+                    ui.end_row();
+                });
+            ui.separator();
+
+            let mut removed = None;
+            egui::ScrollArea::vertical()
+                .id_salt("gateway_mappings")
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("gateway_mappings")
+                        .num_columns(7)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("SOURCE");
+                            ui.strong("REGISTER");
+                            ui.strong("ADDRESS");
+                            ui.strong("TYPE");
+                            ui.strong("ORDER");
+                            ui.strong("WRITABLE");
+                            ui.end_row();
+
+                            for (index, mapping) in config.mappings.iter_mut().enumerate() {
+                                ui.label(&mapping.name)
+                                    .on_hover_text(format!("{}", mapping.source));
+
+                                egui::ComboBox::from_id_salt(("gateway_space", index))
+                                    .selected_text(format!("{}", mapping.register_space))
+                                    .show_ui(ui, |ui| {
+                                        for register_space in [
+                                            ModbusRegisterSpace::Coil,
+                                            ModbusRegisterSpace::DiscreteInput,
+                                            ModbusRegisterSpace::InputRegister,
+                                            ModbusRegisterSpace::HoldingRegister,
+                                        ] {
+                                            ui.selectable_value(
+                                                &mut mapping.register_space,
+                                                register_space,
+                                                format!("{register_space}"),
+                                            );
+                                        }
+                                    });
+
+                                ui.add(egui::DragValue::new(&mut mapping.address));
+
+                                egui::ComboBox::from_id_salt(("gateway_type", index))
+                                    .selected_text(format!("{}", mapping.channel_type))
+                                    .show_ui(ui, |ui| {
+                                        for channel_type in [
+                                            ModbusChannelType::Bool,
+                                            ModbusChannelType::U16,
+                                            ModbusChannelType::I16,
+                                            ModbusChannelType::U32,
+                                            ModbusChannelType::I32,
+                                            ModbusChannelType::F32,
+                                            ModbusChannelType::F64,
+                                        ] {
+                                            let text = format!("{channel_type}");
+                                            ui.selectable_value(
+                                                &mut mapping.channel_type,
+                                                channel_type,
+                                                text,
+                                            );
+                                        }
+                                    });
+
+                                egui::ComboBox::from_id_salt(("gateway_order", index))
+                                    .selected_text(format!("{}", mapping.byte_order))
+                                    .show_ui(ui, |ui| {
+                                        for byte_order in [
+                                            ModbusByteOrder::Abcd,
+                                            ModbusByteOrder::Cdab,
+                                            ModbusByteOrder::Badc,
+                                            ModbusByteOrder::Dcba,
+                                        ] {
+                                            ui.selectable_value(
+                                                &mut mapping.byte_order,
+                                                byte_order,
+                                                format!("{byte_order}"),
+                                            );
+                                        }
+                                    });
+
+                                ui.checkbox(&mut mapping.writable, "");
+
+                                if ui.button(egui_phosphor::regular::TRASH).clicked() {
+                                    removed = Some(index);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+
+            if let Some(index) = removed {
+                config.mappings.remove(index);
+            }
+        });
+
+    result
+}
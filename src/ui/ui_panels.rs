@@ -1,10 +1,15 @@
 use egui::{Color32, Frame, Margin, Sense, Stroke};
 use egui_extras::{Column, TableBuilder};
 
-use crate::ui::ModbusDeviceType;
+use crate::app::{ChannelSettings, ModbusWrite, ThreadCommand};
+use crate::ui::{
+    ModbusChannelBuffer, ModbusDeviceBuffer, ModbusDeviceType, ScalingType, ScanClassBuffer,
+};
 use crate::ColossalApp;
+use crate::{exception_description, ChannelQuality, SerialDataBits, SerialParity, SerialStopBits};
 
 pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
     let table_frame = Frame {
         stroke: Stroke::new(1.0, Color32::LIGHT_YELLOW),
         inner_margin: Margin::symmetric(10, 10),
@@ -22,6 +27,59 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
         });
         ui.separator();
 
+        // Device selection, the config and table follow the selected device.
+        let selected_name = app
+            .modbus_devices
+            .iter()
+            .find(|device| device.id == app.selected_device)
+            .map(|device| device.name.clone())
+            .unwrap_or_default();
+        let mut selected_device = app.selected_device;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("Device")
+                .selected_text(selected_name)
+                .show_ui(ui, |ui| {
+                    for device in &app.modbus_devices {
+                        ui.selectable_value(&mut selected_device, device.id, &device.name);
+                    }
+                });
+
+            // Vendor, product and revision read with FC43/14.
+            let identification = app
+                .received_devices
+                .get(&app.selected_device)
+                .and_then(|device| device.identification.as_ref());
+            match identification {
+                Some(Ok(identification)) => {
+                    let details: Vec<String> = identification
+                        .objects
+                        .iter()
+                        .map(|(id, value)| format!("{id:#04X}: {value}"))
+                        .collect();
+                    ui.label(format!(
+                        "{} {identification}",
+                        egui_phosphor::regular::IDENTIFICATION_CARD
+                    ))
+                    .on_hover_text(details.join("\n"));
+                }
+                Some(Err(e)) => {
+                    ui.weak("No identification").on_hover_text(e);
+                }
+                None => {}
+            }
+        });
+        if selected_device != app.selected_device {
+            app.selected_device = selected_device;
+            app.tabel_selected_row = None;
+            if let Some(device) = app
+                .modbus_devices
+                .iter()
+                .find(|device| device.id == selected_device)
+            {
+                app.device_config_ui_buffer = ModbusDeviceBuffer::from_device(device);
+            }
+        }
+
         ui.collapsing("Device Config", |ui| {
             egui::Grid::new("device_config")
                 .num_columns(4)
@@ -51,17 +109,135 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
                             ui.label("Port");
                             ui.text_edit_singleline(&mut app.device_config_ui_buffer.port);
                         }
-                        ModbusDeviceType::Serial => {}
+                        ModbusDeviceType::Serial => {
+                            let buffer = &mut app.device_config_ui_buffer;
+
+                            ui.label("Port");
+                            ui.text_edit_singleline(&mut buffer.serial_port);
+                            ui.end_row();
+                            ui.label("Baud Rate");
+                            ui.text_edit_singleline(&mut buffer.baud_rate);
+                            ui.end_row();
+                            egui::ComboBox::from_label("Parity")
+                                .selected_text(format!("{}", buffer.parity))
+                                .show_ui(ui, |ui| {
+                                    for parity in
+                                        [SerialParity::None, SerialParity::Even, SerialParity::Odd]
+                                    {
+                                        ui.selectable_value(
+                                            &mut buffer.parity,
+                                            parity,
+                                            format!("{parity}"),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            egui::ComboBox::from_label("Data Bits")
+                                .selected_text(format!("{}", buffer.data_bits))
+                                .show_ui(ui, |ui| {
+                                    for data_bits in [SerialDataBits::Seven, SerialDataBits::Eight]
+                                    {
+                                        ui.selectable_value(
+                                            &mut buffer.data_bits,
+                                            data_bits,
+                                            format!("{data_bits}"),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            egui::ComboBox::from_label("Stop Bits")
+                                .selected_text(format!("{}", buffer.stop_bits))
+                                .show_ui(ui, |ui| {
+                                    for stop_bits in [SerialStopBits::One, SerialStopBits::Two] {
+                                        ui.selectable_value(
+                                            &mut buffer.stop_bits,
+                                            stop_bits,
+                                            format!("{stop_bits}"),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Frame Delay (ms)");
+                            ui.text_edit_singleline(&mut buffer.frame_delay_ms);
+                        }
+                    }
+                    ui.end_row();
+                    ui.label("Unit ID");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.unit_id);
+                    ui.end_row();
+                    ui.label("Poll Interval (ms)");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.poll_interval_ms);
+                    ui.end_row();
+                    // Named intervals the channels can be assigned to.
+                    let mut removed_scan_class = None;
+                    for (index, scan_class) in app
+                        .device_config_ui_buffer
+                        .scan_classes
+                        .iter_mut()
+                        .enumerate()
+                    {
+                        ui.label(format!("Scan Class {}", index + 1));
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut scan_class.name)
+                                    .hint_text("Name")
+                                    .desired_width(90.),
+                            );
+                            ui.add(
+                                egui::TextEdit::singleline(&mut scan_class.interval_ms)
+                                    .hint_text("Interval (ms)")
+                                    .desired_width(70.),
+                            );
+                            if ui.button(egui_phosphor::regular::TRASH).clicked() {
+                                removed_scan_class = Some(index);
+                            }
+                        });
+                        ui.end_row();
+                    }
+                    if let Some(index) = removed_scan_class {
+                        app.device_config_ui_buffer.scan_classes.remove(index);
+                    }
+                    if ui
+                        .button(format!("{} Add Scan Class", egui_phosphor::regular::PLUS))
+                        .clicked()
+                    {
+                        app.device_config_ui_buffer
+                            .scan_classes
+                            .push(ScanClassBuffer::default());
                     }
                     ui.end_row();
+                    ui.label("Max Block Size");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.max_block_size);
+                    ui.end_row();
+                    ui.label("Max Gap");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.max_gap);
+                    ui.end_row();
+                    ui.label("Timeout (ms)");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.timeout_ms);
+                    ui.end_row();
+                    ui.label("Retries");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.retries);
+                    ui.end_row();
+                    ui.label("Reconnect Delay (ms)");
+                    ui.text_edit_singleline(&mut app.device_config_ui_buffer.reconnect_delay_ms);
+                    ui.end_row();
+                    ui.checkbox(
+                        &mut app.device_config_ui_buffer.use_multiple_write,
+                        "Write with FC15/FC16 only",
+                    );
+                    ui.end_row();
+                    ui.checkbox(
+                        &mut app.device_config_ui_buffer.read_back_writes,
+                        "Read back writes with FC23",
+                    );
+                    ui.end_row();
 
                     // If clicked we update the device config.
                     if ui
                         .button(format!("{} Save", egui_phosphor::regular::FLOPPY_DISK))
                         .clicked()
                     {
-                        let port = app.device_config_ui_buffer.port.parse::<usize>();
-                        if let Ok(port) = port {}
+                        result = send_device_config(app);
                     }
                 });
         });
@@ -79,6 +255,10 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
             .column(Column::exact(80.))
             .column(Column::exact(80.))
             .column(Column::exact(80.))
+            .column(Column::exact(80.))
+            .column(Column::exact(80.))
+            .column(Column::exact(80.))
+            .column(Column::exact(100.))
             .column(Column::remainder())
             .vscroll(true)
             .auto_shrink(false)
@@ -89,6 +269,8 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
         // The table rows should sense the user clicks for selection.
         device_channels_table = device_channels_table.sense(Sense::click());
 
+        // Scan classes picked in the table, sent once it is drawn.
+        let mut scan_class_change: Option<ChannelSettings> = None;
         device_channels_table
             .header(30.0, |mut header| {
                 header.col(|ui| {
@@ -97,22 +279,34 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
                 header.col(|ui| {
                     ui.strong("NAME");
                 });
+                header.col(|ui| {
+                    ui.strong("REGISTER");
+                });
                 header.col(|ui| {
                     ui.strong("TYPE");
                 });
+                header.col(|ui| {
+                    ui.strong("ORDER");
+                });
                 header.col(|ui| {
                     ui.strong("ADDRESS");
                 });
                 header.col(|ui| {
                     ui.strong("VALUE");
                 });
+                header.col(|ui| {
+                    ui.strong("QUALITY");
+                });
+                header.col(|ui| {
+                    ui.strong("SCAN CLASS");
+                });
                 header.col(|ui| {
                     ui.strong("DESCRIPTION");
                 });
             })
             .body(|body| {
                 let row_height = 20.0;
-                if let Some(received_device_data) = &app.received_device_data {
+                if let Some(received_device_data) = app.received_devices.get(&app.selected_device) {
                     let num_channel_rows = received_device_data.channels.len();
 
                     body.rows(row_height, num_channel_rows, |mut row| {
@@ -132,30 +326,262 @@ pub fn ui_device_channels_table(app: &mut ColossalApp, ui: &mut egui::Ui) -> any
                         row.col(|ui| {
                             ui.label(format!("{device_channel}"));
                         });
+                        // Channel register space
+                        row.col(|ui| {
+                            ui.label(format!("{}", &device_channel.register_space));
+                        });
                         // Channel type
                         row.col(|ui| {
                             ui.label(format!("{}", &device_channel.channel_type));
                         });
+                        // Channel byte order
+                        row.col(|ui| {
+                            ui.label(format!("{}", &device_channel.byte_order));
+                        });
                         row.col(|ui| {
                             ui.label(format!("{}", &device_channel.address));
                         });
+                        // Values are colored by their quality.
+                        let color = quality_color(&device_channel.quality);
+                        row.col(|ui| {
+                            ui.colored_label(color, device_channel.display_value());
+                        });
+                        row.col(|ui| {
+                            ui.colored_label(color, format!("{}", device_channel.quality))
+                                .on_hover_text(format!("{}", device_channel.quality));
+                        });
                         row.col(|ui| {
-                            ui.label(format!("{}", &device_channel.value));
+                            let mut scan_class = device_channel.scan_class.clone();
+                            egui::ComboBox::from_id_salt(("scan_class", index))
+                                .selected_text(scan_class.as_deref().unwrap_or("Device"))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut scan_class, None, "Device");
+                                    for class in &received_device_data.scan_config.scan_classes {
+                                        ui.selectable_value(
+                                            &mut scan_class,
+                                            Some(class.name.clone()),
+                                            &class.name,
+                                        );
+                                    }
+                                });
+                            if scan_class != device_channel.scan_class {
+                                scan_class_change = Some(ChannelSettings {
+                                    scan_class,
+                                    ..ChannelSettings::from_channel(device_channel)
+                                });
+                            }
                         });
                         row.col(|ui| {
-                            ui.label(format!("{}", &device_channel.description));
+                            ui.label(&device_channel.description);
                         });
 
                         if row.response().clicked() {
                             app.tabel_selected_row = Some(index);
+                            app.channel_config_ui_buffer =
+                                ModbusChannelBuffer::from_channel(device_channel);
                         }
                     });
                 }
             });
+
+        if let Some(settings) = scan_class_change {
+            result = send_channel_settings(app, settings);
+        }
     });
+    result
+}
+
+// Exception counts and log of the selected device.
+pub fn ui_device_diagnostics(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    let Some(device) = app.received_devices.get(&app.selected_device) else {
+        return result;
+    };
+
+    let header = format!(
+        "{} Diagnostics ({} requests, {} exceptions)",
+        egui_phosphor::regular::STETHOSCOPE,
+        device.diagnostics.stats.requests,
+        device.diagnostics.total()
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("device_diagnostics")
+        .show(ui, |ui| {
+            let stats = &device.diagnostics.stats;
+            egui::Grid::new("comm_stats")
+                .num_columns(4)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("Requests");
+                    ui.label(format!("{}", stats.requests));
+                    ui.label("Successes");
+                    ui.label(format!("{}", stats.successes));
+                    ui.end_row();
+                    ui.label("Exceptions");
+                    ui.label(format!("{}", stats.exceptions));
+                    ui.label("Timeouts");
+                    ui.label(format!("{}", stats.timeouts));
+                    ui.end_row();
+                    ui.label("Failures");
+                    ui.label(format!("{}", stats.failures));
+                    ui.label("Reconnects");
+                    ui.label(format!("{}", stats.reconnects()));
+                    ui.end_row();
+                    ui.label("Latency Min");
+                    ui.label(format_latency(stats.latency_min));
+                    ui.label("Latency Avg");
+                    ui.label(format_latency(stats.latency_avg()));
+                    ui.end_row();
+                    ui.label("Latency Max");
+                    ui.label(format_latency(stats.latency_max));
+                    ui.label("Latency P95");
+                    ui.label(format_latency(stats.latency_p95()));
+                    ui.end_row();
+                    ui.label("Last Connected");
+                    ui.label(format_age(stats.last_connected));
+                    ui.end_row();
+                });
+            ui.separator();
+
+            egui::Grid::new("exception_counts")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    for (code, count) in &device.diagnostics.counts {
+                        ui.label(format!("{} ({code:#04X})", exception_description(*code)));
+                        ui.label(format!("{count}"));
+                        ui.end_row();
+                    }
+                });
+
+            if ui
+                .button(format!("{} Clear", egui_phosphor::regular::TRASH))
+                .clicked()
+            {
+                result = app
+                    .sender_main_to_thread
+                    .try_send(ThreadCommand::ClearDiagnostics(device.id))
+                    .map_err(|e| anyhow::anyhow!("Clear diagnostics error: {e}"));
+            }
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("exception_log")
+                        .num_columns(5)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("TIME");
+                            ui.strong("SOURCE");
+                            ui.strong("FC");
+                            ui.strong("ADDRESS");
+                            ui.strong("EXCEPTION");
+                            ui.end_row();
+
+                            for entry in &device.diagnostics.log {
+                                let exception = &entry.exception;
+                                ui.label(format_age(Some(exception.time)));
+                                ui.label(&entry.source);
+                                ui.label(format!("{:02}", exception.function_code));
+                                ui.label(format!("{}", exception.address));
+                                ui.label(exception.description());
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+
+    result
+}
+
+// Text color of a value with the given quality.
+pub fn quality_color(quality: &ChannelQuality) -> Color32 {
+    match quality {
+        ChannelQuality::Good => Color32::LIGHT_GREEN,
+        ChannelQuality::Uncertain(_) => Color32::YELLOW,
+        ChannelQuality::Bad(_) => Color32::LIGHT_RED,
+        ChannelQuality::Stale | ChannelQuality::NotYetRead => Color32::GRAY,
+    }
+}
+
+// A round trip time in milliseconds, "-" before the first answer.
+fn format_latency(latency: Option<std::time::Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.1} ms", latency.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    }
+}
+
+// How long ago a timestamp was, e.g. "1.5 s ago".
+fn format_age(time: Option<std::time::SystemTime>) -> String {
+    match time.map(|time| time.elapsed()) {
+        None => "Never".to_owned(),
+        Some(Ok(age)) => format!("{:.1} s ago", age.as_secs_f64()),
+        // The clock went back since.
+        Some(Err(_)) => "Just now".to_owned(),
+    }
+}
+
+// Parse the config buffer and send it to the polling thread.
+fn send_device_config(app: &mut ColossalApp) -> anyhow::Result<()> {
+    let device_id = app.selected_device;
+    let config = app.device_config_ui_buffer.to_device_config()?;
+    let block_config = app.device_config_ui_buffer.to_block_config()?;
+    let poll_interval_ms = app.device_config_ui_buffer.to_poll_interval_ms()?;
+    let scan_classes = app.device_config_ui_buffer.to_scan_classes()?;
+    let request_config = app.device_config_ui_buffer.to_request_config()?;
+
+    // Keep our copy in sync so the buffer reloads the saved config.
+    let device = app
+        .modbus_devices
+        .iter_mut()
+        .find(|device| device.id == device_id)
+        .ok_or_else(|| anyhow::anyhow!("Unknown device {device_id}"))?;
+    device.config = config.clone();
+    device.block_config = block_config.clone();
+    device.scan_config.poll_interval_ms = poll_interval_ms;
+    device.scan_config.scan_classes = scan_classes;
+    device.request_config = request_config.clone();
+    let scan_config = device.scan_config.clone();
+
+    for command in [
+        ThreadCommand::DeviceConfig(device_id, config),
+        ThreadCommand::BlockConfig(device_id, block_config),
+        ThreadCommand::ScanConfig(device_id, scan_config),
+        ThreadCommand::RequestConfig(device_id, request_config),
+    ] {
+        app.sender_main_to_thread
+            .try_send(command)
+            .map_err(|e| anyhow::anyhow!("Config update error: {e}"))?;
+    }
+
     Ok(())
 }
 
+// Send new channel settings to the polling thread.
+fn send_channel_settings(app: &mut ColossalApp, settings: ChannelSettings) -> anyhow::Result<()> {
+    let device_id = app.selected_device;
+
+    // Keep our copies in sync until the next poll comes in.
+    let local_devices = app
+        .modbus_devices
+        .iter_mut()
+        .chain(app.received_devices.values_mut());
+    for device in local_devices.filter(|device| device.id == device_id) {
+        if let Some(channel) = device
+            .channels
+            .iter_mut()
+            .find(|channel| channel.id == settings.channel_id)
+        {
+            settings.apply(channel);
+        }
+    }
+
+    app.sender_main_to_thread
+        .try_send(ThreadCommand::ChannelSettings(device_id, settings))
+        .map_err(|e| anyhow::anyhow!("Channel settings error: {e}"))
+}
+
 // Top colored status panel to show error messages.
 pub fn ui_status_panel(app: &mut ColossalApp, ctx: &egui::Context) -> anyhow::Result<()> {
     egui::TopBottomPanel::top("status_panel")
@@ -163,20 +589,220 @@ pub fn ui_status_panel(app: &mut ColossalApp, ctx: &egui::Context) -> anyhow::Re
         .frame(app.status_bar_frame)
         .show(ctx, |ui| {
             ui.vertical_centered_justified(|ui| {
-                ui.label(
-                    egui::RichText::new(format!("{}", app.thread_status,)).color(Color32::WHITE),
-                );
+                ui.label(egui::RichText::new(app.thread_status.to_string()).color(Color32::WHITE));
             });
         });
     Ok(())
 }
 
-// Right panel to show details
+// Right panel to show details and write to the selected channel
 pub fn ui_right_panel(app: &mut ColossalApp, ctx: &egui::Context) -> anyhow::Result<()> {
+    let mut result = Ok(());
     egui::SidePanel::right("right")
         .min_width(200.)
-        .show(ctx, |_ui| {
-            if let Some(_selected_channel_index) = app.tabel_selected_row {}
+        .show(ctx, |ui| {
+            let Some(selected_channel_index) = app.tabel_selected_row else {
+                return;
+            };
+            let Some(channel) = app
+                .received_devices
+                .get(&app.selected_device)
+                .and_then(|device| device.channels.get(selected_channel_index))
+                .cloned()
+            else {
+                return;
+            };
+
+            ui.vertical_centered_justified(|ui| {
+                ui.label(format!("{} Channel", egui_phosphor::regular::INFO));
+            });
+            ui.separator();
+
+            egui::Grid::new("channel_details")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Name");
+                    ui.label(&channel.name);
+                    ui.end_row();
+                    ui.label("Register");
+                    ui.label(format!("{}", channel.register_space));
+                    ui.end_row();
+                    ui.label("Type");
+                    ui.label(format!("{}", channel.channel_type));
+                    ui.end_row();
+                    ui.label("Address");
+                    ui.label(format!("{}", channel.address));
+                    ui.end_row();
+                    ui.label("Scan Class");
+                    ui.label(channel.scan_class.as_deref().unwrap_or("Device"));
+                    ui.end_row();
+                    ui.label("Scaling");
+                    ui.label(format!("{}", channel.scaling));
+                    ui.end_row();
+                    ui.label("Value");
+                    ui.label(channel.display_value());
+                    ui.end_row();
+                    ui.label("Quality");
+                    ui.colored_label(
+                        quality_color(&channel.quality),
+                        format!("{}", channel.quality),
+                    );
+                    ui.end_row();
+                    ui.label("Source Time");
+                    ui.label(format_age(channel.source_time));
+                    ui.end_row();
+                    ui.label("Receive Time");
+                    ui.label(format_age(channel.receive_time));
+                    ui.end_row();
+                    ui.label("Exceptions");
+                    ui.label(format!("{}", channel.exception_count));
+                    ui.end_row();
+                    if let Some(exception) = &channel.last_exception {
+                        ui.label("Last Exception");
+                        ui.label(format!("{exception}"));
+                        ui.end_row();
+                    }
+                });
+            ui.separator();
+
+            ui.collapsing("Settings", |ui| {
+                let buffer = &mut app.channel_config_ui_buffer;
+                egui::Grid::new("channel_settings")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Scaling");
+                        egui::ComboBox::from_id_salt("channel_scaling")
+                            .selected_text(format!("{}", buffer.scaling_type))
+                            .show_ui(ui, |ui| {
+                                for scaling_type in [
+                                    ScalingType::None,
+                                    ScalingType::Linear,
+                                    ScalingType::GainOffset,
+                                ] {
+                                    let text = format!("{scaling_type}");
+                                    ui.selectable_value(
+                                        &mut buffer.scaling_type,
+                                        scaling_type,
+                                        text,
+                                    );
+                                }
+                            });
+                        ui.end_row();
+                        match buffer.scaling_type {
+                            ScalingType::None => {}
+                            ScalingType::Linear => {
+                                ui.label("Raw Min");
+                                ui.text_edit_singleline(&mut buffer.raw_min);
+                                ui.end_row();
+                                ui.label("Raw Max");
+                                ui.text_edit_singleline(&mut buffer.raw_max);
+                                ui.end_row();
+                                ui.label("EU Min");
+                                ui.text_edit_singleline(&mut buffer.eu_min);
+                                ui.end_row();
+                                ui.label("EU Max");
+                                ui.text_edit_singleline(&mut buffer.eu_max);
+                                ui.end_row();
+                            }
+                            ScalingType::GainOffset => {
+                                ui.label("Gain");
+                                ui.text_edit_singleline(&mut buffer.gain);
+                                ui.end_row();
+                                ui.label("Offset");
+                                ui.text_edit_singleline(&mut buffer.offset);
+                                ui.end_row();
+                            }
+                        }
+                        ui.checkbox(&mut buffer.use_clamp, "Clamp");
+                        ui.end_row();
+                        if buffer.use_clamp {
+                            ui.label("Clamp Min");
+                            ui.text_edit_singleline(&mut buffer.clamp_min);
+                            ui.end_row();
+                            ui.label("Clamp Max");
+                            ui.text_edit_singleline(&mut buffer.clamp_max);
+                            ui.end_row();
+                        }
+                        ui.label("Unit");
+                        ui.text_edit_singleline(&mut buffer.unit);
+                        ui.end_row();
+                    });
+
+                if ui
+                    .button(format!("{} Save", egui_phosphor::regular::FLOPPY_DISK))
+                    .clicked()
+                {
+                    result = app
+                        .channel_config_ui_buffer
+                        .to_channel_settings(&channel)
+                        .and_then(|settings| send_channel_settings(app, settings));
+                }
+            });
+            ui.separator();
+
+            if !channel.register_space.is_writable() {
+                ui.label(format!("{} is read-only.", channel.register_space));
+                return;
+            }
+
+            if channel.unit.is_empty() {
+                ui.label("New Value");
+            } else {
+                ui.label(format!("New Value ({})", channel.unit));
+            }
+            ui.text_edit_singleline(&mut app.write_value_buffer);
+            if ui
+                .button(format!("{} Write", egui_phosphor::regular::PENCIL_SIMPLE))
+                .clicked()
+            {
+                match channel.parse_value(&app.write_value_buffer) {
+                    Ok(value) => {
+                        app.write_error = None;
+                        app.pending_write = Some(ModbusWrite {
+                            device_id: app.selected_device,
+                            channel_id: channel.id,
+                            channel_name: channel.name.clone(),
+                            value,
+                        });
+                    }
+                    Err(e) => app.write_error = Some(format!("{e}")),
+                }
+            }
+            if let Some(e) = &app.write_error {
+                ui.colored_label(Color32::LIGHT_RED, e);
+            }
         });
-    Ok(())
+
+    // Nothing is written until the user confirms.
+    if let Some(write) = app.pending_write.clone() {
+        let modal = egui::Modal::new(egui::Id::new("write_confirmation")).show(ctx, |ui| {
+            ui.label(format!("Write {} to {}?", write.value, write.channel_name));
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui
+                    .button(format!("{} Write", egui_phosphor::regular::CHECK))
+                    .clicked()
+                {
+                    if let Err(e) = app
+                        .sender_main_to_thread
+                        .try_send(ThreadCommand::Write(write.clone()))
+                    {
+                        app.write_error = Some(format!("Write error: {e}"));
+                    }
+                    app.pending_write = None;
+                }
+                if ui
+                    .button(format!("{} Cancel", egui_phosphor::regular::X))
+                    .clicked()
+                {
+                    app.pending_write = None;
+                }
+            });
+        });
+        if modal.should_close() {
+            app.pending_write = None;
+        }
+    }
+
+    result
 }
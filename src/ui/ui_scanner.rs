@@ -0,0 +1,352 @@
+use std::collections::BTreeSet;
+
+use egui::{Color32, Sense};
+use egui_extras::{Column, TableBuilder};
+
+use crate::app::ThreadCommand;
+use crate::ColossalApp;
+use crate::{
+    candidate_decodings, exception_description, ModbusByteOrder, ModbusChannel, ModbusChannelType,
+    ModbusRegisterSpace, ScanEvent, ScanOutcome, ScanResult, ScannerHandle,
+};
+
+// Results of the register scanner, not saved.
+#[derive(Default)]
+pub struct ScannerState {
+    pub handle: Option<ScannerHandle>,
+    pub results: Vec<ScanResult>,
+    // (done, total) request chunks.
+    pub progress: (usize, usize),
+    pub status: Option<String>,
+    pub hide_exceptions: bool,
+    // Indexes into the results.
+    pub checked: BTreeSet<usize>,
+    pub clicked: Option<usize>,
+}
+
+// Probe an endpoint for responding addresses and turn them into channels.
+pub fn ui_scanner(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
+
+    // Collect what the scan thread found since the last frame.
+    if let Some(handle) = &app.scanner.handle {
+        let mut finished = false;
+        for event in handle.receiver.try_iter() {
+            match event {
+                ScanEvent::Result(scan_result) => app.scanner.results.push(scan_result),
+                ScanEvent::Progress { done, total } => app.scanner.progress = (done, total),
+                ScanEvent::UnitSilent(unit_id) => {
+                    app.scanner.status = Some(format!("Unit {unit_id} didn't answer."))
+                }
+                ScanEvent::Finished(error) => {
+                    app.scanner.status = error.or(Some("Scan finished.".to_owned()));
+                    finished = true;
+                }
+            }
+        }
+        if finished {
+            app.scanner.handle = None;
+        }
+    }
+
+    let scanning = app.scanner.handle.is_some();
+    let header = format!(
+        "{} Scanner ({} addresses)",
+        egui_phosphor::regular::MAGNIFYING_GLASS,
+        app.scanner.results.len()
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("scanner")
+        .show(ui, |ui| {
+            let buffer = &mut app.scanner_buffer;
+            egui::Grid::new("scanner_config")
+                .num_columns(4)
+                .show(ui, |ui| {
+                    ui.label("IP");
+                    ui.text_edit_singleline(&mut buffer.ip);
+                    ui.label("Port");
+                    ui.text_edit_singleline(&mut buffer.port);
+                    ui.end_row();
+                    ui.label("First Unit ID");
+                    ui.text_edit_singleline(&mut buffer.first_unit_id);
+                    ui.label("Last Unit ID");
+                    ui.text_edit_singleline(&mut buffer.last_unit_id);
+                    ui.end_row();
+                    ui.label("Start Address");
+                    ui.text_edit_singleline(&mut buffer.start_address);
+                    ui.label("End Address");
+                    ui.text_edit_singleline(&mut buffer.end_address);
+                    ui.end_row();
+                    ui.label("Chunk Size");
+                    ui.text_edit_singleline(&mut buffer.chunk_size);
+                    ui.label("Timeout (ms)");
+                    ui.text_edit_singleline(&mut buffer.timeout_ms);
+                    ui.end_row();
+                    ui.checkbox(&mut buffer.coils, "Coils");
+                    ui.checkbox(&mut buffer.discrete_inputs, "Discrete Inputs");
+                    ui.checkbox(&mut buffer.input_registers, "Input Registers");
+                    ui.checkbox(&mut buffer.holding_registers, "Holding Registers");
+                    ui.end_row();
+                });
+
+            ui.horizontal(|ui| {
+                if scanning {
+                    if ui
+                        .button(format!("{} Cancel", egui_phosphor::regular::STOP))
+                        .clicked()
+                    {
+                        app.scanner.handle = None;
+                        app.scanner.status = Some("Scan cancelled.".to_owned());
+                    }
+                } else if ui
+                    .button(format!("{} Scan", egui_phosphor::regular::PLAY))
+                    .clicked()
+                {
+                    match app.scanner_buffer.to_scanner_config() {
+                        Ok(config) => {
+                            app.scanner = ScannerState {
+                                handle: Some(ScannerHandle::spawn(config)),
+                                hide_exceptions: app.scanner.hide_exceptions,
+                                ..Default::default()
+                            };
+                        }
+                        Err(e) => result = Err(e),
+                    }
+                }
+                ui.checkbox(&mut app.scanner.hide_exceptions, "Hide exceptions");
+
+                let (done, total) = app.scanner.progress;
+                if total > 0 {
+                    ui.add(
+                        egui::ProgressBar::new(done as f32 / total as f32)
+                            .desired_width(150.0)
+                            .show_percentage(),
+                    );
+                }
+            });
+            if let Some(status) = &app.scanner.status {
+                ui.label(status);
+            }
+            ui.separator();
+
+            ui_scan_results(app, ui);
+            ui.separator();
+
+            if let Err(e) = ui_scan_channels(app, ui) {
+                result = Err(e);
+            }
+        });
+
+    result
+}
+
+fn ui_scan_results(app: &mut ColossalApp, ui: &mut egui::Ui) {
+    let scanner = &mut app.scanner;
+    let rows: Vec<usize> = scanner
+        .results
+        .iter()
+        .enumerate()
+        .filter(|(_, scan_result)| {
+            !(scanner.hide_exceptions && matches!(scan_result.outcome, ScanOutcome::Exception(_)))
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    TableBuilder::new(ui)
+        .id_salt("scan_results")
+        .striped(true)
+        .resizable(false)
+        .sense(Sense::click())
+        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+        .column(Column::exact(30.))
+        .column(Column::exact(60.))
+        .column(Column::exact(80.))
+        .column(Column::exact(80.))
+        .column(Column::remainder())
+        .vscroll(true)
+        .auto_shrink(false)
+        .max_scroll_height(200.0)
+        .header(20.0, |mut header| {
+            header.col(|_| {});
+            header.col(|ui| {
+                ui.strong("UNIT");
+            });
+            header.col(|ui| {
+                ui.strong("REGISTER");
+            });
+            header.col(|ui| {
+                ui.strong("ADDRESS");
+            });
+            header.col(|ui| {
+                ui.strong("VALUE");
+            });
+        })
+        .body(|body| {
+            body.rows(20.0, rows.len(), |mut row| {
+                let index = rows[row.index()];
+                let scan_result = &scanner.results[index];
+                row.set_selected(scanner.clicked == Some(index));
+
+                row.col(|ui| {
+                    let mut checked = scanner.checked.contains(&index);
+                    if ui.checkbox(&mut checked, "").changed() {
+                        if checked {
+                            scanner.checked.insert(index);
+                        } else {
+                            scanner.checked.remove(&index);
+                        }
+                    }
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", scan_result.unit_id));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", scan_result.register_space));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", scan_result.address));
+                });
+                row.col(|ui| match scan_result.outcome {
+                    ScanOutcome::Exception(code) => {
+                        ui.colored_label(Color32::LIGHT_RED, format!("{}", scan_result.outcome))
+                            .on_hover_text(exception_description(code));
+                    }
+                    _ => {
+                        ui.label(format!("{}", scan_result.outcome));
+                    }
+                });
+
+                if row.response().clicked() {
+                    scanner.clicked = Some(index);
+                }
+            });
+        });
+}
+
+// Candidate decodings of the clicked address and the buttons
+// adding channels to the selected device.
+fn ui_scan_channels(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut new_channels = Vec::new();
+
+    if let Some(clicked) = app
+        .scanner
+        .clicked
+        .and_then(|index| app.scanner.results.get(index))
+    {
+        // The clicked word and the one after it, if that answered too.
+        let word_at = |address: u16| {
+            app.scanner.results.iter().find_map(|scan_result| {
+                let same_table = scan_result.unit_id == clicked.unit_id
+                    && scan_result.register_space == clicked.register_space
+                    && scan_result.address == address;
+                match scan_result.outcome {
+                    ScanOutcome::Word(word) if same_table => Some(word),
+                    _ => None,
+                }
+            })
+        };
+        let words: Vec<u16> = [
+            word_at(clicked.address),
+            word_at(clicked.address.wrapping_add(1)),
+        ]
+        .into_iter()
+        .map_while(|word| word)
+        .collect();
+
+        egui::Grid::new("scan_candidates")
+            .num_columns(4)
+            .striped(true)
+            .show(ui, |ui| {
+                for (channel_type, byte_order, value) in candidate_decodings(&words) {
+                    ui.label(format!("{channel_type}"));
+                    ui.label(format!("{byte_order}"));
+                    ui.label(value);
+                    if ui
+                        .button(format!("{} Add", egui_phosphor::regular::PLUS))
+                        .clicked()
+                    {
+                        new_channels.push((
+                            clicked.register_space,
+                            clicked.address,
+                            channel_type,
+                            byte_order,
+                        ));
+                    }
+                    ui.end_row();
+                }
+            });
+    }
+
+    // Bits become BOOL and words U16 channels.
+    if ui
+        .add_enabled(
+            !app.scanner.checked.is_empty(),
+            egui::Button::new(format!(
+                "{} Add {} Checked",
+                egui_phosphor::regular::LIST_PLUS,
+                app.scanner.checked.len()
+            )),
+        )
+        .clicked()
+    {
+        for index in &app.scanner.checked {
+            let scan_result = &app.scanner.results[*index];
+            let channel_type = match scan_result.outcome {
+                ScanOutcome::Bit(_) => ModbusChannelType::Bool,
+                ScanOutcome::Word(_) => ModbusChannelType::U16,
+                ScanOutcome::Exception(_) => continue,
+            };
+            new_channels.push((
+                scan_result.register_space,
+                scan_result.address,
+                channel_type,
+                ModbusByteOrder::Abcd,
+            ));
+        }
+        app.scanner.checked.clear();
+    }
+
+    if new_channels.is_empty() {
+        return Ok(());
+    }
+    add_channels(app, new_channels)
+}
+
+// Append the channels to the selected device and send them to its task.
+fn add_channels(
+    app: &mut ColossalApp,
+    new_channels: Vec<(ModbusRegisterSpace, u16, ModbusChannelType, ModbusByteOrder)>,
+) -> anyhow::Result<()> {
+    let device_id = app.selected_device;
+    let device = app
+        .modbus_devices
+        .iter_mut()
+        .find(|device| device.id == device_id)
+        .ok_or_else(|| anyhow::anyhow!("Unknown device {device_id}"))?;
+
+    let mut next_id = device
+        .channels
+        .iter()
+        .map(|channel| channel.id)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut channels = Vec::new();
+    for (register_space, address, channel_type, byte_order) in new_channels {
+        let name = format!("{}{next_id}", device.code);
+        channels.push(ModbusChannel::new(
+            next_id,
+            name,
+            register_space,
+            address,
+            channel_type,
+            byte_order,
+        ));
+        next_id += 1;
+    }
+
+    device.channels.extend(channels.iter().cloned());
+    app.sender_main_to_thread
+        .try_send(ThreadCommand::AddChannels(device_id, channels))
+        .map_err(|e| anyhow::anyhow!("Add channels error: {e}"))
+}
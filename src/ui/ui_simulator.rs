@@ -0,0 +1,121 @@
+use egui::Color32;
+
+use crate::ColossalApp;
+use crate::{SimulatorConfig, SimulatorHandle};
+
+// Start/stop the in-app slave simulator and show its register map.
+pub fn ui_simulator(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let mut result = Ok(());
+
+    // The simulator thread ends on its own if it can't serve, e.g. the port is taken.
+    if app
+        .simulator
+        .as_ref()
+        .is_some_and(|simulator| !simulator.is_running())
+    {
+        if let Some(simulator) = app.simulator.take() {
+            app.simulator_error = simulator.stop().err().map(|e| format!("{e}"));
+        }
+    }
+    if let Some(error) = app
+        .simulator
+        .as_ref()
+        .and_then(|simulator| simulator.take_client_error())
+    {
+        app.simulator_error = Some(error);
+    }
+
+    let running = app.simulator.is_some();
+    let header = format!(
+        "{} Simulator ({})",
+        egui_phosphor::regular::CPU,
+        if running { "running" } else { "stopped" }
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("simulator")
+        .show(ui, |ui| {
+            egui::Grid::new("simulator_config")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Port");
+                    ui.add_enabled(
+                        !running,
+                        egui::DragValue::new(&mut app.simulator_config.port),
+                    );
+                    ui.end_row();
+                    ui.label("Register Map (RON)");
+                    ui.add_enabled(
+                        !running,
+                        egui::TextEdit::singleline(&mut app.simulator_map_path),
+                    );
+                    ui.end_row();
+
+                    if ui
+                        .add_enabled(
+                            !running,
+                            egui::Button::new(format!(
+                                "{} Load",
+                                egui_phosphor::regular::FOLDER_OPEN
+                            )),
+                        )
+                        .clicked()
+                    {
+                        match SimulatorConfig::load(app.simulator_map_path.trim()) {
+                            Ok(config) => app.simulator_config = config,
+                            Err(e) => result = Err(e),
+                        }
+                    }
+
+                    if running {
+                        if ui
+                            .button(format!("{} Stop", egui_phosphor::regular::STOP))
+                            .clicked()
+                        {
+                            if let Some(simulator) = app.simulator.take() {
+                                result = simulator.stop();
+                            }
+                        }
+                    } else if ui
+                        .button(format!("{} Start", egui_phosphor::regular::PLAY))
+                        .clicked()
+                    {
+                        app.simulator_error = None;
+                        app.simulator = Some(SimulatorHandle::spawn(app.simulator_config.clone()));
+                    }
+                    ui.end_row();
+                });
+
+            if let Some(error) = &app.simulator_error {
+                ui.colored_label(Color32::RED, error);
+            }
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .id_salt("simulator_registers")
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("simulator_registers")
+                        .num_columns(5)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("NAME");
+                            ui.strong("REGISTER");
+                            ui.strong("ADDRESS");
+                            ui.strong("TYPE");
+                            ui.strong("BEHAVIOUR");
+                            ui.end_row();
+
+                            for register in &app.simulator_config.registers {
+                                ui.label(&register.name);
+                                ui.label(format!("{}", register.register_space));
+                                ui.label(format!("{}", register.address));
+                                ui.label(format!("{}", register.channel_type));
+                                ui.label(format!("{}", register.behaviour));
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+
+    result
+}
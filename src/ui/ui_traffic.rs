@@ -0,0 +1,177 @@
+use egui::Color32;
+use egui_extras::{Column, TableBuilder};
+
+use crate::ColossalApp;
+use crate::{
+    export_traffic_pcap, export_traffic_text, format_bytes, format_time_of_day, TrafficEntry,
+};
+
+// Filter and export settings of the traffic monitor panel.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct TrafficView {
+    // Matched against the device, function code, address and result.
+    pub filter: String,
+    pub errors_only: bool,
+    pub export_path: String,
+    #[serde(skip)]
+    pub export_status: Option<String>,
+}
+
+impl Default for TrafficView {
+    fn default() -> Self {
+        Self {
+            filter: String::new(),
+            errors_only: false,
+            export_path: "traffic.txt".to_owned(),
+            export_status: None,
+        }
+    }
+}
+
+impl TrafficView {
+    fn shows(&self, entry: &TrafficEntry) -> bool {
+        (!self.errors_only || entry.is_error()) && entry.matches(&self.filter)
+    }
+}
+
+// Requests and responses of all the devices, with their raw frames.
+pub fn ui_traffic(app: &mut ColossalApp, ui: &mut egui::Ui) -> anyhow::Result<()> {
+    let header = format!(
+        "{} Traffic Monitor ({} frames)",
+        egui_phosphor::regular::ARROWS_LEFT_RIGHT,
+        app.traffic.len()
+    );
+    egui::CollapsingHeader::new(header)
+        .id_salt("traffic_monitor")
+        .show(ui, |ui| {
+            let view = &mut app.traffic_view;
+            let entries: Vec<TrafficEntry> = app
+                .traffic
+                .entries()
+                .into_iter()
+                .filter(|entry| view.shows(entry))
+                .collect();
+
+            ui.horizontal(|ui| {
+                let mut paused = app.traffic.is_paused();
+                if ui.checkbox(&mut paused, "Pause").changed() {
+                    app.traffic.set_paused(paused);
+                }
+                ui.checkbox(&mut view.errors_only, "Errors only");
+                ui.label("Filter");
+                ui.text_edit_singleline(&mut view.filter);
+                if ui
+                    .button(format!("{} Clear", egui_phosphor::regular::TRASH))
+                    .clicked()
+                {
+                    app.traffic.clear();
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Export to");
+                ui.text_edit_singleline(&mut view.export_path);
+                let text = ui.button(format!("{} Text", egui_phosphor::regular::FILE_TEXT));
+                let pcap = ui.button(format!("{} PCAP", egui_phosphor::regular::FILE));
+                let result = if text.clicked() {
+                    Some(export_traffic_text(&view.export_path, &entries))
+                } else if pcap.clicked() {
+                    Some(export_traffic_pcap(&view.export_path, &entries))
+                } else {
+                    None
+                };
+                match result {
+                    Some(Ok(_)) => {
+                        view.export_status = Some(format!(
+                            "Exported {} frames to {}.",
+                            entries.len(),
+                            view.export_path
+                        ))
+                    }
+                    Some(Err(e)) => view.export_status = Some(format!("Export error: {e}")),
+                    None => {}
+                }
+            });
+            if let Some(status) = &view.export_status {
+                ui.label(status);
+            }
+            ui.separator();
+
+            ui_traffic_table(ui, &entries);
+        });
+
+    Ok(())
+}
+
+fn ui_traffic_table(ui: &mut egui::Ui, entries: &[TrafficEntry]) {
+    TableBuilder::new(ui)
+        .id_salt("traffic_table")
+        .striped(true)
+        .resizable(true)
+        .stick_to_bottom(true)
+        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+        .column(Column::exact(90.))
+        .column(Column::initial(80.))
+        .column(Column::exact(40.))
+        .column(Column::exact(40.))
+        .column(Column::exact(60.))
+        .column(Column::exact(50.))
+        .column(Column::exact(70.))
+        .column(Column::initial(100.))
+        .column(Column::initial(200.))
+        .column(Column::remainder())
+        .vscroll(true)
+        .auto_shrink(false)
+        .max_scroll_height(250.0)
+        .header(20.0, |mut header| {
+            for title in [
+                "TIME", "DEVICE", "UNIT", "FC", "ADDRESS", "COUNT", "DURATION", "RESULT", "TX",
+                "RX",
+            ] {
+                header.col(|ui| {
+                    ui.strong(title);
+                });
+            }
+        })
+        .body(|body| {
+            body.rows(20.0, entries.len(), |mut row| {
+                let entry = &entries[row.index()];
+
+                row.col(|ui| {
+                    ui.label(format_time_of_day(entry.time));
+                });
+                row.col(|ui| {
+                    ui.label(&entry.device);
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", entry.unit_id));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{:02}", entry.function_code));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", entry.address));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{}", entry.count));
+                });
+                row.col(|ui| {
+                    ui.label(format!("{:.1} ms", entry.duration.as_secs_f64() * 1000.0));
+                });
+                row.col(|ui| {
+                    if entry.is_error() {
+                        ui.colored_label(Color32::LIGHT_RED, entry.result());
+                    } else {
+                        ui.label(entry.result());
+                    }
+                });
+                row.col(|ui| {
+                    ui.monospace(format_bytes(&entry.sent));
+                });
+                row.col(|ui| {
+                    ui.monospace(format_bytes(&entry.received));
+                });
+            });
+        });
+}